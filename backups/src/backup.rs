@@ -1,60 +1,280 @@
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use tracing::*;
+
 use crate::config::*;
+use crate::manifest::Manifest;
+use crate::retention::*;
+use crate::snapshot::*;
+
+pub mod archive;
+pub mod differential;
+pub mod repository;
+
+#[derive(Default, Clone, Debug)]
+pub struct BackupOptions {
+  /// Let the incremental mirror delete more than the task's `max-delete` allows
+  pub force: bool,
+}
+
+/// What a backup run captured
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackupSummary {
+  /// Files and symlinks in the backed up tree
+  pub files: u64,
+  /// Their total size
+  pub bytes: u64,
+}
+
+impl From<&Manifest> for BackupSummary {
+  fn from(manifest: &Manifest) -> Self {
+    let files = manifest.entries.iter().filter(|entry| entry.kind != crate::manifest::EntryKind::Dir);
+    files.fold(Self::default(), |summary, entry| Self {
+      files: summary.files + 1,
+      bytes: summary.bytes + entry.size,
+    })
+  }
+}
+
+pub fn make_backup(config: &BackupTaskConfig) -> anyhow::Result<BackupSummary> {
+  make_backup_with_options(config, &BackupOptions::default())
+}
+
+pub fn make_backup_with_options(
+  config: &BackupTaskConfig,
+  options: &BackupOptions,
+) -> anyhow::Result<BackupSummary> {
+  if config.encryption.is_some()
+    && !config.on.format.is_archive()
+    && !matches!(config.on.strategy, BackupStrategyConfig::Repository)
+  {
+    anyhow::bail!("encryption needs the `repository` strategy or an archive format");
+  }
+  // directory trees and archives rely on hardlinks, renames and file metadata of a local filesystem
+  if crate::storage::is_remote(&config.dst)
+    && (config.on.format.is_archive() || !matches!(config.on.strategy, BackupStrategyConfig::Repository))
+  {
+    anyhow::bail!("remote dst {} needs the `repository` strategy", config.dst.display());
+  }
+  // over SFTP there are no hardlinks for snapshots, only the mirror itself is kept in sync
+  if crate::fs::is_sftp(&config.dst)
+    && (config.on.format.is_archive()
+      || config.on.snapshots
+      || !matches!(config.on.strategy, BackupStrategyConfig::Incremental))
+  {
+    anyhow::bail!("sftp dst {} needs the `incremental` strategy without snapshots", config.dst.display());
+  }
+
+  let summary = match config.on.strategy {
+    _ if config.on.format.is_archive() => archive::make_archive_backup(config)?,
+    BackupStrategyConfig::Incremental => incremental::make_incremental_backup(config, options)?,
+    BackupStrategyConfig::Differential => differential::make_differential_backup(config)?,
+    BackupStrategyConfig::Repository => repository::make_repository_backup(config)?,
+  };
+
+  if let Some(retention) = &config.retention {
+    let span = info_span!("prune", dst = config.dst.display().to_string());
+    let _guard = span.enter();
+    prune_snapshots(config, retention)?;
+  }
+
+  // one unreachable destination shouldn't keep the others from getting their copy
+  let mut failed = 0;
+  for destination in config.destinations.iter() {
+    let span = info_span!("destination", dst = destination.dst.display().to_string());
+    let _guard = span.enter();
+    if let Err(e) = make_destination_backup(config, destination, options) {
+      error!("backup to {} failed: {}", destination.dst.display(), e);
+      failed += 1;
+    }
+  }
+  if failed > 0 {
+    anyhow::bail!("backup to {} of {} further destinations failed", failed, config.destinations.len());
+  }
+
+  Ok(summary)
+}
 
-pub fn make_backup(config: &BackupTaskConfig) -> anyhow::Result<()> {
+fn make_destination_backup(
+  config: &BackupTaskConfig,
+  destination: &DestinationConfig,
+  options: &BackupOptions,
+) -> anyhow::Result<()> {
+  let destination_config = config.for_destination(destination);
+  if !destination.copy {
+    make_backup_with_options(&destination_config, options)?;
+    return Ok(());
+  }
+
+  repository::copy_repository(config, &destination_config)?;
+  if let Some(retention) = &destination_config.retention {
+    prune_snapshots(&destination_config, retention)?;
+  }
+  Ok(())
+}
+
+/// Snapshots kept by the task in `dst`, oldest first.
+/// Returns `None` if the strategy keeps a single copy and has no history
+pub fn list_backup_snapshots(config: &BackupTaskConfig) -> anyhow::Result<Option<Vec<Snapshot>>> {
   match config.on.strategy {
-    BackupStrategyConfig::Incremental => incremental::make_incremental_backup(config),
-    BackupStrategyConfig::Differential => differential::make_differential_backup(config),
+    _ if config.on.format.is_archive() => Ok(Some(archive::Archives::for_task(config)?.list()?)),
+    BackupStrategyConfig::Incremental if config.on.snapshots => Ok(Some(list_snapshots(&config.dst, None)?)),
+    BackupStrategyConfig::Repository => Ok(Some(repository::Repository::for_task(config)?.snapshots()?)),
+    BackupStrategyConfig::Incremental | BackupStrategyConfig::Differential => Ok(None),
   }
 }
 
+pub fn prune_snapshots(config: &BackupTaskConfig, retention: &RetentionConfig) -> anyhow::Result<()> {
+  let Some(snapshots) = list_backup_snapshots(config)? else {
+    warn!("strategy `{}` keeps no snapshots, retention policy is ignored", config.on.strategy);
+    return Ok(());
+  };
+
+  let mut decisions = apply_policy(&snapshots, retention);
+  if config.on.format.is_archive() {
+    archive::Archives::for_task(config)?.keep_bases(&mut decisions)?;
+  }
+  log_decisions(&decisions);
+
+  let pruned = decisions.iter().filter(|decision| !decision.keep).collect::<Vec<_>>();
+  if pruned.is_empty() {
+    info!("nothing to prune, keeping {} snapshots", decisions.len());
+    return Ok(());
+  }
+
+  match config.on.strategy {
+    BackupStrategyConfig::Repository => {
+      let repo = repository::Repository::for_task(config)?;
+      for decision in pruned.iter() {
+        repo.remove_snapshot(&decision.snapshot.name)?;
+      }
+      let (chunks, bytes) = repo.collect_garbage()?;
+      info!("removed {} unreferenced chunks ({} bytes)", chunks, bytes);
+    }
+    _ if config.on.format.is_archive() => {
+      let archives = archive::Archives::for_task(config)?;
+      for decision in pruned.iter() {
+        archives.remove(&decision.snapshot.name)?;
+      }
+    }
+    _ => {
+      for decision in pruned.iter() {
+        std::fs::remove_dir_all(config.dst.join(&decision.snapshot.name))?;
+      }
+    }
+  }
+
+  info!("pruned {} snapshots, kept {}", pruned.len(), decisions.len() - pruned.len());
+  Ok(())
+}
+
+/// Writes the checksum manifest of the tree backed up into `root`
+fn write_manifest(
+  config: &BackupTaskConfig,
+  root: &std::path::Path,
+  previous: Option<&Manifest>,
+) -> anyhow::Result<Manifest> {
+  let manifest = Manifest::build(&config.src, root, previous, None, true)?;
+  manifest.write_to_dir(root)?;
+  info!("wrote manifest of {} entries", manifest.entries.len());
+  Ok(manifest)
+}
+
 mod incremental {
   use std::path::Path;
+  use std::path::PathBuf;
 
+  use super::write_manifest;
+  use super::BackupOptions;
+  use super::BackupSummary;
   use super::BackupTaskConfig;
+  use crate::compare::*;
+  use crate::config::ExcludedPolicy;
+  use crate::config::PreserveConfig;
+  use crate::filter::Filter;
+  use crate::fs::*;
+  use crate::manifest::Manifest;
+  use crate::manifest::MANIFEST_FILE_NAME;
+  use crate::metadata::*;
+  use crate::snapshot::*;
   use tracing::*;
 
-  pub fn make_incremental_backup(config: &BackupTaskConfig) -> anyhow::Result<()> {
+  pub fn make_incremental_backup(
+    config: &BackupTaskConfig,
+    options: &BackupOptions,
+  ) -> anyhow::Result<BackupSummary> {
     if !config.src.exists() {
       anyhow::bail!("src directory does not exist: {}", config.src.display());
     }
 
-    std::fs::create_dir_all(&config.dst)?;
+    if config.on.snapshots {
+      return make_snapshot_backup(config);
+    }
+
+    let filter = Filter::new(config);
+    let (fs, dst) = open_fs(&config.dst)?;
+    fs.create_dir_all(&dst)?;
     let span =
       info_span!("rm", src = config.src.display().to_string(), dst = config.dst.display().to_string());
     let _guard = span.enter();
-    remove_unwanted_files_from_dst(&config.src, &config.dst)?;
+    remove_unwanted_files_from_dst(config, fs.as_ref(), &dst, &filter, options.force)?;
     drop(_guard);
     let span =
       info_span!("cp", src = config.src.display().to_string(), dst = config.dst.display().to_string());
     let _guard = span.enter();
-    copy_incremental_all(&config.src, &config.dst)?;
+    let mut comparer = Comparer::new(fs.as_ref(), &dst, config.compare, &config.preserve);
+    copy_incremental_all(fs.as_ref(), &mut comparer, &config.src, &dst, &filter, &config.preserve)?;
     drop(_guard);
-    Ok(())
+    // hashes of files that weren't replaced stay valid for the new manifest
+    let previous = comparer.into_manifest(&config.src);
+
+    if config.src.is_dir() && is_sftp(&config.dst) {
+      // hashing the remote mirror would download all of it, so the manifest describes what was uploaded
+      let manifest =
+        Manifest::build(&config.src, &config.src, Some(&previous), Some(&filter), config.preserve.symlinks)?;
+      fs.write(&dst.join(MANIFEST_FILE_NAME), &serde_json::to_vec(&manifest)?)?;
+      info!("wrote manifest of {} entries", manifest.entries.len());
+      return Ok((&manifest).into());
+    }
+    if config.src.is_dir() {
+      return Ok((&write_manifest(config, &config.dst, Some(&previous))?).into());
+    }
+    Ok(BackupSummary { files: 1, bytes: config.src.metadata()?.len() })
   }
 
-  pub fn copy_incremental_all(src: &Path, dst: &Path) -> anyhow::Result<()> {
+  pub fn copy_incremental_all(
+    fs: &dyn Fs,
+    comparer: &mut Comparer,
+    src: &Path,
+    dst: &Path,
+    filter: &Filter,
+    preserve: &PreserveConfig,
+  ) -> anyhow::Result<()> {
     let mut copied_count = 0;
 
     if src.is_dir() {
-      std::fs::create_dir_all(dst)?;
+      fs.create_dir_all(dst)?;
 
       for entry in std::fs::read_dir(src)? {
         let entry = entry?;
         let path = entry.path();
         let dst_path = dst.join(path.file_name().unwrap());
         let src_path = src.join(path.file_name().unwrap());
+        let symlink = is_preserved_symlink(&path, preserve);
 
-        if path.is_dir() {
-          copy_incremental_all(&path, &dst_path)?;
-        } else if !dst_path.exists() || dst_path.metadata()?.modified()? < src_path.metadata()?.modified()? {
-          info!("copying {} to {}", path.display(), dst_path.display());
-          std::fs::copy(&path, &dst_path)?;
+        if filter.is_excluded(&path, !symlink && path.is_dir()) {
+          debug!("skipping excluded {}", path.display());
+        } else if !symlink && path.is_dir() {
+          copy_incremental_all(fs, comparer, &path, &dst_path, filter, preserve)?;
+        } else if !symlink && !path.is_file() {
+          debug!("skipping special file {}", path.display());
+        } else if update_entry(fs, comparer, &src_path, &dst_path, preserve)? {
           copied_count += 1;
         }
       }
-    } else if !dst.exists() || dst.metadata()?.modified()? < src.metadata()?.modified()? {
-      info!("copying {} to {}", src.display(), dst.display());
-      std::fs::copy(src, dst)?;
+
+      fs.copy_metadata(src, dst, preserve)?;
+    } else if update_entry(fs, comparer, src, dst, preserve)? {
       copied_count += 1;
     }
 
@@ -67,101 +287,238 @@ mod incremental {
     Ok(())
   }
 
-  pub fn remove_unwanted_files_from_dst(src: &Path, dst: &Path) -> anyhow::Result<()> {
-    let mut removed_count = 0;
+  /// Brings `dst` up to date with `src`, returns whether the content had to be copied
+  fn update_entry(
+    fs: &dyn Fs,
+    comparer: &mut Comparer,
+    src: &Path,
+    dst: &Path,
+    preserve: &PreserveConfig,
+  ) -> anyhow::Result<bool> {
+    match comparer.update(src, dst)? {
+      Update::Content => {
+        info!("copying {} to {}", src.display(), dst.display());
+        comparer.forget(dst);
+        fs.update_entry(src, dst, preserve)?;
+        Ok(true)
+      }
+      Update::Metadata => {
+        info!("updating metadata of {}", dst.display());
+        fs.copy_metadata(src, dst, preserve)?;
+        Ok(false)
+      }
+      Update::UpToDate => Ok(false),
+    }
+  }
+
+  /// Creates a new complete snapshot `dst/<time>/`. Files that didn't change since the previous
+  /// snapshot are hardlinked to it, the rest are copied, like `rsync --link-dest`
+  fn make_snapshot_backup(config: &BackupTaskConfig) -> anyhow::Result<BackupSummary> {
+    std::fs::create_dir_all(&config.dst)?;
+
+    let previous = list_snapshots(&config.dst, None)?.pop();
+    let name = new_snapshot_name(|name| config.dst.join(name).exists());
+
+    // build the snapshot under a hidden name, so an interrupted run never looks like a complete snapshot
+    let partial_dir = tempfile::Builder::new().prefix(".partial-").tempdir_in(&config.dst)?;
+    let link_dest = previous.as_ref().map(|snapshot| config.dst.join(&snapshot.name));
+
+    let span = info_span!(
+      "snapshot",
+      name = name.as_str(),
+      previous = previous.as_ref().map(|snapshot| snapshot.name.as_str()).unwrap_or("<none>")
+    );
+    let _guard = span.enter();
+    let mut counts = LinkCounts::default();
+    link_or_copy_all(
+      &config.src,
+      partial_dir.path(),
+      link_dest.as_deref(),
+      &Filter::new(config),
+      &config.preserve,
+      &mut counts,
+    )?;
+    info!("copied {} files, linked {} unchanged files", counts.copied, counts.linked);
+
+    let summary = match config.src.is_dir() {
+      true => {
+        let previous = link_dest.as_deref().and_then(|link_dest| Manifest::read_from_dir(link_dest).ok());
+        (&write_manifest(config, partial_dir.path(), previous.as_ref())?).into()
+      }
+      false => BackupSummary { files: 1, bytes: config.src.metadata()?.len() },
+    };
+
+    std::fs::rename(partial_dir.path(), config.dst.join(&name))?;
+    info!("created snapshot {}", name);
+    drop(_guard);
+
+    Ok(summary)
+  }
+
+  #[derive(Default)]
+  struct LinkCounts {
+    copied: usize,
+    linked: usize,
+  }
+
+  fn link_or_copy_all(
+    src: &Path,
+    dst: &Path,
+    link_dest: Option<&Path>,
+    filter: &Filter,
+    preserve: &PreserveConfig,
+    counts: &mut LinkCounts,
+  ) -> anyhow::Result<()> {
     if src.is_dir() {
       std::fs::create_dir_all(dst)?;
 
       for entry in std::fs::read_dir(src)? {
         let entry = entry?;
         let path = entry.path();
-        let dst_path = dst.join(path.file_name().unwrap());
-        let src_path = src.join(path.file_name().unwrap());
+        let dst_path = dst.join(entry.file_name());
+        let link_path = link_dest.map(|link_dest| link_dest.join(entry.file_name()));
+        let symlink = is_preserved_symlink(&path, preserve);
 
-        if path.is_dir() {
-          if dst_path.exists() && !src_path.exists() {
-            std::fs::remove_dir_all(&dst_path)?;
-          } else {
-            remove_unwanted_files_from_dst(&path, &dst_path)?;
-          }
-        } else if dst_path.exists() && !src_path.exists() {
-          std::fs::remove_file(&dst_path)?;
-          removed_count += 1;
+        if filter.is_excluded(&path, !symlink && path.is_dir()) {
+          debug!("skipping excluded {}", path.display());
+        } else if !symlink && path.is_dir() {
+          link_or_copy_all(&path, &dst_path, link_path.as_deref(), filter, preserve, counts)?;
+        } else if !symlink && !path.is_file() {
+          debug!("skipping special file {}", path.display());
+        } else {
+          link_or_copy(&path, &dst_path, link_path.as_deref(), preserve, counts)?;
         }
       }
-    } else if dst.exists() && !src.exists() {
-      std::fs::remove_dir_all(dst)?;
-      removed_count += 1;
-    }
 
-    if removed_count > 0 {
-      info!("removed {} files", removed_count);
+      copy_metadata(src, dst, preserve)?;
     } else {
-      info!("no files removed, everything is up-to-date");
+      link_or_copy(src, dst, link_dest, preserve, counts)?;
     }
 
     Ok(())
   }
-}
 
-mod differential {
-  use std::path::Path;
+  fn link_or_copy(
+    src: &Path,
+    dst: &Path,
+    link_dest: Option<&Path>,
+    preserve: &PreserveConfig,
+    counts: &mut LinkCounts,
+  ) -> anyhow::Result<()> {
+    // a hardlink shares metadata with the previous snapshot, so only link when that matches too
+    match link_dest {
+      Some(link_dest) if is_up_to_date(src, link_dest, preserve)? => {
+        std::fs::hard_link(link_dest, dst)?;
+        counts.linked += 1;
+      }
+      _ => {
+        info!("copying {} to {}", src.display(), dst.display());
+        copy_entry(src, dst, preserve)?;
+        counts.copied += 1;
+      }
+    }
 
-  use super::BackupTaskConfig;
-  use tracing::*;
+    Ok(())
+  }
 
-  pub fn make_differential_backup(config: &BackupTaskConfig) -> anyhow::Result<()> {
-    std::fs::create_dir_all(&config.dst)?;
-    let temp_dir = tempfile::tempdir_in(
-      config
-        .dst
-        .parent()
-        .ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "dst has no parent"))?,
-    )?;
-    let temp_bak_dir = temp_dir.path();
-    std::fs::create_dir_all(temp_bak_dir)?;
+  /// Removes what is in `dst` but no longer in `src`. Deletions are planned first and refused if they
+  /// would remove more than `max-delete` percent of the mirror, unless forced
+  pub fn remove_unwanted_files_from_dst(
+    config: &BackupTaskConfig,
+    fs: &dyn Fs,
+    dst: &Path,
+    filter: &Filter,
+    force: bool,
+  ) -> anyhow::Result<()> {
+    if !config.src.is_dir() {
+      return Ok(());
+    }
 
-    let span = info_span!("tmp", path = temp_bak_dir.display().to_string());
-    let _guard = span.enter();
-    info!("temp dir path: {}", temp_bak_dir.display());
-    copy_all(&config.src, temp_bak_dir)?;
-    drop(_guard);
-    let span =
-      info_span!("mv", src = temp_bak_dir.display().to_string(), dst = config.dst.display().to_string());
-    let _guard = span.enter();
-    info!("remove old backup");
-    std::fs::remove_dir_all(&config.dst)?;
-    info!("moving temp dir to dst");
-    std::fs::rename(temp_bak_dir, &config.dst)?;
-    drop(_guard);
+    let mut unwanted = Vec::new();
+    find_unwanted(config, fs, dst, filter, Path::new(""), &mut unwanted)?;
+    let mut removed_count = 0;
+    for path in unwanted.iter() {
+      removed_count += count_entries(fs, &dst.join(path))?;
+    }
+    let manifest_count = fs.metadata(&dst.join(MANIFEST_FILE_NAME))?.is_some() as usize;
+    let total_count = count_entries(fs, dst)? - 1 - manifest_count;
+
+    if !force && removed_count * 100 > total_count * config.max_delete as usize {
+      anyhow::bail!(
+        "refusing to delete {} of {} entries in {}, more than {}%; is src unmounted or empty? \
+         Raise `max-delete` or force the run if the deletions are intended",
+        removed_count,
+        total_count,
+        config.dst.display(),
+        config.max_delete
+      );
+    }
+
+    for path in unwanted.iter() {
+      let dst_path = dst.join(path);
+      info!("removing {}", dst_path.display());
+      fs.remove(&dst_path)?;
+    }
+
+    if removed_count > 0 {
+      info!("removed {} entries", removed_count);
+    } else {
+      info!("no files removed, everything is up-to-date");
+    }
 
     Ok(())
   }
 
-  fn copy_all(src: &Path, dst: &Path) -> anyhow::Result<()> {
-    let mut copied_count = 0;
-    if src.is_dir() {
-      std::fs::create_dir_all(dst)?;
-
-      for entry in std::fs::read_dir(src)? {
-        let entry = entry?;
-        let path = entry.path();
-        let dst_path = dst.join(path.file_name().unwrap());
+  /// Collects paths under `dst/rel` that are missing from `src`, changed between directory and
+  /// non-directory, or excluded with the `delete` policy
+  fn find_unwanted(
+    config: &BackupTaskConfig,
+    fs: &dyn Fs,
+    dst: &Path,
+    filter: &Filter,
+    rel: &Path,
+    unwanted: &mut Vec<PathBuf>,
+  ) -> anyhow::Result<()> {
+    for (file_name, metadata) in fs.read_dir(&dst.join(rel))? {
+      let rel_path = rel.join(&file_name);
+      if rel == Path::new("") && file_name == MANIFEST_FILE_NAME {
+        continue;
+      }
 
-        if path.is_dir() {
-          copy_all(&path, &dst_path)?;
-        } else {
-          std::fs::copy(&path, &dst_path)?;
-          copied_count += 1;
+      let is_dir = metadata.kind == FileKind::Dir;
+      if filter.is_excluded_rel(&rel_path, is_dir) {
+        if config.excluded_in_dst == ExcludedPolicy::Delete {
+          debug!("{} is excluded", rel_path.display());
+          unwanted.push(rel_path);
         }
+        continue;
       }
-    } else {
-      std::fs::copy(src, dst)?;
-      copied_count += 1;
-    }
 
-    info!("copied {} files", copied_count);
+      let src_path = config.src.join(&rel_path);
+      let src_metadata = match config.preserve.symlinks {
+        true => src_path.symlink_metadata(),
+        false => src_path.metadata(),
+      };
+      match src_metadata {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => unwanted.push(rel_path),
+        Err(e) => return Err(e.into()),
+        Ok(metadata) if metadata.is_dir() != is_dir => unwanted.push(rel_path),
+        Ok(_) if is_dir => find_unwanted(config, fs, dst, filter, &rel_path, unwanted)?,
+        Ok(_) => (),
+      }
+    }
 
     Ok(())
   }
+
+  /// Number of entries in the tree at `path`, itself included
+  fn count_entries(fs: &dyn Fs, path: &Path) -> anyhow::Result<usize> {
+    let mut count = 1;
+    if fs.metadata(path)?.is_some_and(|metadata| metadata.kind == FileKind::Dir) {
+      for (file_name, _) in fs.read_dir(path)? {
+        count += count_entries(fs, &path.join(file_name))?;
+      }
+    }
+    Ok(count)
+  }
 }
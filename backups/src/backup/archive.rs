@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use tracing::*;
+
+use super::BackupSummary;
+use super::BackupTaskConfig;
+use crate::config::*;
+use crate::crypto::*;
+use crate::filter::Filter;
+use crate::manifest::*;
+use crate::retention::RetentionDecision;
+use crate::snapshot::*;
+use crate::storage::LocalStorage;
+
+/// Name of the archive member listing paths removed since the base archive, one per line
+pub const DELETED_LIST_NAME: &str = ".backups-deleted";
+
+/// One archive per run, each with an index next to it:
+///
+/// ```text
+/// dst/
+///   <name>.tar.zst # files of the run; only the changed ones for incremental archives
+///   <name>.json    # index: the state of the whole src tree and what the archive is based on
+/// ```
+///
+/// The index is written last, so an archive without one is an interrupted run and is ignored.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ArchiveIndex {
+  pub format: OutputFormat,
+  /// Archive this one only holds the changes to; `None` for a full archive
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub base: Option<String>,
+  /// Paths removed from src since `base`
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub deleted: Vec<PathBuf>,
+  /// State of the whole src tree at the time of the run
+  pub manifest: Manifest,
+}
+
+impl ArchiveIndex {
+  /// Files the archive must contain: all of them for a full archive, otherwise only those that
+  /// differ from `base`
+  pub fn stored_files(&self, base: Option<&ArchiveIndex>) -> HashMap<PathBuf, &ManifestEntry> {
+    let base: HashMap<_, _> = base
+      .map(|base| base.manifest.entries.iter().map(|entry| (entry.path.as_path(), entry)).collect())
+      .unwrap_or_default();
+
+    self
+      .manifest
+      .entries
+      .iter()
+      .filter(|entry| entry.kind == EntryKind::File)
+      .filter(|entry| {
+        !base.get(entry.path.as_path()).is_some_and(|prev| {
+          prev.kind == EntryKind::File && prev.size == entry.size && prev.hash == entry.hash
+        })
+      })
+      .map(|entry| (entry.path.clone(), entry))
+      .collect()
+  }
+}
+
+/// Archives of a task in `dst`; with encryption, archives and indexes are encrypted
+pub struct Archives {
+  root: PathBuf,
+  cipher: Option<Cipher>,
+}
+
+impl Archives {
+  pub fn open(root: &Path) -> Self {
+    Self { root: root.to_path_buf(), cipher: None }
+  }
+
+  /// Opens the task's archives, with its encryption key if the task is encrypted
+  pub fn for_task(config: &BackupTaskConfig) -> anyhow::Result<Self> {
+    let cipher = Cipher::open(&LocalStorage::new(&config.dst), config.encryption.as_ref())?;
+    Ok(Self { cipher, ..Self::open(&config.dst) })
+  }
+
+  pub fn list(&self) -> anyhow::Result<Vec<Snapshot>> {
+    Ok(list_snapshots(&self.root, Some("json"))?)
+  }
+
+  fn index_path(&self, name: &str) -> PathBuf {
+    self.root.join(format!("{}.json", name))
+  }
+
+  pub fn archive_path(&self, name: &str, index: &ArchiveIndex) -> PathBuf {
+    self.root.join(format!("{}.{}", name, index.format.extension()))
+  }
+
+  pub fn read_index(&self, name: &str) -> anyhow::Result<ArchiveIndex> {
+    let content = std::fs::read(self.index_path(name))?;
+    match &self.cipher {
+      Some(cipher) => Ok(serde_json::from_slice(&cipher.decrypt(&content)?)?),
+      None => Ok(serde_json::from_slice(&content)?),
+    }
+  }
+
+  fn write_index(&self, name: &str, index: &ArchiveIndex) -> anyhow::Result<()> {
+    let content = serde_json::to_vec(index)?;
+    match &self.cipher {
+      Some(cipher) => write_atomic(&self.index_path(name), &cipher.encrypt(&content)?),
+      None => write_atomic(&self.index_path(name), &content),
+    }
+  }
+
+  /// Opens the archive for reading, decrypting and decompressing it on the fly
+  pub fn open_archive(
+    &self,
+    name: &str,
+    index: &ArchiveIndex,
+  ) -> anyhow::Result<tar::Archive<Box<dyn Read>>> {
+    let path = self.archive_path(name, index);
+    let file = std::fs::File::open(&path)?;
+    let file: Box<dyn Read> = match &self.cipher {
+      Some(cipher) => Box::new(cipher.decrypt_reader(file)?),
+      None => Box::new(file),
+    };
+    let reader: Box<dyn Read> = match index.format {
+      OutputFormat::TarZst => Box::new(zstd::Decoder::new(file)?),
+      OutputFormat::TarGz => Box::new(flate2::read::GzDecoder::new(file)),
+      OutputFormat::Directory => anyhow::bail!("{} is not an archive", path.display()),
+    };
+    Ok(tar::Archive::new(reader))
+  }
+
+  pub fn remove(&self, name: &str) -> anyhow::Result<()> {
+    let index = self.read_index(name)?;
+    std::fs::remove_file(self.index_path(name))?;
+    std::fs::remove_file(self.archive_path(name, &index))?;
+    Ok(())
+  }
+
+  /// Keeps every archive a kept incremental archive is based on, otherwise it couldn't be restored
+  pub fn keep_bases(&self, decisions: &mut [RetentionDecision]) -> anyhow::Result<()> {
+    let mut bases = HashMap::new();
+    for decision in decisions.iter() {
+      if let Some(base) = self.read_index(&decision.snapshot.name)?.base {
+        bases.insert(decision.snapshot.name.clone(), base);
+      }
+    }
+
+    // decisions are sorted oldest first, so walking from the newest marks whole chains
+    for i in (0..decisions.len()).rev() {
+      if !decisions[i].keep {
+        continue;
+      }
+      let Some(base) = bases.get(&decisions[i].snapshot.name) else {
+        continue;
+      };
+      let name = decisions[i].snapshot.name.clone();
+      if let Some(base) = decisions.iter_mut().find(|decision| decision.snapshot.name == *base) {
+        if !base.keep {
+          base.keep = true;
+          base.reasons = vec![format!("base of {}", name)];
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Unpacks the archive `name` into `into`, after first unpacking the archives it's based on. Only
+  /// entries under `scope` are unpacked, an empty one takes all. Returns the index of the archive
+  pub fn extract_chain(&self, name: &str, scope: &Path, into: &Path) -> anyhow::Result<ArchiveIndex> {
+    let mut chain = vec![(name.to_string(), self.read_index(name)?)];
+    while let Some(base) = chain.last().and_then(|(_, index)| index.base.clone()) {
+      let index = self
+        .read_index(&base)
+        .map_err(|e| anyhow::anyhow!("base archive {} of {} is unreadable: {}", base, name, e))?;
+      chain.push((base, index));
+    }
+
+    for (name, index) in chain.iter().rev() {
+      debug!("extracting archive {}", name);
+      let mut archive = self.open_archive(name, index)?;
+      for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if path == Path::new(DELETED_LIST_NAME) || !path.starts_with(scope) {
+          continue;
+        }
+        // every archive stores all symlinks again, replace the ones unpacked from its base
+        let existing = into.join(&path);
+        if entry.header().entry_type().is_symlink() && existing.symlink_metadata().is_ok_and(|m| !m.is_dir())
+        {
+          std::fs::remove_file(&existing)?;
+        }
+        entry.unpack_in(into)?;
+      }
+
+      for path in index.deleted.iter() {
+        let path = into.join(path);
+        match path.symlink_metadata() {
+          Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path)?,
+          Ok(_) => std::fs::remove_file(path)?,
+          Err(_) => (),
+        }
+      }
+    }
+
+    Ok(chain.swap_remove(0).1)
+  }
+}
+
+pub fn make_archive_backup(config: &BackupTaskConfig) -> anyhow::Result<BackupSummary> {
+  if let BackupStrategyConfig::Repository = config.on.strategy {
+    anyhow::bail!("strategy `{}` can't write `{}` archives", config.on.strategy, config.on.format);
+  }
+  if !config.src.is_dir() {
+    anyhow::bail!("src must be a directory to write archives: {}", config.src.display());
+  }
+
+  let archives = Archives::for_task(config)?;
+  std::fs::create_dir_all(&config.dst)?;
+
+  // every chain starts with a full archive, a new one is taken when due per `full-every`
+  let snapshots = archives.list()?;
+  let mut full = None;
+  for (i, snapshot) in snapshots.iter().enumerate().rev() {
+    let index = archives.read_index(&snapshot.name)?;
+    if index.base.is_none() {
+      let based = (snapshots.len() - i - 1) as u32;
+      if !config.on.full_every.is_due(snapshot.time, based) {
+        full = Some((snapshot.name.clone(), index));
+      }
+      break;
+    }
+  }
+  let base = match (&config.on.strategy, full) {
+    // incremental archives are based on the latest one, differential ones on the full one
+    (BackupStrategyConfig::Incremental, Some(_)) => {
+      let latest = &snapshots.last().expect("a full archive was found").name;
+      Some((latest.clone(), archives.read_index(latest)?))
+    }
+    (BackupStrategyConfig::Differential, full) => full,
+    _ => None,
+  };
+
+  let name = new_snapshot_name(|name| archives.index_path(name).exists());
+  let span = info_span!(
+    "archive",
+    name = name.as_str(),
+    base = base.as_ref().map(|(name, _)| name.as_str()).unwrap_or("<none>")
+  );
+  let _guard = span.enter();
+
+  let previous = base.as_ref().map(|(_, index)| &index.manifest);
+  let filter = Filter::new(config);
+  let manifest =
+    Manifest::build(&config.src, &config.src, previous, Some(&filter), config.preserve.symlinks)?;
+  let current = manifest.entries.iter().map(|entry| entry.path.as_path()).collect::<HashSet<_>>();
+  let deleted = base
+    .as_ref()
+    .map(|(_, index)| {
+      index
+        .manifest
+        .entries
+        .iter()
+        .filter(|entry| !current.contains(entry.path.as_path()))
+        .map(|entry| entry.path.clone())
+        .collect()
+    })
+    .unwrap_or_default();
+
+  let index = ArchiveIndex {
+    format: config.on.format,
+    base: base.as_ref().map(|(name, _)| name.clone()),
+    deleted,
+    manifest,
+  };
+  let stored = index.stored_files(base.as_ref().map(|(_, index)| index));
+
+  // write under a hidden name, so an interrupted run never leaves an archive that looks complete
+  let file = tempfile::Builder::new().prefix(".partial-").tempfile_in(&config.dst)?;
+  let sink = match &archives.cipher {
+    Some(cipher) => Sink::Encrypted(cipher.encrypt_writer(file)?),
+    None => Sink::Plain(file),
+  };
+  let mut builder = tar::Builder::new(Compressor::new(config.on.format, sink)?);
+  builder.follow_symlinks(!config.preserve.symlinks);
+  for entry in index.manifest.entries.iter() {
+    if entry.kind != EntryKind::File || stored.contains_key(&entry.path) {
+      builder.append_path_with_name(config.src.join(&entry.path), &entry.path)?;
+    }
+  }
+  if !index.deleted.is_empty() {
+    let list = index.deleted.iter().map(|path| format!("{}\n", path.display())).collect::<String>();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(list.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs());
+    builder.append_data(&mut header, DELETED_LIST_NAME, list.as_bytes())?;
+  }
+  let mut file = builder.into_inner()?.finish()?.finish()?;
+  file.flush()?;
+  file.persist(archives.archive_path(&name, &index))?;
+  archives.write_index(&name, &index)?;
+
+  info!("archived {} files, {} deleted since the base archive", stored.len(), index.deleted.len());
+  info!("created archive {}", archives.archive_path(&name, &index).display());
+
+  Ok((&index.manifest).into())
+}
+
+enum Compressor<W: Write> {
+  Zstd(zstd::Encoder<'static, W>),
+  Gzip(flate2::write::GzEncoder<W>),
+}
+
+impl<W: Write> Compressor<W> {
+  fn new(format: OutputFormat, writer: W) -> anyhow::Result<Self> {
+    match format {
+      OutputFormat::TarZst => {
+        Ok(Compressor::Zstd(zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?))
+      }
+      OutputFormat::TarGz => {
+        Ok(Compressor::Gzip(flate2::write::GzEncoder::new(writer, flate2::Compression::default())))
+      }
+      OutputFormat::Directory => anyhow::bail!("`{}` is not an archive format", format),
+    }
+  }
+
+  /// Writes the end of the compressed stream and returns the underlying writer
+  fn finish(self) -> std::io::Result<W> {
+    match self {
+      Compressor::Zstd(encoder) => encoder.finish(),
+      Compressor::Gzip(encoder) => encoder.finish(),
+    }
+  }
+}
+
+impl<W: Write> Write for Compressor<W> {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    match self {
+      Compressor::Zstd(encoder) => encoder.write(buf),
+      Compressor::Gzip(encoder) => encoder.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    match self {
+      Compressor::Zstd(encoder) => encoder.flush(),
+      Compressor::Gzip(encoder) => encoder.flush(),
+    }
+  }
+}
+
+/// Where the compressed stream goes: straight into the file or through encryption first
+enum Sink<W: Write> {
+  Plain(W),
+  Encrypted(EncryptWriter<W>),
+}
+
+impl<W: Write> Sink<W> {
+  fn finish(self) -> std::io::Result<W> {
+    match self {
+      Sink::Plain(writer) => Ok(writer),
+      Sink::Encrypted(writer) => writer.finish(),
+    }
+  }
+}
+
+impl<W: Write> Write for Sink<W> {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    match self {
+      Sink::Plain(writer) => writer.write(buf),
+      Sink::Encrypted(writer) => writer.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    match self {
+      Sink::Plain(writer) => writer.flush(),
+      Sink::Encrypted(writer) => writer.flush(),
+    }
+  }
+}
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use tracing::*;
+
+use super::write_manifest;
+use super::BackupSummary;
+use super::BackupTaskConfig;
+use crate::config::ExcludedPolicy;
+use crate::config::PreserveConfig;
+use crate::filter::Filter;
+use crate::manifest::*;
+use crate::metadata::*;
+use crate::snapshot::*;
+
+/// Directory in `dst` holding the complete tree of the last full backup
+pub const FULL_DIR_NAME: &str = "full";
+/// Directory in `dst` holding everything that changed since the full backup
+pub const DIFF_DIR_NAME: &str = "diff";
+/// Name of the file in `dst` telling which full backup the differential set is based on
+pub const STATE_FILE_NAME: &str = ".backups-differential.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct DifferentialState {
+  /// When the full backup was taken, as a snapshot name
+  full: String,
+  /// Differential runs based on it so far
+  differentials: u32,
+}
+
+impl DifferentialState {
+  fn read(dst: &Path) -> Option<Self> {
+    serde_json::from_slice(&std::fs::read(dst.join(STATE_FILE_NAME)).ok()?).ok()
+  }
+
+  fn write(&self, dst: &Path) -> anyhow::Result<()> {
+    write_atomic(&dst.join(STATE_FILE_NAME), &serde_json::to_vec(self)?)
+  }
+}
+
+/// Takes a full backup into `dst/full` when one is due per `full-every`, otherwise replaces `dst/diff`
+/// with every file that changed since the full backup. Restoring never needs more than these two
+pub fn make_differential_backup(config: &BackupTaskConfig) -> anyhow::Result<BackupSummary> {
+  if !config.src.is_dir() {
+    anyhow::bail!("src must be a directory for the `differential` strategy: {}", config.src.display());
+  }
+  std::fs::create_dir_all(&config.dst)?;
+
+  let state = DifferentialState::read(&config.dst).filter(|_| config.dst.join(FULL_DIR_NAME).is_dir());
+  let due = |state: &DifferentialState| match parse_snapshot_name(&state.full) {
+    Some(full) => config.on.full_every.is_due(full.time, state.differentials),
+    None => true,
+  };
+  match state {
+    Some(state) if !due(&state) => make_differential_set(config, state),
+    _ => make_full_backup(config),
+  }
+}
+
+/// Manifest of the latest backup: of the differential set if there is one, of the full backup otherwise
+pub fn current_manifest(dst: &Path) -> anyhow::Result<Manifest> {
+  match dst.join(DIFF_DIR_NAME).is_dir() {
+    true => Manifest::read_from_dir(&dst.join(DIFF_DIR_NAME)),
+    false => Manifest::read_from_dir(&dst.join(FULL_DIR_NAME)),
+  }
+}
+
+/// Where the latest version of `rel` is stored: in the differential set if it changed since the full
+/// backup, in the full backup otherwise
+pub fn content_path(dst: &Path, rel: &Path) -> PathBuf {
+  let changed = dst.join(DIFF_DIR_NAME).join(rel);
+  match changed.symlink_metadata() {
+    Ok(_) => changed,
+    Err(_) => dst.join(FULL_DIR_NAME).join(rel),
+  }
+}
+
+fn make_full_backup(config: &BackupTaskConfig) -> anyhow::Result<BackupSummary> {
+  let temp_dir = tempfile::tempdir_in(
+    config.dst.parent().ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "dst has no parent"))?,
+  )?;
+  let full = temp_dir.path().join(FULL_DIR_NAME);
+
+  let span = info_span!("full", tmp = temp_dir.path().display().to_string());
+  let _guard = span.enter();
+  let filter = Filter::new(config);
+  copy_all(&config.src, &full, &filter, &config.preserve)?;
+  // `dst` written by older versions holds the tree itself
+  let old_full = match config.dst.join(FULL_DIR_NAME) {
+    old_full if old_full.is_dir() => old_full,
+    _ => config.dst.clone(),
+  };
+  if config.excluded_in_dst == ExcludedPolicy::Keep && !filter.is_empty() && old_full.is_dir() {
+    carry_over_excluded(&old_full, &full, Path::new(""), &filter)?;
+  }
+  let manifest = write_manifest(config, &full, None)?;
+  let state = DifferentialState { full: new_snapshot_name(|_| false), differentials: 0 };
+  state.write(temp_dir.path())?;
+  drop(_guard);
+
+  let span =
+    info_span!("mv", src = temp_dir.path().display().to_string(), dst = config.dst.display().to_string());
+  let _guard = span.enter();
+  replace_dir(temp_dir.path(), &config.dst, Path::new(FULL_DIR_NAME))?;
+  info!("created full backup {}", state.full);
+  drop(_guard);
+
+  Ok((&manifest).into())
+}
+
+fn make_differential_set(
+  config: &BackupTaskConfig,
+  state: DifferentialState,
+) -> anyhow::Result<BackupSummary> {
+  let span = info_span!("diff", full = state.full.as_str());
+  let _guard = span.enter();
+
+  let base = Manifest::read_from_dir(&config.dst.join(FULL_DIR_NAME))?;
+  let filter = Filter::new(config);
+  let manifest =
+    Manifest::build(&config.src, &config.src, Some(&base), Some(&filter), config.preserve.symlinks)?;
+  let base = base.entries.iter().map(|entry| (entry.path.as_path(), entry)).collect::<HashMap<_, _>>();
+
+  // build the set under a hidden name, so an interrupted run leaves the previous set in place
+  let partial_dir = tempfile::Builder::new().prefix(".partial-").tempdir_in(&config.dst)?;
+  let mut copied_count = 0;
+  for entry in manifest.entries.iter().filter(|entry| entry.kind != EntryKind::Dir) {
+    if base.get(entry.path.as_path()).is_some_and(|base| is_same(base, entry, &config.preserve)) {
+      continue;
+    }
+
+    let dst_path = partial_dir.path().join(&entry.path);
+    std::fs::create_dir_all(dst_path.parent().expect("path in backup always has a parent"))?;
+    debug!("copying changed {}", entry.path.display());
+    copy_entry(&config.src.join(&entry.path), &dst_path, &config.preserve)?;
+    copied_count += 1;
+  }
+  // directories last, so adding their children doesn't bump mtime again
+  for entry in manifest.entries.iter().rev().filter(|entry| entry.kind == EntryKind::Dir) {
+    let dst_path = partial_dir.path().join(&entry.path);
+    if dst_path.is_dir() {
+      copy_metadata(&config.src.join(&entry.path), &dst_path, &config.preserve)?;
+    }
+  }
+  manifest.write_to_dir(partial_dir.path())?;
+
+  replace_dir(partial_dir.path(), &config.dst.join(DIFF_DIR_NAME), Path::new(""))?;
+  DifferentialState { differentials: state.differentials + 1, ..state }.write(&config.dst)?;
+  info!("differential set holds {} files changed since the full backup", copied_count);
+
+  Ok((&manifest).into())
+}
+
+/// Whether `entry` is stored unchanged by the full backup, as far as the differential set goes:
+/// timestamps alone don't make a file changed, they're restored from the manifest
+fn is_same(base: &ManifestEntry, entry: &ManifestEntry, preserve: &PreserveConfig) -> bool {
+  base.kind == entry.kind
+    && base.size == entry.size
+    && base.hash == entry.hash
+    && base.link == entry.link
+    && (!preserve.mode || base.mode == entry.mode)
+    && (!preserve.ownership || (base.uid == entry.uid && base.gid == entry.gid))
+}
+
+/// Puts the directory `new` in place of `old`, swapping them atomically where the filesystem allows.
+/// The old copy is only removed once the new one is in place and its manifest in `manifest_dir` reads
+/// back, so a crash or a full disk at any point leaves a complete backup behind
+fn replace_dir(new: &Path, old: &Path, manifest_dir: &Path) -> anyhow::Result<()> {
+  if old.symlink_metadata().is_err() {
+    info!("moving {} to {}", new.display(), old.display());
+    std::fs::rename(new, old)?;
+    return Ok(());
+  }
+
+  info!("swapping {} with {}", new.display(), old.display());
+  let retired = match exchange(new, old) {
+    Ok(()) => Retired::Exchanged(new.to_path_buf()),
+    Err(e) => {
+      debug!("can't swap atomically ({}), renaming the old copy aside", e);
+      let parent =
+        old.parent().ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "dst has no parent"))?;
+      let aside = tempfile::Builder::new().prefix(".old-").tempdir_in(parent)?;
+      std::fs::rename(old, aside.path())?;
+      if let Err(e) = std::fs::rename(new, old) {
+        std::fs::rename(aside.path(), old)?;
+        return Err(e.into());
+      }
+      Retired::Aside(aside)
+    }
+  };
+
+  if let Err(e) = Manifest::read_from_dir(&old.join(manifest_dir)) {
+    match &retired {
+      Retired::Exchanged(path) => exchange(path, old)?,
+      Retired::Aside(aside) => {
+        std::fs::rename(old, new)?;
+        std::fs::rename(aside.path(), old)?;
+      }
+    }
+    anyhow::bail!("new backup is unreadable, kept the previous one in {}: {}", old.display(), e);
+  }
+
+  info!("removing the previous copy");
+  match retired {
+    Retired::Exchanged(path) => std::fs::remove_dir_all(path)?,
+    Retired::Aside(aside) => aside.close()?,
+  }
+  Ok(())
+}
+
+/// Where the previous copy went after [`replace_dir`] put the new one in place
+enum Retired {
+  /// To the path of the new copy
+  Exchanged(PathBuf),
+  /// Into a hidden directory next to it
+  Aside(tempfile::TempDir),
+}
+
+/// Atomically exchanges two paths with `renameat2(RENAME_EXCHANGE)`
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> std::io::Result<()> {
+  use std::os::unix::ffi::OsStrExt;
+
+  let a = std::ffi::CString::new(a.as_os_str().as_bytes())?;
+  let b = std::ffi::CString::new(b.as_os_str().as_bytes())?;
+  // SAFETY: both paths are valid NUL-terminated strings that outlive the call
+  let result =
+    unsafe { libc::renameat2(libc::AT_FDCWD, a.as_ptr(), libc::AT_FDCWD, b.as_ptr(), libc::RENAME_EXCHANGE) };
+  match result {
+    0 => Ok(()),
+    _ => Err(std::io::Error::last_os_error()),
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(_: &Path, _: &Path) -> std::io::Result<()> {
+  Err(std::io::ErrorKind::Unsupported.into())
+}
+
+fn copy_all(src: &Path, dst: &Path, filter: &Filter, preserve: &PreserveConfig) -> anyhow::Result<()> {
+  let mut copied_count = 0;
+  if src.is_dir() {
+    std::fs::create_dir_all(dst)?;
+
+    for entry in std::fs::read_dir(src)? {
+      let entry = entry?;
+      let path = entry.path();
+      let dst_path = dst.join(path.file_name().unwrap());
+      let symlink = is_preserved_symlink(&path, preserve);
+
+      if filter.is_excluded(&path, !symlink && path.is_dir()) {
+        debug!("skipping excluded {}", path.display());
+      } else if !symlink && path.is_dir() {
+        copy_all(&path, &dst_path, filter, preserve)?;
+      } else if !symlink && !path.is_file() {
+        debug!("skipping special file {}", path.display());
+      } else {
+        copy_entry(&path, &dst_path, preserve)?;
+        copied_count += 1;
+      }
+    }
+
+    copy_metadata(src, dst, preserve)?;
+  } else {
+    copy_entry(src, dst, preserve)?;
+    copied_count += 1;
+  }
+
+  info!("copied {} files", copied_count);
+
+  Ok(())
+}
+
+/// Hardlinks excluded paths of the old backup into the new one, so the swap doesn't drop them. The old
+/// backup keeps its own links until it is replaced, so a run failing halfway loses nothing
+fn carry_over_excluded(old: &Path, new: &Path, rel: &Path, filter: &Filter) -> anyhow::Result<()> {
+  for entry in std::fs::read_dir(old.join(rel))? {
+    let entry = entry?;
+    let rel_path = rel.join(entry.file_name());
+    if rel == Path::new("") && entry.file_name() == MANIFEST_FILE_NAME {
+      continue;
+    }
+
+    let is_dir = entry.file_type()?.is_dir();
+    if filter.is_excluded_rel(&rel_path, is_dir) {
+      debug!("keeping excluded {}", rel_path.display());
+      let new_path = new.join(&rel_path);
+      std::fs::create_dir_all(new_path.parent().expect("path in backup always has a parent"))?;
+      link_tree(&entry.path(), &new_path)?;
+    } else if is_dir {
+      carry_over_excluded(old, new, &rel_path, filter)?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Recreates the tree `from` at `to` out of hardlinks to its files, copying those that can't be linked
+fn link_tree(from: &Path, to: &Path) -> anyhow::Result<()> {
+  // the old backup already holds what was preserved, all of it is carried over
+  let preserve = PreserveConfig::default();
+  let metadata = from.symlink_metadata()?;
+  if metadata.is_dir() {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+      let entry = entry?;
+      link_tree(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    copy_metadata(from, to, &preserve)?;
+  } else if metadata.is_symlink() {
+    std::os::unix::fs::symlink(std::fs::read_link(from)?, to)?;
+    copy_metadata(from, to, &preserve)?;
+  } else if let Err(e) = std::fs::hard_link(from, to) {
+    debug!("can't link {} ({}), copying it", from.display(), e);
+    copy_entry(from, to, &preserve)?;
+  }
+  Ok(())
+}
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use fastcdc::v2020::StreamCDC;
+use tracing::*;
+
+use super::BackupSummary;
+use super::BackupTaskConfig;
+use crate::crypto::Cipher;
+use crate::crypto::ENCRYPTION_FILE_NAME;
+use crate::filter::Filter;
+use crate::manifest::*;
+use crate::retention::apply_policy;
+use crate::snapshot::*;
+use crate::storage::*;
+
+const MIN_CHUNK_SIZE: usize = 256 * 1024;
+const AVG_CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Content-addressed store of deduplicated chunks:
+///
+/// ```text
+/// dst/
+///   chunks/ab/ab12..ef   # chunk data, named by its BLAKE3 hash
+///   snapshots/<name>.json # manifest of a single backup run
+/// ```
+///
+/// With encryption, chunks and manifests are encrypted and chunks are named by a keyed hash instead.
+/// `dst` may be a local directory or any other [`Storage`]
+pub struct Repository {
+  storage: Box<dyn Storage>,
+  cipher: Option<Cipher>,
+  /// Hashes of the stored chunks, listed once on first use instead of asking the storage per chunk
+  chunks: Mutex<Option<HashSet<String>>>,
+}
+
+#[derive(Default)]
+struct ChunkStats {
+  new_chunks: usize,
+  new_bytes: u64,
+  reused_chunks: usize,
+  files: usize,
+}
+
+impl Repository {
+  pub fn open(dst: &Path) -> anyhow::Result<Self> {
+    Ok(Self { storage: open_storage(dst)?, cipher: None, chunks: Default::default() })
+  }
+
+  /// Opens the task's repository, with its encryption key if the task is encrypted
+  pub fn for_task(config: &BackupTaskConfig) -> anyhow::Result<Self> {
+    let storage = open_storage(&config.dst)?;
+    let cipher = Cipher::open(storage.as_ref(), config.encryption.as_ref())?;
+    Ok(Self { storage, cipher, chunks: Default::default() })
+  }
+
+  /// Name of the chunk holding `data`
+  pub fn chunk_id(&self, data: &[u8]) -> String {
+    match &self.cipher {
+      Some(cipher) => cipher.content_id(data),
+      None => blake3::hash(data).to_hex().to_string(),
+    }
+  }
+
+  fn chunk_key(hash: &str) -> String {
+    format!("chunks/{}/{}", &hash[..2], hash)
+  }
+
+  fn snapshot_key(name: &str) -> String {
+    format!("snapshots/{}.json", name)
+  }
+
+  /// Where the chunk is stored, whether it exists or not
+  pub fn chunk_path(&self, hash: &str) -> PathBuf {
+    self.storage.path(&Self::chunk_key(hash))
+  }
+
+  pub fn has_chunk(&self, hash: &str) -> anyhow::Result<bool> {
+    let mut chunks = self.chunks.lock().unwrap();
+    if chunks.is_none() {
+      *chunks =
+        Some(self.storage.list("chunks")?.iter().map(|chunk| Self::chunk_hash(chunk).to_string()).collect());
+    }
+    Ok(chunks.as_ref().is_some_and(|chunks| chunks.contains(hash)))
+  }
+
+  fn chunk_hash(chunk: &Object) -> &str {
+    chunk.key.rsplit('/').next().unwrap_or_default()
+  }
+
+  pub fn has_snapshot(&self, name: &str) -> anyhow::Result<bool> {
+    self.storage.exists(&Self::snapshot_key(name))
+  }
+
+  pub fn snapshots(&self) -> anyhow::Result<Vec<Snapshot>> {
+    let mut snapshots = self
+      .storage
+      .list("snapshots")?
+      .iter()
+      .filter_map(|object| object.key.strip_prefix("snapshots/")?.strip_suffix(".json"))
+      .filter_map(parse_snapshot_name)
+      .collect::<Vec<_>>();
+    snapshots.sort_by_key(|snapshot| snapshot.time);
+    Ok(snapshots)
+  }
+
+  pub fn read_snapshot(&self, name: &str) -> anyhow::Result<Manifest> {
+    let data = self.storage.get(&Self::snapshot_key(name))?;
+    match &self.cipher {
+      Some(cipher) => Ok(serde_json::from_slice(&cipher.decrypt(&data)?)?),
+      None => Ok(serde_json::from_slice(&data)?),
+    }
+  }
+
+  pub fn write_snapshot(&self, name: &str, manifest: &Manifest) -> anyhow::Result<()> {
+    let data = match &self.cipher {
+      Some(cipher) => cipher.encrypt(&serde_json::to_vec(manifest)?)?,
+      None => serde_json::to_vec(manifest)?,
+    };
+    self.storage.put(&Self::snapshot_key(name), &data)
+  }
+
+  pub fn read_chunk(&self, hash: &str) -> anyhow::Result<Vec<u8>> {
+    let data = self.storage.get(&Self::chunk_key(hash))?;
+    match &self.cipher {
+      Some(cipher) => cipher.decrypt(&data),
+      None => Ok(data),
+    }
+  }
+
+  pub fn remove_snapshot(&self, name: &str) -> anyhow::Result<()> {
+    self.storage.delete(&Self::snapshot_key(name))
+  }
+
+  /// Stored chunks that no snapshot refers to
+  fn unreferenced(&self) -> anyhow::Result<Vec<Object>> {
+    let mut referenced = HashSet::new();
+    for snapshot in self.snapshots()? {
+      for entry in self.read_snapshot(&snapshot.name)?.entries {
+        referenced.extend(entry.chunks);
+      }
+    }
+
+    let chunks = self.storage.list("chunks")?;
+    let unreferenced = chunks.into_iter().filter(|chunk| !referenced.contains(Self::chunk_hash(chunk)));
+    Ok(unreferenced.collect())
+  }
+
+  /// Paths of stored chunks that no snapshot refers to
+  pub fn unreferenced_chunks(&self) -> anyhow::Result<Vec<PathBuf>> {
+    Ok(self.unreferenced()?.iter().map(|chunk| self.storage.path(&chunk.key)).collect())
+  }
+
+  /// Removes chunks that no snapshot refers to; returns the number of removed chunks and their total size
+  pub fn collect_garbage(&self) -> anyhow::Result<(usize, u64)> {
+    let (mut removed, mut removed_bytes) = (0, 0);
+    for chunk in self.unreferenced()? {
+      self.storage.delete(&chunk.key)?;
+      if let Some(chunks) = self.chunks.lock().unwrap().as_mut() {
+        chunks.remove(Self::chunk_hash(&chunk));
+      }
+      removed_bytes += chunk.size;
+      removed += 1;
+    }
+
+    Ok((removed, removed_bytes))
+  }
+
+  /// Writes the chunk unless the repository already has it; returns whether it was written
+  fn write_chunk(&self, hash: &str, data: &[u8]) -> anyhow::Result<bool> {
+    if self.has_chunk(hash)? {
+      return Ok(false);
+    }
+
+    match &self.cipher {
+      Some(cipher) => self.storage.put(&Self::chunk_key(hash), &cipher.encrypt(data)?)?,
+      None => self.storage.put(&Self::chunk_key(hash), data)?,
+    }
+    if let Some(chunks) = self.chunks.lock().unwrap().as_mut() {
+      chunks.insert(hash.to_string());
+    }
+    Ok(true)
+  }
+
+  /// Splits the file into chunks and stores them; returns the chunk hashes and the hash of the whole file
+  fn store_file(&self, path: &Path, stats: &mut ChunkStats) -> anyhow::Result<(Vec<String>, String)> {
+    let file = std::fs::File::open(path)?;
+    let mut chunks = Vec::new();
+    let mut file_hasher = blake3::Hasher::new();
+
+    for chunk in StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+      let chunk = chunk?;
+      file_hasher.update(&chunk.data);
+      let hash = self.chunk_id(&chunk.data);
+      if self.write_chunk(&hash, &chunk.data)? {
+        stats.new_chunks += 1;
+        stats.new_bytes += chunk.length as u64;
+      } else {
+        stats.reused_chunks += 1;
+      }
+      chunks.push(hash);
+    }
+
+    Ok((chunks, file_hasher.finalize().to_hex().to_string()))
+  }
+}
+
+/// Copies the snapshots of the repository of `from` that the one of `to` lacks, along with their chunks,
+/// so either can be restored from on its own. Snapshots the retention of `to` would prune aren't copied,
+/// so a pruned copy stays pruned. Data is copied as stored: an encrypted repository stays encrypted with
+/// the same key. Chunks go first, so a snapshot never refers to a missing chunk. Returns the number of
+/// copied snapshots and chunks
+pub fn copy_repository(from: &BackupTaskConfig, to: &BackupTaskConfig) -> anyhow::Result<(usize, usize)> {
+  let source = Repository::open(&from.dst)?;
+  let target = Repository::open(&to.dst)?;
+  let (source, target) = (source.storage.as_ref(), target.storage.as_ref());
+
+  let params = match source.exists(ENCRYPTION_FILE_NAME)? {
+    true => Some(source.get(ENCRYPTION_FILE_NAME)?),
+    false => None,
+  };
+  let target_params = match target.exists(ENCRYPTION_FILE_NAME)? {
+    true => Some(target.get(ENCRYPTION_FILE_NAME)?),
+    false => None,
+  };
+  match (params, target_params) {
+    (params, target_params) if params == target_params => (),
+    (Some(params), None) if target.list("")?.is_empty() => target.put(ENCRYPTION_FILE_NAME, &params)?,
+    _ => anyhow::bail!("{} holds backups encrypted differently from {}", target, source),
+  }
+
+  let span = info_span!("copy", src = from.dst.display().to_string(), dst = to.dst.display().to_string());
+  let _guard = span.enter();
+  // the manifests say which chunks a snapshot needs
+  let repo = Repository::for_task(from)?;
+  let mut wanted = repo.snapshots()?;
+  if let Some(retention) = &to.retention {
+    wanted = apply_policy(&wanted, retention)
+      .into_iter()
+      .filter(|decision| decision.keep)
+      .map(|decision| decision.snapshot)
+      .collect();
+  }
+  let stored = target.list("snapshots")?.into_iter().map(|snapshot| snapshot.key).collect::<HashSet<_>>();
+  let missing = wanted
+    .iter()
+    .filter(|snapshot| !stored.contains(&Repository::snapshot_key(&snapshot.name)))
+    .collect::<Vec<_>>();
+
+  let mut needed = HashSet::new();
+  for snapshot in missing.iter() {
+    for entry in repo.read_snapshot(&snapshot.name)?.entries {
+      needed.extend(entry.chunks.iter().map(|hash| Repository::chunk_key(hash)));
+    }
+  }
+  let stored = target.list("chunks")?.into_iter().map(|chunk| chunk.key).collect::<HashSet<_>>();
+  let (mut chunks, mut bytes) = (0, 0);
+  for key in needed.iter().filter(|key| !stored.contains(*key)) {
+    let data = source.get(key)?;
+    target.put(key, &data)?;
+    chunks += 1;
+    bytes += data.len();
+  }
+  for snapshot in missing.iter() {
+    let key = Repository::snapshot_key(&snapshot.name);
+    target.put(&key, &source.get(&key)?)?;
+  }
+  info!("copied {} snapshots, {} chunks ({} bytes)", missing.len(), chunks, bytes);
+
+  Ok((missing.len(), chunks))
+}
+
+pub fn make_repository_backup(config: &BackupTaskConfig) -> anyhow::Result<BackupSummary> {
+  if !config.src.exists() {
+    anyhow::bail!("src directory does not exist: {}", config.src.display());
+  }
+
+  let repo = Repository::for_task(config)?;
+
+  let previous = match repo.snapshots()?.last() {
+    Some(snapshot) => {
+      debug!("previous snapshot: {}", snapshot);
+      repo
+        .read_snapshot(&snapshot.name)?
+        .entries
+        .into_iter()
+        .map(|entry| (entry.path.clone(), entry))
+        .collect()
+    }
+    None => HashMap::new(),
+  };
+
+  let span =
+    info_span!("chunk", src = config.src.display().to_string(), dst = config.dst.display().to_string());
+  let _guard = span.enter();
+  let mut stats = ChunkStats::default();
+  let mut entries = Vec::new();
+  if config.src.is_dir() {
+    let filter = Filter::new(config);
+    collect_entries(&repo, config, Path::new(""), &previous, &filter, &mut entries, &mut stats)?;
+  } else {
+    let name = config.src.file_name().map(PathBuf::from).unwrap_or_default();
+    entries.push(store_entry(&repo, &config.src, name, &previous, config.preserve.symlinks, &mut stats)?);
+  }
+  info!(
+    "processed {} files: {} new chunks ({} bytes), {} reused chunks",
+    stats.files, stats.new_chunks, stats.new_bytes, stats.reused_chunks
+  );
+  drop(_guard);
+
+  let name = new_snapshot_name(|name| repo.has_snapshot(name).unwrap_or(true));
+  let manifest = Manifest { src: config.src.clone(), entries };
+  repo.write_snapshot(&name, &manifest)?;
+  info!("created snapshot {}", name);
+
+  Ok((&manifest).into())
+}
+
+fn collect_entries(
+  repo: &Repository,
+  config: &BackupTaskConfig,
+  rel: &Path,
+  previous: &HashMap<PathBuf, ManifestEntry>,
+  filter: &Filter,
+  entries: &mut Vec<ManifestEntry>,
+  stats: &mut ChunkStats,
+) -> anyhow::Result<()> {
+  let mut children = std::fs::read_dir(config.src.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+  children.sort_by_key(|entry| entry.file_name());
+
+  for entry in children {
+    let path = entry.path();
+    let rel_path = rel.join(entry.file_name());
+    let symlink = crate::metadata::is_preserved_symlink(&path, &config.preserve);
+    if filter.is_excluded_rel(&rel_path, !symlink && path.is_dir()) {
+      debug!("skipping excluded {}", path.display());
+      continue;
+    }
+    if !symlink && !path.is_dir() && !path.is_file() {
+      debug!("skipping special file {}", path.display());
+      continue;
+    }
+
+    let entry = store_entry(repo, &path, rel_path.clone(), previous, config.preserve.symlinks, stats)?;
+    let is_dir = entry.kind == EntryKind::Dir;
+    entries.push(entry);
+
+    if is_dir {
+      collect_entries(repo, config, &rel_path, previous, filter, entries, stats)?;
+    }
+  }
+
+  Ok(())
+}
+
+fn store_entry(
+  repo: &Repository,
+  path: &Path,
+  rel_path: PathBuf,
+  previous: &HashMap<PathBuf, ManifestEntry>,
+  symlinks: bool,
+  stats: &mut ChunkStats,
+) -> anyhow::Result<ManifestEntry> {
+  let metadata = match symlinks {
+    true => path.symlink_metadata()?,
+    false => path.metadata()?,
+  };
+  let entry = ManifestEntry {
+    path: rel_path,
+    kind: EntryKind::Dir,
+    size: 0,
+    mtime: metadata.modified()?,
+    mode: metadata.permissions().mode(),
+    uid: Some(metadata.uid()),
+    gid: Some(metadata.gid()),
+    link: None,
+    hash: None,
+    chunks: vec![],
+  };
+
+  if metadata.is_dir() {
+    return Ok(entry);
+  }
+  if metadata.is_symlink() {
+    return Ok(ManifestEntry { kind: EntryKind::Symlink, link: Some(std::fs::read_link(path)?), ..entry });
+  }
+
+  stats.files += 1;
+
+  let unchanged = previous.get(&entry.path).filter(|prev| {
+    prev.kind == EntryKind::File
+      && prev.size == metadata.len()
+      && prev.mtime == entry.mtime
+      && prev.hash.is_some()
+      && prev.chunks.iter().all(|hash| repo.has_chunk(hash).unwrap_or(false))
+  });
+
+  let (chunks, hash) = match unchanged {
+    Some(prev) => {
+      stats.reused_chunks += prev.chunks.len();
+      (prev.chunks.clone(), prev.hash.clone().expect("checked above"))
+    }
+    None => {
+      debug!("chunking {}", path.display());
+      repo.store_file(path, stats)?
+    }
+  };
+
+  Ok(ManifestEntry { kind: EntryKind::File, size: metadata.len(), hash: Some(hash), chunks, ..entry })
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use tracing::*;
+
+use crate::config::CompareMode;
+use crate::config::PreserveConfig;
+use crate::fs::*;
+use crate::manifest::*;
+use crate::metadata::is_preserved_symlink;
+use crate::metadata::metadata_differs;
+
+/// What the mirror has to do to bring a file in `dst` up to date with its source
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Update {
+  UpToDate,
+  /// The content is current, only preserved metadata differs
+  Metadata,
+  Content,
+}
+
+/// Decides which files of the incremental mirror in `root` need copying, see [`CompareMode`].
+/// Keeps the entries of the mirror's last manifest as an index of content hashes
+pub struct Comparer<'a> {
+  fs: &'a dyn Fs,
+  root: PathBuf,
+  mode: CompareMode,
+  preserve: &'a PreserveConfig,
+  index: HashMap<PathBuf, ManifestEntry>,
+}
+
+impl<'a> Comparer<'a> {
+  pub fn new(fs: &'a dyn Fs, root: &Path, mode: CompareMode, preserve: &'a PreserveConfig) -> Self {
+    let manifest = fs.read(&root.join(MANIFEST_FILE_NAME)).ok();
+    let manifest = manifest.and_then(|data| serde_json::from_slice::<Manifest>(&data).ok());
+    let index = manifest
+      .into_iter()
+      .flat_map(|manifest| manifest.entries)
+      .map(|entry| (entry.path.clone(), entry))
+      .collect();
+    Self { fs, root: root.to_path_buf(), mode, preserve, index }
+  }
+
+  pub fn update(&self, src: &Path, dst: &Path) -> anyhow::Result<Update> {
+    let Some(dst_metadata) = self.fs.metadata(dst)? else {
+      return Ok(Update::Content);
+    };
+    if is_preserved_symlink(src, self.preserve) {
+      let same =
+        dst_metadata.kind == FileKind::Symlink && self.fs.read_link(dst)? == std::fs::read_link(src)?;
+      return Ok(if same { Update::UpToDate } else { Update::Content });
+    }
+    if dst_metadata.kind != FileKind::File {
+      return Ok(Update::Content);
+    }
+
+    let src_metadata = src.metadata()?;
+    let src_mtime = self.fs.stored_mtime(src_metadata.modified()?);
+    let same_content = match self.mode {
+      CompareMode::Mtime => dst_metadata.mtime >= src_mtime,
+      CompareMode::SizeMtime => dst_metadata.size == src_metadata.len() && dst_metadata.mtime == src_mtime,
+      CompareMode::Checksum => {
+        dst_metadata.size == src_metadata.len() && self.hash(dst, &dst_metadata)? == hash_file(src)?
+      }
+    };
+    if !same_content {
+      return Ok(Update::Content);
+    }
+
+    let ownership = self.fs.sets_ownership();
+    let metadata_differs = metadata_differs(&src_metadata, &dst_metadata, self.preserve, ownership)
+      // the other modes go by the modification time, so a differing one already means new content
+      || (self.mode == CompareMode::Checksum && self.preserve.times && dst_metadata.mtime != src_mtime);
+    Ok(if metadata_differs { Update::Metadata } else { Update::UpToDate })
+  }
+
+  /// Hash of `dst` from the index while its size and modification time match, otherwise read anew
+  fn hash(&self, dst: &Path, metadata: &FsMetadata) -> anyhow::Result<String> {
+    let indexed = dst
+      .strip_prefix(&self.root)
+      .ok()
+      .and_then(|rel| self.index.get(rel))
+      .filter(|entry| entry.size == metadata.size && self.fs.stored_mtime(entry.mtime) == metadata.mtime)
+      .and_then(|entry| entry.hash.clone());
+    match indexed {
+      Some(hash) => Ok(hash),
+      None => {
+        debug!("hashing {}", dst.display());
+        self.fs.hash(dst)
+      }
+    }
+  }
+
+  /// Drops the indexed hash of `dst`, whose content is being replaced
+  pub fn forget(&mut self, dst: &Path) {
+    if let Ok(rel) = dst.strip_prefix(&self.root) {
+      self.index.remove(rel);
+    }
+  }
+
+  /// The remaining index, to reuse its hashes for the next manifest
+  pub fn into_manifest(self, src: &Path) -> Manifest {
+    Manifest { src: src.to_path_buf(), entries: self.index.into_values().collect() }
+  }
+}
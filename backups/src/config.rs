@@ -1,6 +1,8 @@
 use std::path::Path;
 use std::path::PathBuf;
 
+use chrono::Local;
+use chrono::NaiveDateTime;
 use color_eyre::owo_colors::OwoColorize;
 use tracing::*;
 
@@ -11,31 +13,483 @@ use serde_derive::Serialize;
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
   pub tasks: Vec<BackupTaskConfig>,
+  /// How many backups may run at the same time across all tasks; the rest wait for their turn
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub max_concurrent: Option<usize>,
+  /// Where the last run of every task is recorded, see [`crate::state`]
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub state_file: Option<PathBuf>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct BackupTaskConfig {
+  /// Unique name the task is selected by on the command line and shown as in logs
+  pub name: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
   pub src: PathBuf,
   pub dst: PathBuf,
   pub on: BackupTriggerConfig,
+  /// Which snapshots to keep; pruning runs after every successful backup
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub retention: Option<RetentionConfig>,
+  /// Encrypt everything written under `dst`; needs the `repository` strategy or an archive format
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub encryption: Option<EncryptionConfig>,
+  /// Back up only files matching these globs, see [`crate::filter::Filter`]
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub include: Vec<String>,
+  /// Never back up paths matching these globs
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub exclude: Vec<String>,
+  /// What to do with excluded paths already present in `dst`
+  #[serde(default)]
+  pub excluded_in_dst: ExcludedPolicy,
+  /// Which file metadata is reproduced in `dst` and on restore
+  #[serde(default)]
+  pub preserve: PreserveConfig,
+  /// The incremental mirror refuses to delete more than this percentage of `dst` in one run unless
+  /// forced, so an unmounted or emptied `src` doesn't wipe it; 100 turns the guard off
+  #[serde(default = "default_max_delete")]
+  pub max_delete: u8,
+  /// How the incremental mirror tells a changed file from one it already holds
+  #[serde(default)]
+  pub compare: CompareMode,
+  /// What to do when the task is triggered while its previous run is still in progress
+  #[serde(default)]
+  pub overlap: OverlapPolicy,
+  /// Try a failed scheduled backup again before waiting for the next trigger
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub retry: Option<RetryConfig>,
+  /// Give up on a scheduled backup running longer than this, e.g. `2 hours`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub timeout: Option<String>,
+  /// Shell commands run around every backup of the task
+  #[serde(default, skip_serializing_if = "HooksConfig::is_empty")]
+  pub hooks: HooksConfig,
+  /// More places the backup is kept in after `dst`, e.g. a slower off-site copy
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub destinations: Vec<DestinationConfig>,
+}
+
+/// Another place a task's backup is kept in. It is written after `dst`, either by backing up `src`
+/// again with its own strategy, or by copying the repository in `dst` without reading `src` at all
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct DestinationConfig {
+  pub dst: PathBuf,
+  /// Replicate the repository in the task's `dst` here; needs the task to have the `repository` strategy
+  #[serde(default)]
+  pub copy: bool,
+  /// The task's strategy, snapshots and format apply unless set here; copies keep the repository's
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub strategy: Option<BackupStrategyConfig>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub snapshots: Option<bool>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub format: Option<OutputFormat>,
+  /// Which snapshots to keep here, independently of the task's retention
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub retention: Option<RetentionConfig>,
+}
+
+impl std::fmt::Display for DestinationConfig {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "`{}`", self.dst.display().bold())?;
+    if self.copy {
+      write!(f, " ({})", "copy".bold())?;
+    }
+    if let Some(strategy) = &self.strategy {
+      write!(f, " strategy: {}", strategy)?;
+    }
+    if let Some(retention) = &self.retention {
+      write!(f, " retention: {}", retention)?;
+    }
+    Ok(())
+  }
+}
+
+/// Commands run with `sh -c`; `BACKUPS_*` environment variables describe the task and the run,
+/// see [`crate::hooks`]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct HooksConfig {
+  /// Runs before the backup; if it fails, the backup is not made and counts as failed
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub before: Option<String>,
+  /// Runs after every backup, whether it succeeded or not
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub after: Option<String>,
+  /// Runs after a failed backup, following `after`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub on_failure: Option<String>,
+}
+
+impl HooksConfig {
+  pub fn is_empty(&self) -> bool {
+    self.before.is_none() && self.after.is_none() && self.on_failure.is_none()
+  }
+}
+
+impl std::fmt::Display for HooksConfig {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let hooks = [("before", &self.before), ("after", &self.after), ("on failure", &self.on_failure)];
+    let hooks = hooks
+      .iter()
+      .filter_map(|(name, command)| command.as_ref().map(|command| format!("{}: `{}`", name, command)));
+    write!(f, "{}", hooks.collect::<Vec<_>>().join(", "))
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RetryConfig {
+  /// How many times a backup is attempted in total, the first attempt included
+  pub attempts: u32,
+  /// Wait before the first retry, doubled before each next one, e.g. `30 seconds`
+  #[serde(default = "retry_default_backoff")]
+  pub backoff: String,
+}
+
+fn retry_default_backoff() -> String {
+  "1 minute".to_string()
+}
+
+impl std::fmt::Display for RetryConfig {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} attempts, backoff {}", self.attempts.bold(), self.backoff.bold())
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverlapPolicy {
+  /// Drop the new run
+  #[default]
+  Skip,
+  /// Run once more after the current run; further triggers meanwhile are merged into that run
+  Queue,
+}
+
+impl std::fmt::Display for OverlapPolicy {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      OverlapPolicy::Skip => write!(f, "skip"),
+      OverlapPolicy::Queue => write!(f, "queue"),
+    }
+  }
+}
+
+/// Everything is preserved by default; ownership is only restored when running as root
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct PreserveConfig {
+  /// Permission bits, including setuid/setgid/sticky
+  pub mode: bool,
+  /// Owner user and group
+  pub ownership: bool,
+  /// Access and modification times
+  pub times: bool,
+  /// Copy symlinks as links; otherwise they are followed
+  pub symlinks: bool,
+  /// Extended attributes
+  pub xattrs: bool,
+  /// POSIX ACLs, stored as `system.posix_acl_*` extended attributes
+  pub acls: bool,
+}
+
+impl Default for PreserveConfig {
+  fn default() -> Self {
+    Self { mode: true, ownership: true, times: true, symlinks: true, xattrs: true, acls: true }
+  }
+}
+
+impl std::fmt::Display for PreserveConfig {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let kept = [
+      ("mode", self.mode),
+      ("ownership", self.ownership),
+      ("times", self.times),
+      ("symlinks", self.symlinks),
+      ("xattrs", self.xattrs),
+      ("acls", self.acls),
+    ]
+    .into_iter()
+    .filter_map(|(name, kept)| kept.then_some(name))
+    .collect::<Vec<_>>();
+
+    match kept.is_empty() {
+      true => write!(f, "{}", "nothing".bold()),
+      false => write!(f, "{}", kept.join(", ").bold()),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompareMode {
+  /// Copy if `dst` is older than `src`
+  #[default]
+  Mtime,
+  /// Copy if the size or the modification time differ, either way
+  #[serde(rename = "size+mtime")]
+  SizeMtime,
+  /// Copy if the content differs. `src` is hashed on every run; hashes of `dst` come from its manifest
+  /// while the file's size and modification time still match it
+  Checksum,
+}
+
+impl std::fmt::Display for CompareMode {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CompareMode::Mtime => write!(f, "mtime"),
+      CompareMode::SizeMtime => write!(f, "size+mtime"),
+      CompareMode::Checksum => write!(f, "checksum"),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExcludedPolicy {
+  /// Leave them as they are
+  #[default]
+  Keep,
+  /// Remove them from `dst`
+  Delete,
+}
+
+impl std::fmt::Display for ExcludedPolicy {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ExcludedPolicy::Keep => write!(f, "keep"),
+      ExcludedPolicy::Delete => write!(f, "delete"),
+    }
+  }
 }
 
 impl std::fmt::Display for BackupTaskConfig {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    writeln!(f, "\ttask `{}` -> `{}`; on: {}", self.src.display().bold(), self.dst.display().bold(), self.on)
+    write!(
+      f,
+      "\ttask {} `{}` -> `{}`; on: {}",
+      self.name.bold(),
+      self.src.display().bold(),
+      self.dst.display().bold(),
+      self.on
+    )?;
+    if let Some(description) = &self.description {
+      write!(f, "; {}", description)?;
+    }
+    if let Some(retention) = &self.retention {
+      write!(f, "; retention: {}", retention)?;
+    }
+    if self.encryption.is_some() {
+      write!(f, "; {}", "encrypted".bold())?;
+    }
+    if !self.include.is_empty() {
+      write!(f, "; include: {}", self.include.join(", ").bold())?;
+    }
+    if !self.exclude.is_empty() {
+      write!(f, "; exclude: {} (in dst: {})", self.exclude.join(", ").bold(), self.excluded_in_dst)?;
+    }
+    if self.overlap != OverlapPolicy::default() {
+      write!(f, "; on overlap: {}", self.overlap.bold())?;
+    }
+    if self.max_delete != default_max_delete() {
+      write!(f, "; max delete: {}%", self.max_delete.bold())?;
+    }
+    if self.compare != CompareMode::default() {
+      write!(f, "; compare: {}", self.compare.bold())?;
+    }
+    if let Some(retry) = &self.retry {
+      write!(f, "; retry: {}", retry)?;
+    }
+    if let Some(timeout) = &self.timeout {
+      write!(f, "; timeout: {}", timeout.bold())?;
+    }
+    if !self.hooks.is_empty() {
+      write!(f, "; hooks: {}", self.hooks)?;
+    }
+    if self.preserve != PreserveConfig::default() {
+      write!(f, "; preserve: {}", self.preserve)?;
+    }
+    if !self.destinations.is_empty() {
+      let destinations = self.destinations.iter().map(|destination| destination.to_string());
+      write!(f, "; also to: {}", destinations.collect::<Vec<_>>().join(", "))?;
+    }
+    writeln!(f)
+  }
+}
+
+impl BackupTaskConfig {
+  /// A task backing up `src` into `dst` once a day, with everything else as a config leaving it out
+  pub fn new(name: &str, src: &Path, dst: &Path, strategy: BackupStrategyConfig) -> Self {
+    Self {
+      name: name.to_string(),
+      description: None,
+      src: src.to_path_buf(),
+      dst: dst.to_path_buf(),
+      on: BackupTriggerConfig {
+        trigger: BackupTrigger::Schedule { every: None, at: None, cron: None },
+        strategy,
+        snapshots: false,
+        format: OutputFormat::default(),
+        full_every: FullEveryConfig::default(),
+      },
+      retention: None,
+      encryption: None,
+      include: vec![],
+      exclude: vec![],
+      excluded_in_dst: ExcludedPolicy::default(),
+      preserve: PreserveConfig::default(),
+      max_delete: default_max_delete(),
+      compare: CompareMode::default(),
+      overlap: OverlapPolicy::default(),
+      retry: None,
+      timeout: None,
+      hooks: HooksConfig::default(),
+      destinations: vec![],
+    }
+  }
+
+  /// The task as it writes to `destination` instead of `dst`
+  pub fn for_destination(&self, destination: &DestinationConfig) -> Self {
+    let mut config = self.clone();
+    config.dst = destination.dst.clone();
+    config.retention = destination.retention.clone();
+    config.destinations = vec![];
+    if !destination.copy {
+      config.on.strategy = destination.strategy.clone().unwrap_or(config.on.strategy);
+      config.on.snapshots = destination.snapshots.unwrap_or(config.on.snapshots);
+      config.on.format = destination.format.unwrap_or(config.on.format);
+    }
+    config
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncryptionConfig {
+  /// Key is derived from the passphrase in this file with Argon2id
+  PassphraseFile(PathBuf),
+  /// Key is derived from the random bytes in this file, at least 32 of them
+  KeyFile(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct RetentionConfig {
+  pub keep_last: Option<usize>,
+  pub keep_hourly: Option<usize>,
+  pub keep_daily: Option<usize>,
+  pub keep_weekly: Option<usize>,
+  pub keep_monthly: Option<usize>,
+}
+
+impl std::fmt::Display for RetentionConfig {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let rules = [
+      ("last", self.keep_last),
+      ("hourly", self.keep_hourly),
+      ("daily", self.keep_daily),
+      ("weekly", self.keep_weekly),
+      ("monthly", self.keep_monthly),
+    ]
+    .into_iter()
+    .filter_map(|(name, count)| count.map(|count| format!("{} {}", name, count)))
+    .collect::<Vec<_>>();
+
+    if rules.is_empty() {
+      write!(f, "{}", "keep all".bold())
+    } else {
+      write!(f, "{}", rules.join(", ").bold())
+    }
   }
 }
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct BackupTriggerConfig {
   pub trigger: BackupTrigger,
   pub strategy: BackupStrategyConfig,
+  /// Keep timestamped snapshot directories instead of a single mirror (`incremental` only)
+  #[serde(default)]
+  pub snapshots: bool,
+  /// Write a directory tree or a compressed archive per run
+  #[serde(default)]
+  pub format: OutputFormat,
+  /// When the next run takes a new full backup instead of a differential set (`differential`), or starts
+  /// a new chain of incremental archives
+  #[serde(default)]
+  pub full_every: FullEveryConfig,
+}
+
+/// A new full backup is taken once any of the limits is reached
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct FullEveryConfig {
+  /// Runs per full backup, the full one included; `1` makes every run a full one
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub runs: Option<u32>,
+  /// Age of the full backup in days
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub days: Option<u32>,
+}
+
+impl Default for FullEveryConfig {
+  fn default() -> Self {
+    Self { runs: None, days: Some(7) }
+  }
+}
+
+impl FullEveryConfig {
+  /// Whether a full backup taken at `full` with `differentials` runs based on it since is due for a new one
+  pub fn is_due(&self, full: NaiveDateTime, differentials: u32) -> bool {
+    let runs_due = self.runs.is_some_and(|runs| differentials + 1 >= runs);
+    let days_due =
+      self.days.is_some_and(|days| Local::now().naive_local() - full >= chrono::Duration::days(days as i64));
+    runs_due || days_due
+  }
+}
+
+impl std::fmt::Display for FullEveryConfig {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let limits = [("runs", self.runs), ("days", self.days)]
+      .into_iter()
+      .filter_map(|(name, limit)| limit.map(|limit| format!("{} {}", limit, name)))
+      .collect::<Vec<_>>();
+
+    match limits.is_empty() {
+      true => write!(f, "{}", "never".bold()),
+      false => write!(f, "{}", limits.join(" or ").bold()),
+    }
+  }
+}
+
+impl BackupTriggerConfig {
+  /// Whether runs keep snapshots a retention policy can prune, rather than a single copy
+  pub fn keeps_history(&self) -> bool {
+    self.format.is_archive()
+      || matches!(self.strategy, BackupStrategyConfig::Repository)
+      || (matches!(self.strategy, BackupStrategyConfig::Incremental) && self.snapshots)
+  }
 }
 
 impl std::fmt::Display for BackupTriggerConfig {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "strategy: {}; trigger: {}", self.strategy, self.trigger)
+    write!(f, "strategy: {}", self.strategy)?;
+    if self.snapshots {
+      write!(f, " (snapshots)")?;
+    }
+    if matches!(self.strategy, BackupStrategyConfig::Differential) || self.format.is_archive() {
+      write!(f, " (full every {})", self.full_every)?;
+    }
+    if self.format.is_archive() {
+      write!(f, "; format: {}", self.format.bold())?;
+    }
+    write!(f, "; trigger: {}", self.trigger)
   }
 }
 
@@ -43,22 +497,66 @@ impl std::fmt::Display for BackupTriggerConfig {
 #[serde(rename_all = "kebab-case")]
 #[serde(tag = "type")]
 pub enum BackupTrigger {
-  // Change,
+  /// Back up when files under `src` change
+  Change {
+    /// Wait until nothing changed for this long, e.g. `10 seconds`
+    #[serde(default = "change_default_quiet")]
+    quiet: String,
+    /// Back up at the latest this long after the first change, even if changes keep coming
+    #[serde(default = "change_default_max_delay")]
+    max_delay: String,
+  },
   Schedule {
-    #[serde(default = "schedule_default_every")]
-    every: Vec<String>,
+    /// Intervals like `1 day` or `friday`; once a day if neither this nor `cron` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    every: Option<Vec<String>>,
     at: Option<String>,
+    /// Standard 5-field cron expression, or 6 fields with seconds first; replaces `every` and `at`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cron: Option<String>,
   },
 }
 
+impl BackupTrigger {
+  /// Checks that durations, intervals and cron expressions parse, so a bad config fails at load
+  pub fn validate(&self) -> anyhow::Result<()> {
+    match self {
+      BackupTrigger::Change { quiet, max_delay } => {
+        let quiet = crate::scheduler::parse_duration(quiet)?;
+        let max_delay = crate::scheduler::parse_duration(max_delay)?;
+        if quiet > max_delay {
+          anyhow::bail!("quiet period {:?} is longer than the maximum delay {:?}", quiet, max_delay);
+        }
+      }
+      BackupTrigger::Schedule { every, at, cron: Some(cron) } => {
+        if at.is_some() || every.is_some() {
+          anyhow::bail!("`cron` replaces `every` and `at`, remove them from the schedule");
+        }
+        crate::scheduler::parse_cron(cron)?;
+      }
+      BackupTrigger::Schedule { every, at, .. } => {
+        crate::scheduler::parse_schedule(&schedule_every(every))?;
+        if let Some(at) = at {
+          crate::scheduler::parse_at(at)?;
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
 impl std::fmt::Display for BackupTrigger {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
-      BackupTrigger::Schedule { every, at } => {
+      BackupTrigger::Change { quiet, max_delay } => {
+        write!(f, "on change; quiet: {}; max delay: {}", quiet.bold(), max_delay.bold())
+      }
+      BackupTrigger::Schedule { cron: Some(cron), .. } => write!(f, "cron: {}", cron.bold()),
+      BackupTrigger::Schedule { every, at, .. } => {
         write!(
           f,
           "every: {}; at: {}",
-          every.join(", ").bold(),
+          schedule_every(every).join(", ").bold(),
           at.as_deref().unwrap_or("<not specified>").bold()
         )
       }
@@ -71,6 +569,8 @@ impl std::fmt::Display for BackupTrigger {
 pub enum BackupStrategyConfig {
   Incremental,
   Differential,
+  /// Deduplicated store of content-defined chunks with a manifest per run
+  Repository,
 }
 
 impl std::fmt::Display for BackupStrategyConfig {
@@ -78,12 +578,52 @@ impl std::fmt::Display for BackupStrategyConfig {
     match self {
       BackupStrategyConfig::Incremental => write!(f, "inc"),
       BackupStrategyConfig::Differential => write!(f, "diff"),
+      BackupStrategyConfig::Repository => write!(f, "repo"),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+  #[default]
+  Directory,
+  /// A single zstd-compressed tar archive per run
+  TarZst,
+  /// A single gzip-compressed tar archive per run
+  TarGz,
+}
+
+impl OutputFormat {
+  pub fn is_archive(&self) -> bool {
+    *self != OutputFormat::Directory
+  }
+
+  /// File extension of archives written in this format
+  pub fn extension(&self) -> &'static str {
+    match self {
+      OutputFormat::Directory => "",
+      OutputFormat::TarZst => "tar.zst",
+      OutputFormat::TarGz => "tar.gz",
+    }
+  }
+}
+
+impl std::fmt::Display for OutputFormat {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      OutputFormat::Directory => write!(f, "dir"),
+      format => write!(f, "{}", format.extension()),
     }
   }
 }
 
 impl std::fmt::Display for Config {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    writeln!(f, "\tstate file: {}", self.state_file().display().bold())?;
+    if let Some(max_concurrent) = self.max_concurrent {
+      writeln!(f, "\tat most {} backups at a time", max_concurrent.bold())?;
+    }
     for task in &self.tasks {
       write!(f, "{}", task)?;
     }
@@ -93,15 +633,56 @@ impl std::fmt::Display for Config {
 
 impl Config {
   pub fn example() -> Self {
-    Config {
-      tasks: vec![BackupTaskConfig {
-        src: PathBuf::from("/src"),
-        dst: PathBuf::from("/dst"),
-        on: BackupTriggerConfig {
-          trigger: BackupTrigger::Schedule { every: vec!["10 seconds".to_string()], at: None },
-          strategy: BackupStrategyConfig::Incremental,
-        },
-      }],
+    let mut task = BackupTaskConfig {
+      description: Some("Back up /src into /dst every 10 seconds".to_string()),
+      retry: Some(RetryConfig { attempts: 3, backoff: retry_default_backoff() }),
+      timeout: Some("6 hours".to_string()),
+      hooks: HooksConfig {
+        before: None,
+        after: Some("echo \"$BACKUPS_TASK: $BACKUPS_STATUS in $BACKUPS_DURATION s\"".to_string()),
+        on_failure: None,
+      },
+      ..BackupTaskConfig::new(
+        "example",
+        Path::new("/src"),
+        Path::new("/dst"),
+        BackupStrategyConfig::Incremental,
+      )
+    };
+    task.on.trigger =
+      BackupTrigger::Schedule { every: Some(vec!["10 seconds".to_string()]), at: None, cron: None };
+
+    Config { tasks: vec![task], max_concurrent: Some(2), state_file: None }
+  }
+
+  /// Finds a task by its name, its `dst` or, if unambiguous, its `src`. Without a selector, the config
+  /// must have exactly one task
+  pub fn find_task(&self, selector: Option<&str>) -> anyhow::Result<&BackupTaskConfig> {
+    let Some(selector) = selector else {
+      return match self.tasks.as_slice() {
+        [task] => Ok(task),
+        _ => anyhow::bail!("config has {} tasks, select one with --task <name>", self.tasks.len()),
+      };
+    };
+
+    if let Some(task) = self.tasks.iter().find(|task| task.name == selector) {
+      return Ok(task);
+    }
+
+    let path = Path::new(selector);
+    if let Some(task) = self.tasks.iter().find(|task| task.dst == path) {
+      return Ok(task);
+    }
+
+    match self.tasks.iter().filter(|task| task.src == path).collect::<Vec<_>>().as_slice() {
+      [task] => Ok(task),
+      [] => anyhow::bail!("no task named `{}` or with such src or dst", selector),
+      tasks => anyhow::bail!(
+        "{} tasks back up `{}`, select one by name: {}",
+        tasks.len(),
+        selector,
+        tasks.iter().map(|task| task.name.as_str()).collect::<Vec<_>>().join(", ")
+      ),
     }
   }
 
@@ -118,9 +699,95 @@ impl Config {
 
     debug!("config: {:#?}", config);
 
+    if config.max_concurrent == Some(0) {
+      anyhow::bail!("`max-concurrent` must allow at least one backup");
+    }
+
+    let mut names = std::collections::HashSet::new();
+    for task in config.tasks.iter() {
+      if task.name.is_empty() {
+        anyhow::bail!("task `{}` -> `{}` has an empty name", task.src.display(), task.dst.display());
+      }
+      if !names.insert(task.name.as_str()) {
+        anyhow::bail!("task name `{}` is used more than once", task.name);
+      }
+      task
+        .on
+        .trigger
+        .validate()
+        .map_err(|e| anyhow::anyhow!("invalid trigger of task `{}`: {}", task.name, e))?;
+      if let Some(retry) = &task.retry {
+        if retry.attempts == 0 {
+          anyhow::bail!("`retry` of task `{}` needs at least one attempt", task.name);
+        }
+        crate::scheduler::parse_duration(&retry.backoff)
+          .map_err(|e| anyhow::anyhow!("invalid retry backoff of task `{}`: {}", task.name, e))?;
+      }
+      if let Some(timeout) = &task.timeout {
+        crate::scheduler::parse_duration(timeout)
+          .map_err(|e| anyhow::anyhow!("invalid timeout of task `{}`: {}", task.name, e))?;
+      }
+      let repository_archives = |on: &BackupTriggerConfig| {
+        matches!(on.strategy, BackupStrategyConfig::Repository) && on.format.is_archive()
+      };
+      if repository_archives(&task.on) {
+        anyhow::bail!(
+          "task `{}` can't write `{}` archives with the `repository` strategy",
+          task.name,
+          task.on.format
+        );
+      }
+      if task.retention.is_some() && !task.on.keeps_history() {
+        anyhow::bail!("task `{}` keeps no snapshots, `retention` has nothing to prune", task.name);
+      }
+      for destination in task.destinations.iter() {
+        let on = task.for_destination(destination).on;
+        if !destination.copy && repository_archives(&on) {
+          anyhow::bail!(
+            "destination `{}` of task `{}` can't write `{}` archives with the `repository` strategy",
+            destination.dst.display(),
+            task.name,
+            on.format
+          );
+        }
+        if destination.retention.is_some() && !on.keeps_history() {
+          anyhow::bail!(
+            "destination `{}` of task `{}` keeps no snapshots, `retention` has nothing to prune",
+            destination.dst.display(),
+            task.name
+          );
+        }
+        if destination.dst == task.dst || destination.dst == task.src {
+          anyhow::bail!(
+            "destination `{}` of task `{}` is its src or dst",
+            destination.dst.display(),
+            task.name
+          );
+        }
+        if destination.copy && !matches!(task.on.strategy, BackupStrategyConfig::Repository) {
+          anyhow::bail!(
+            "destination `{}` of task `{}` copies the repository, the task needs the `repository` strategy",
+            destination.dst.display(),
+            task.name
+          );
+        }
+      }
+      if task.max_delete > 100 {
+        anyhow::bail!("`max-delete` of task `{}` is a percentage, got {}", task.name, task.max_delete);
+      }
+      if task.compare == CompareMode::SizeMtime && !task.preserve.times {
+        // copies would get a fresh modification time and never match their source
+        anyhow::bail!("`compare: size+mtime` of task `{}` needs `preserve.times`", task.name);
+      }
+    }
+
     Ok(config)
   }
 
+  pub fn state_file(&self) -> PathBuf {
+    self.state_file.clone().unwrap_or_else(|| PathBuf::from(crate::state::DEFAULT_STATE_FILE))
+  }
+
   pub fn resolve(config_path: Option<PathBuf>, format: Option<String>) -> anyhow::Result<Self> {
     const DEFAULT_CONFIG_FILENAMES: &[&str] = &["config.yaml", "config.yml", "config.json"];
 
@@ -178,6 +845,19 @@ impl ConfigFormat {
   }
 }
 
-fn schedule_default_every() -> Vec<String> {
-  vec!["1 day".to_string()]
+pub fn default_max_delete() -> u8 {
+  50
+}
+
+/// The intervals of a schedule, once a day by default
+pub(crate) fn schedule_every(every: &Option<Vec<String>>) -> Vec<String> {
+  every.clone().unwrap_or_else(|| vec!["1 day".to_string()])
+}
+
+fn change_default_quiet() -> String {
+  "10 seconds".to_string()
+}
+
+fn change_default_max_delay() -> String {
+  "5 minutes".to_string()
 }
@@ -0,0 +1,248 @@
+use std::io::Read;
+use std::io::Write;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::DecryptorBE32;
+use chacha20poly1305::aead::stream::EncryptorBE32;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::KeyInit;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::XNonce;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+use crate::config::EncryptionConfig;
+use crate::storage::Storage;
+
+/// Name of the file in the root of `dst` describing how the key is derived; it holds no secrets
+pub const ENCRYPTION_FILE_NAME: &str = ".backups-encryption.json";
+
+const NONCE_SIZE: usize = 24;
+const STREAM_NONCE_SIZE: usize = 19;
+const TAG_SIZE: usize = 16;
+const SEGMENT_SIZE: usize = 1024 * 1024;
+const KEY_CHECK: &[u8] = b"backups key check";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct EncryptionParams {
+  /// Salt of the Argon2id passphrase derivation; empty for key files
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  salt: Vec<u8>,
+  /// Known plaintext encrypted with the key, tells a wrong key from corrupted data
+  check: Vec<u8>,
+}
+
+/// Authenticated encryption (XChaCha20-Poly1305) of everything a task writes under `dst`
+#[derive(Clone)]
+pub struct Cipher {
+  aead: XChaCha20Poly1305,
+  key: [u8; 32],
+  id_key: [u8; 32],
+}
+
+impl Cipher {
+  /// Loads the key for the task's `dst`. On first use with an empty `dst` the encryption parameters
+  /// are written there; a `dst` already holding backups must have been encrypted with the same key
+  pub fn open(dst: &dyn Storage, encryption: Option<&EncryptionConfig>) -> anyhow::Result<Option<Self>> {
+    let params = match dst.exists(ENCRYPTION_FILE_NAME)? {
+      true => Some(serde_json::from_slice::<EncryptionParams>(&dst.get(ENCRYPTION_FILE_NAME)?)?),
+      false => None,
+    };
+
+    let Some(encryption) = encryption else {
+      if params.is_some() {
+        anyhow::bail!("backups in {} are encrypted, but the task has no `encryption` configured", dst);
+      }
+      return Ok(None);
+    };
+
+    let Some(params) = params else {
+      if !dst.list("")?.is_empty() {
+        anyhow::bail!("{} already holds unencrypted backups, use an empty dst for encryption", dst);
+      }
+
+      let mut salt = vec![];
+      if let EncryptionConfig::PassphraseFile(_) = encryption {
+        salt = vec![0; 16];
+        OsRng.fill_bytes(&mut salt);
+      }
+      let cipher = Self::new(derive_key(encryption, &salt)?);
+      let params = EncryptionParams { salt, check: cipher.encrypt(KEY_CHECK)? };
+      dst.put(ENCRYPTION_FILE_NAME, &serde_json::to_vec(&params)?)?;
+      return Ok(Some(cipher));
+    };
+
+    let cipher = Self::new(derive_key(encryption, &params.salt)?);
+    if cipher.decrypt(&params.check).ok().as_deref() != Some(KEY_CHECK) {
+      anyhow::bail!("wrong key or passphrase for backups in {}", dst);
+    }
+    Ok(Some(cipher))
+  }
+
+  fn new(master: [u8; 32]) -> Self {
+    let key = blake3::derive_key("backups data encryption key", &master);
+    let id_key = blake3::derive_key("backups content id key", &master);
+    Self { aead: XChaCha20Poly1305::new(&key.into()), key, id_key }
+  }
+
+  /// Keyed hash naming stored content, so equal names don't reveal equal plaintext to others
+  pub fn content_id(&self, data: &[u8]) -> String {
+    blake3::keyed_hash(&self.id_key, data).to_hex().to_string()
+  }
+
+  /// Encrypts a small blob in one piece: a random nonce followed by the ciphertext
+  pub fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut nonce = [0; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = self
+      .aead
+      .encrypt(XNonce::from_slice(&nonce), plaintext)
+      .map_err(|_| anyhow::anyhow!("failed to encrypt"))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+  }
+
+  pub fn decrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if data.len() < NONCE_SIZE + TAG_SIZE {
+      anyhow::bail!("encrypted data is truncated");
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+    self
+      .aead
+      .decrypt(XNonce::from_slice(nonce), ciphertext)
+      .map_err(|_| anyhow::anyhow!("failed to decrypt, the data is corrupted or was tampered with"))
+  }
+
+  /// Encrypts a stream of any length in authenticated segments, see [`DecryptReader`]
+  pub fn encrypt_writer<W: Write>(&self, mut inner: W) -> std::io::Result<EncryptWriter<W>> {
+    let mut nonce = [0; STREAM_NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    inner.write_all(&nonce)?;
+    let encryptor = EncryptorBE32::new(&self.key.into(), (&nonce).into());
+    Ok(EncryptWriter { inner, encryptor: Some(encryptor), buf: Vec::with_capacity(SEGMENT_SIZE) })
+  }
+
+  pub fn decrypt_reader<R: Read>(&self, mut inner: R) -> std::io::Result<DecryptReader<R>> {
+    let mut nonce = [0; STREAM_NONCE_SIZE];
+    inner.read_exact(&mut nonce)?;
+    let decryptor = DecryptorBE32::new(&self.key.into(), (&nonce).into());
+    Ok(DecryptReader { inner, decryptor: Some(decryptor), raw: vec![], plain: vec![], pos: 0 })
+  }
+}
+
+fn derive_key(encryption: &EncryptionConfig, salt: &[u8]) -> anyhow::Result<[u8; 32]> {
+  let mut key = [0; 32];
+  match encryption {
+    EncryptionConfig::PassphraseFile(path) => {
+      let passphrase = std::fs::read_to_string(path)?;
+      let passphrase = passphrase.trim_end_matches(['\n', '\r']);
+      if passphrase.is_empty() {
+        anyhow::bail!("passphrase file {} is empty", path.display());
+      }
+      argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("failed to derive key: {}", e))?;
+    }
+    EncryptionConfig::KeyFile(path) => {
+      let content = std::fs::read(path)?;
+      if content.len() < key.len() {
+        anyhow::bail!("key file {} must hold at least {} bytes", path.display(), key.len());
+      }
+      key = blake3::hash(&content).into();
+    }
+  }
+  Ok(key)
+}
+
+/// Writes the stream as segments of [`SEGMENT_SIZE`] bytes, each sealed with its counter and whether
+/// it's the last one, so segments can't be reordered or dropped. [`EncryptWriter::finish`] must be called
+pub struct EncryptWriter<W: Write> {
+  inner: W,
+  encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+  buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+  /// Seals the last segment and returns the underlying writer
+  pub fn finish(mut self) -> std::io::Result<W> {
+    let encryptor = self.encryptor.take().expect("finish is only called once");
+    let segment =
+      encryptor.encrypt_last(self.buf.as_slice()).map_err(|_| std::io::Error::other("failed to encrypt"))?;
+    self.inner.write_all(&segment)?;
+    Ok(self.inner)
+  }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.buf.extend_from_slice(buf);
+    // a full segment is only sealed once more data follows, the last one is sealed by `finish`
+    while self.buf.len() > SEGMENT_SIZE {
+      let encryptor = self.encryptor.as_mut().expect("writer is not finished");
+      let segment = encryptor
+        .encrypt_next(&self.buf[..SEGMENT_SIZE])
+        .map_err(|_| std::io::Error::other("failed to encrypt"))?;
+      self.inner.write_all(&segment)?;
+      self.buf.drain(..SEGMENT_SIZE);
+    }
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+pub struct DecryptReader<R: Read> {
+  inner: R,
+  decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+  raw: Vec<u8>,
+  plain: Vec<u8>,
+  pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+  fn next_segment(&mut self) -> std::io::Result<()> {
+    // read one byte past the segment to know whether it's the last one
+    let want = SEGMENT_SIZE + TAG_SIZE + 1;
+    while self.raw.len() < want {
+      let len = self.raw.len();
+      self.raw.resize(want, 0);
+      let read = self.inner.read(&mut self.raw[len..])?;
+      self.raw.truncate(len + read);
+      if read == 0 {
+        break;
+      }
+    }
+
+    let corrupted = |_| std::io::Error::new(std::io::ErrorKind::InvalidData, "encrypted stream is corrupted");
+    if self.raw.len() == want {
+      let decryptor = self.decryptor.as_mut().expect("stream is not finished");
+      self.plain = decryptor.decrypt_next(&self.raw[..want - 1]).map_err(corrupted)?;
+      self.raw.drain(..want - 1);
+    } else {
+      let decryptor = self.decryptor.take().expect("stream is not finished");
+      self.plain = decryptor.decrypt_last(self.raw.as_slice()).map_err(corrupted)?;
+      self.raw.clear();
+    }
+    self.pos = 0;
+    Ok(())
+  }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    while self.pos == self.plain.len() {
+      if self.decryptor.is_none() {
+        return Ok(0);
+      }
+      self.next_segment()?;
+    }
+
+    let len = buf.len().min(self.plain.len() - self.pos);
+    buf[..len].copy_from_slice(&self.plain[self.pos..self.pos + len]);
+    self.pos += len;
+    Ok(len)
+  }
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use tracing::*;
+
+use crate::config::PreserveConfig;
+use crate::metadata::*;
+
+/// Files smaller than this are copied whole, a signature wouldn't save much
+pub const MIN_DELTA_SIZE: u64 = 1024 * 1024;
+const MIN_BLOCK_SIZE: usize = 1024;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+/// How much of `src` is read at once
+const READ_SIZE: usize = 1024 * 1024;
+
+/// How much of a file [`update_file`] had to rewrite
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeltaSummary {
+  /// Bytes of `dst` left where they were
+  pub kept: u64,
+  /// Bytes of `dst` copied within it from another offset
+  pub moved: u64,
+  /// Bytes read from `src` and written
+  pub written: u64,
+}
+
+/// Brings the file `dst`, an older version of `src`, up to date by rewriting only the blocks that
+/// changed, like `rsync --inplace`. Anything else, like a new, small, read-only or hardlinked `dst`,
+/// is copied whole by [`copy_entry`], then there's no summary
+pub fn update_file(
+  src: &Path,
+  dst: &Path,
+  preserve: &PreserveConfig,
+) -> anyhow::Result<Option<DeltaSummary>> {
+  let file = match is_delta_candidate(src, dst, preserve)? {
+    true => std::fs::OpenOptions::new().read(true).write(true).open(dst).ok(),
+    false => None,
+  };
+  let Some(file) = file else {
+    copy_entry(src, dst, preserve)?;
+    return Ok(None);
+  };
+
+  // a run interrupted halfway must not leave a file that looks newer than `src`
+  filetime::set_file_mtime(dst, filetime::FileTime::zero())?;
+  let summary = apply_delta(&mut std::fs::File::open(src)?, &file)?;
+  drop(file);
+  debug!(
+    "updated {}: {} bytes kept, {} moved, {} written",
+    dst.display(),
+    summary.kept,
+    summary.moved,
+    summary.written
+  );
+
+  copy_metadata(src, dst, preserve)?;
+  if !preserve.times {
+    filetime::set_file_mtime(dst, filetime::FileTime::now())?;
+  }
+  Ok(Some(summary))
+}
+
+/// A large regular file replacing a large regular file no other path shares
+fn is_delta_candidate(src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<bool> {
+  if is_preserved_symlink(src, preserve) {
+    return Ok(false);
+  }
+  let Ok(dst_metadata) = dst.symlink_metadata() else {
+    return Ok(false);
+  };
+  let src_metadata = src.metadata()?;
+  Ok(
+    src_metadata.is_file()
+      && dst_metadata.is_file()
+      && dst_metadata.nlink() == 1
+      && src_metadata.len() >= MIN_DELTA_SIZE
+      && dst_metadata.len() >= MIN_DELTA_SIZE,
+  )
+}
+
+/// Block size for a file of `len` bytes, about its square root like rsync picks it
+fn block_size(len: u64) -> usize {
+  ((len as f64).sqrt() as usize).next_multiple_of(MIN_BLOCK_SIZE).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// rsync's weak checksum of a window, cheap to roll forward one byte at a time
+#[derive(Clone, Copy, Default)]
+struct Rolling {
+  a: u32,
+  b: u32,
+  len: u32,
+}
+
+impl Rolling {
+  fn new(data: &[u8]) -> Self {
+    let mut rolling = Self::default();
+    for &byte in data {
+      rolling.a = rolling.a.wrapping_add(byte as u32);
+      rolling.b = rolling.b.wrapping_add(rolling.a);
+    }
+    rolling.len = data.len() as u32;
+    rolling
+  }
+
+  fn digest(&self) -> u32 {
+    (self.a & 0xffff) | (self.b << 16)
+  }
+
+  /// Moves the window past `out`, taking in `next` if there is one
+  fn roll(&mut self, out: u8, next: Option<u8>) {
+    self.a = self.a.wrapping_sub(out as u32);
+    self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32));
+    match next {
+      Some(next) => {
+        self.a = self.a.wrapping_add(next as u32);
+        self.b = self.b.wrapping_add(self.a);
+      }
+      None => self.len -= 1,
+    }
+  }
+}
+
+/// Weak and strong checksums of the blocks of the old file
+struct Signature {
+  block_size: usize,
+  len: u64,
+  blocks: HashMap<u32, Vec<(u64, usize, blake3::Hash)>>,
+}
+
+impl Signature {
+  fn new(file: &std::fs::File) -> anyhow::Result<Self> {
+    let len = file.metadata()?.len();
+    let block_size = block_size(len);
+    let mut blocks = HashMap::<u32, Vec<_>>::new();
+    let mut block = vec![0; block_size];
+    let mut offset = 0;
+    while offset < len {
+      let size = block_size.min((len - offset) as usize);
+      file.read_exact_at(&mut block[..size], offset)?;
+      let block = &block[..size];
+      blocks.entry(Rolling::new(block).digest()).or_default().push((offset, size, blake3::hash(block)));
+      offset += size as u64;
+    }
+    Ok(Self { block_size, len, blocks })
+  }
+
+  /// Offset of a block of the old file equal to `window`, one that is still intact when the new
+  /// content is written up to `written`. The block in place is preferred, it needn't be touched at all
+  fn find(&self, weak: u32, window: &[u8], written: u64) -> Option<u64> {
+    let candidates = self.blocks.get(&weak)?;
+    let mut strong = None;
+    let mut found = None;
+    for &(offset, size, hash) in candidates {
+      if size != window.len() || offset < written {
+        continue;
+      }
+      if *strong.get_or_insert_with(|| blake3::hash(window)) != hash {
+        continue;
+      }
+      if offset == written {
+        return Some(offset);
+      }
+      found = found.or(Some(offset));
+    }
+    found
+  }
+}
+
+/// Rewrites `dst` in place into a copy of `src`. The new content goes in front to back, so blocks of the
+/// old file are only reused from offsets not yet overwritten
+fn apply_delta(src: &mut std::fs::File, dst: &std::fs::File) -> anyhow::Result<DeltaSummary> {
+  let signature = Signature::new(dst)?;
+  let block_size = signature.block_size;
+  let mut summary = DeltaSummary::default();
+
+  // `data` holds `src` from the start of the pending literal on; `pos` is where the window starts
+  let mut data = Vec::new();
+  let mut literal = 0;
+  let mut pos = 0;
+  let mut written = 0u64;
+  let mut eof = false;
+  let mut rolling = None;
+
+  loop {
+    if !eof && data.len() - pos < block_size + 1 {
+      data.drain(..literal);
+      pos -= literal;
+      literal = 0;
+      let start = data.len();
+      data.resize(start + READ_SIZE, 0);
+      let read = read_full(src, &mut data[start..])?;
+      data.truncate(start + read);
+      eof = read < READ_SIZE;
+    }
+    if pos == data.len() {
+      break;
+    }
+
+    let end = data.len().min(pos + block_size);
+    let weak = rolling.get_or_insert_with(|| Rolling::new(&data[pos..end]));
+    let offset = written + (pos - literal) as u64;
+    if let Some(block) = signature.find(weak.digest(), &data[pos..end], offset) {
+      dst.write_all_at(&data[literal..pos], written)?;
+      summary.written += (pos - literal) as u64;
+      if block == offset {
+        summary.kept += (end - pos) as u64;
+      } else {
+        let mut moved = vec![0; end - pos];
+        dst.read_exact_at(&mut moved, block)?;
+        dst.write_all_at(&moved, offset)?;
+        summary.moved += moved.len() as u64;
+      }
+      written = offset + (end - pos) as u64;
+      pos = end;
+      literal = pos;
+      rolling = None;
+      continue;
+    }
+
+    weak.roll(data[pos], data.get(pos + block_size).copied());
+    pos += 1;
+    if pos - literal >= block_size {
+      dst.write_all_at(&data[literal..pos], written)?;
+      summary.written += (pos - literal) as u64;
+      written += (pos - literal) as u64;
+      literal = pos;
+    }
+  }
+
+  dst.write_all_at(&data[literal..pos], written)?;
+  summary.written += (pos - literal) as u64;
+  written += (pos - literal) as u64;
+  if written != signature.len {
+    dst.set_len(written)?;
+  }
+  Ok(summary)
+}
+
+/// Reads until `buf` is full or the file ends, returns how much was read
+fn read_full(file: &mut std::fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+  let mut read = 0;
+  while read < buf.len() {
+    match file.read(&mut buf[read..]) {
+      Ok(0) => break,
+      Ok(n) => read += n,
+      Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(read)
+}
@@ -0,0 +1,58 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::config::BackupTaskConfig;
+
+/// Decides which paths under a task's `src` are backed up.
+///
+/// Patterns are globs matched against the path relative to `src` (`cache/**`, `**/*.log`); patterns
+/// without a `/` match the name of a file or directory at any depth (`node_modules`, `*.tmp`).
+/// Excluded directories are skipped with everything in them. With `include` patterns, only matching
+/// files are backed up, directories are still descended into to find them
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+  root: PathBuf,
+  include: Vec<String>,
+  exclude: Vec<String>,
+}
+
+impl Filter {
+  pub fn new(config: &BackupTaskConfig) -> Self {
+    Self { root: config.src.clone(), include: config.include.clone(), exclude: config.exclude.clone() }
+  }
+
+  /// Whether the filter lets everything through
+  pub fn is_empty(&self) -> bool {
+    self.include.is_empty() && self.exclude.is_empty()
+  }
+
+  /// Whether `path`, somewhere under `src`, is left out of the backup
+  pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+    match path.strip_prefix(&self.root) {
+      Ok(rel) => self.is_excluded_rel(rel, is_dir),
+      Err(_) => false,
+    }
+  }
+
+  /// Whether `rel`, a path relative to `src`, is left out of the backup
+  pub fn is_excluded_rel(&self, rel: &Path, is_dir: bool) -> bool {
+    if rel.as_os_str().is_empty() {
+      return false;
+    }
+
+    if self.exclude.iter().any(|pattern| matches(pattern, rel)) {
+      return true;
+    }
+
+    !is_dir && !self.include.is_empty() && !self.include.iter().any(|pattern| matches(pattern, rel))
+  }
+}
+
+fn matches(pattern: &str, rel: &Path) -> bool {
+  if !pattern.contains('/') {
+    if let Some(name) = rel.file_name() {
+      return glob_match::glob_match(pattern, &name.to_string_lossy());
+    }
+  }
+  glob_match::glob_match(pattern.trim_start_matches('/'), &rel.to_string_lossy())
+}
@@ -0,0 +1,492 @@
+use std::ffi::OsString;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use tracing::*;
+
+use crate::config::PreserveConfig;
+use crate::manifest::hash_file;
+use crate::manifest::write_atomic;
+use crate::metadata::is_preserved_symlink;
+
+const SFTP_SCHEME: &str = "sftp://";
+const DEFAULT_SSH_PORT: u16 = 22;
+/// Keys tried, in order, when neither `BACKUPS_SSH_KEY` nor an agent is available; like `ssh` does
+const DEFAULT_SSH_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+/// SFTP status codes, from the protocol draft
+const FX_NO_SUCH_FILE: i32 = 2;
+const FX_PERMISSION_DENIED: i32 = 3;
+/// File type bits of SFTP permissions, POSIX values whatever the local platform
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+  File,
+  Dir,
+  Symlink,
+  /// Devices, sockets and pipes, which backups skip
+  Other,
+}
+
+/// Metadata of an entry, not following symlinks
+#[derive(Clone, Debug)]
+pub struct FsMetadata {
+  pub kind: FileKind,
+  pub size: u64,
+  pub mtime: SystemTime,
+  pub mode: u32,
+  pub uid: u32,
+  pub gid: u32,
+}
+
+/// Filesystem the incremental mirror is written to: the local one, or a directory on an SFTP server.
+/// Sources are always local paths, destinations are absolute paths on this filesystem
+pub trait Fs {
+  /// `None` if nothing is at `path`
+  fn metadata(&self, path: &Path) -> anyhow::Result<Option<FsMetadata>>;
+  fn read_dir(&self, path: &Path) -> anyhow::Result<Vec<(OsString, FsMetadata)>>;
+  fn read_link(&self, path: &Path) -> anyhow::Result<PathBuf>;
+  fn read(&self, path: &Path) -> anyhow::Result<Vec<u8>>;
+  /// BLAKE3 hash of the file's content, as in manifests
+  fn hash(&self, path: &Path) -> anyhow::Result<String>;
+  /// Writes `data` so a reader never sees a half-written file
+  fn write(&self, path: &Path, data: &[u8]) -> anyhow::Result<()>;
+  fn create_dir_all(&self, path: &Path) -> anyhow::Result<()>;
+  /// Removes a file, a symlink or a whole directory tree
+  fn remove(&self, path: &Path) -> anyhow::Result<()>;
+  /// Replaces whatever is at `dst` with a copy of the local file or, if symlinks are preserved,
+  /// symlink `src` along with its metadata
+  fn copy_entry(&self, src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()>;
+  /// [`Fs::copy_entry`] for a `dst` that may hold an older version of `src`, which a filesystem may
+  /// update in place rather than write whole again
+  fn update_entry(&self, src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()> {
+    self.copy_entry(src, dst, preserve)
+  }
+  /// Applies the metadata of the local `src` to `dst` as far as `preserve` asks
+  fn copy_metadata(&self, src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()>;
+  /// What a modification time becomes when stored here
+  fn stored_mtime(&self, mtime: SystemTime) -> SystemTime {
+    mtime
+  }
+  /// Whether files here can be given to the owner of their source, so ownership is worth comparing
+  fn sets_ownership(&self) -> bool {
+    true
+  }
+}
+
+/// Whether `dst` names a directory on an SFTP server
+pub fn is_sftp(dst: &Path) -> bool {
+  dst.to_str().is_some_and(|dst| dst.starts_with(SFTP_SCHEME))
+}
+
+/// Filesystem holding a task's `dst`, connected if it is remote, and the path of `dst` on it
+pub fn open_fs(dst: &Path) -> anyhow::Result<(Box<dyn Fs>, PathBuf)> {
+  match dst.to_str().and_then(|dst| dst.strip_prefix(SFTP_SCHEME)) {
+    Some(location) => {
+      let location = SftpLocation::parse(location)?;
+      Ok((Box::new(SftpFs::connect(&location)?), location.path))
+    }
+    None => Ok((Box::new(LocalFs), dst.to_path_buf())),
+  }
+}
+
+pub struct LocalFs;
+
+impl From<std::fs::Metadata> for FsMetadata {
+  fn from(metadata: std::fs::Metadata) -> Self {
+    let file_type = metadata.file_type();
+    let kind = match () {
+      _ if file_type.is_dir() => FileKind::Dir,
+      _ if file_type.is_file() => FileKind::File,
+      _ if file_type.is_symlink() => FileKind::Symlink,
+      _ => FileKind::Other,
+    };
+    Self {
+      kind,
+      size: metadata.len(),
+      mtime: metadata.modified().unwrap_or(UNIX_EPOCH),
+      mode: metadata.mode(),
+      uid: metadata.uid(),
+      gid: metadata.gid(),
+    }
+  }
+}
+
+impl Fs for LocalFs {
+  fn metadata(&self, path: &Path) -> anyhow::Result<Option<FsMetadata>> {
+    match path.symlink_metadata() {
+      Ok(metadata) => Ok(Some(metadata.into())),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  fn read_dir(&self, path: &Path) -> anyhow::Result<Vec<(OsString, FsMetadata)>> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+      let entry = entry?;
+      entries.push((entry.file_name(), entry.path().symlink_metadata()?.into()));
+    }
+    Ok(entries)
+  }
+
+  fn read_link(&self, path: &Path) -> anyhow::Result<PathBuf> {
+    Ok(std::fs::read_link(path)?)
+  }
+
+  fn read(&self, path: &Path) -> anyhow::Result<Vec<u8>> {
+    Ok(std::fs::read(path)?)
+  }
+
+  fn hash(&self, path: &Path) -> anyhow::Result<String> {
+    Ok(hash_file(path)?)
+  }
+
+  fn write(&self, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    write_atomic(path, data)
+  }
+
+  fn create_dir_all(&self, path: &Path) -> anyhow::Result<()> {
+    Ok(std::fs::create_dir_all(path)?)
+  }
+
+  fn remove(&self, path: &Path) -> anyhow::Result<()> {
+    match path.symlink_metadata()?.is_dir() {
+      true => std::fs::remove_dir_all(path)?,
+      false => std::fs::remove_file(path)?,
+    }
+    Ok(())
+  }
+
+  fn copy_entry(&self, src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()> {
+    crate::metadata::copy_entry(src, dst, preserve)
+  }
+
+  fn update_entry(&self, src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()> {
+    crate::delta::update_file(src, dst, preserve)?;
+    Ok(())
+  }
+
+  fn copy_metadata(&self, src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()> {
+    crate::metadata::copy_metadata(src, dst, preserve)
+  }
+}
+
+/// `[user@]host[:port]/path` of an `sftp://` dst. The path is absolute; `host:/path` with an empty
+/// port is accepted too, like `scp` writes it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SftpLocation {
+  pub user: String,
+  pub host: String,
+  pub port: u16,
+  pub path: PathBuf,
+}
+
+impl SftpLocation {
+  /// Parses the part of the dst after `sftp://`; the user defaults to `$USER`
+  pub fn parse(location: &str) -> anyhow::Result<Self> {
+    let Some((authority, path)) = location.find('/').map(|i| location.split_at(i)) else {
+      anyhow::bail!("no path in `{}{}`", SFTP_SCHEME, location);
+    };
+    let (user, host) = match authority.rsplit_once('@') {
+      Some((user, host)) => (user.to_string(), host),
+      None => (std::env::var("USER").unwrap_or_default(), authority),
+    };
+    let (host, port) = match host.rsplit_once(':') {
+      Some((host, "")) => (host, DEFAULT_SSH_PORT),
+      Some((host, port)) => (
+        host,
+        port
+          .parse()
+          .map_err(|_| anyhow::anyhow!("invalid port `{}` in `{}{}`", port, SFTP_SCHEME, location))?,
+      ),
+      None => (host, DEFAULT_SSH_PORT),
+    };
+    if host.is_empty() || user.is_empty() {
+      anyhow::bail!("no host or user in `{}{}`", SFTP_SCHEME, location);
+    }
+    Ok(Self { user, host: host.to_string(), port, path: PathBuf::from(path) })
+  }
+}
+
+impl std::fmt::Display for SftpLocation {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}{}@{}:{}{}", SFTP_SCHEME, self.user, self.host, self.port, self.path.display())
+  }
+}
+
+/// Directory on an SSH server, written over SFTP. The host key must be in `~/.ssh/known_hosts`
+/// (or the file in `BACKUPS_SSH_KNOWN_HOSTS`). Authenticates with the key in `BACKUPS_SSH_KEY` if set,
+/// otherwise with the SSH agent or the default keys in `~/.ssh`. Extended attributes and ACLs have no
+/// SFTP counterpart and are not copied
+pub struct SftpFs {
+  sftp: ssh2::Sftp,
+  /// Only root may give files away, anyone else would fail to set the owner of every file. Taken from
+  /// `id -u` on the server; where commands can't be run, e.g. on SFTP-only accounts, until a chown fails
+  is_root: AtomicBool,
+}
+
+impl SftpFs {
+  pub fn connect(location: &SftpLocation) -> anyhow::Result<Self> {
+    let tcp = TcpStream::connect((location.host.as_str(), location.port))
+      .map_err(|e| anyhow::anyhow!("failed to connect to {}:{}: {}", location.host, location.port, e))?;
+    let mut session = ssh2::Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.handshake()?;
+    check_host_key(&session, location)?;
+    authenticate(&session, location)?;
+    debug!("connected to {}", location);
+    let is_root = match remote_uid(&session) {
+      Some(uid) => uid == 0,
+      None => {
+        debug!("can't run `id -u` on {}, setting owners until the server refuses", location.host);
+        true
+      }
+    };
+    Ok(Self { sftp: session.sftp()?, is_root: AtomicBool::new(is_root) })
+  }
+
+  fn metadata_of(stat: &ssh2::FileStat) -> FsMetadata {
+    let mode = stat.perm.unwrap_or_default();
+    let kind = match mode & S_IFMT {
+      S_IFDIR => FileKind::Dir,
+      S_IFREG => FileKind::File,
+      S_IFLNK => FileKind::Symlink,
+      _ => FileKind::Other,
+    };
+    FsMetadata {
+      kind,
+      size: stat.size.unwrap_or_default(),
+      mtime: UNIX_EPOCH + Duration::from_secs(stat.mtime.unwrap_or_default()),
+      mode,
+      uid: stat.uid.unwrap_or_default(),
+      gid: stat.gid.unwrap_or_default(),
+    }
+  }
+
+  /// Uploads `src` into a hidden file next to `dst` and renames it into place, so an interrupted upload
+  /// is never mistaken for an up-to-date file. SFTP v3 can't rename over a file, `dst` is removed first
+  fn upload(&self, src: &mut impl Read, dst: &Path) -> anyhow::Result<()> {
+    let name = dst.file_name().expect("uploaded path always has a file name").to_string_lossy();
+    let partial = dst.with_file_name(format!(".partial-{}", name));
+    let mut file = self.sftp.create(&partial)?;
+    std::io::copy(src, &mut file)?;
+    file.flush()?;
+    drop(file);
+
+    if self.metadata(dst)?.is_some() {
+      self.remove(dst)?;
+    }
+    self.sftp.rename(&partial, dst, None)?;
+    Ok(())
+  }
+}
+
+/// Uid the server runs the session as, if it lets us run a command
+fn remote_uid(session: &ssh2::Session) -> Option<u32> {
+  let mut channel = session.channel_session().ok()?;
+  channel.exec("id -u").ok()?;
+  let mut output = String::new();
+  channel.read_to_string(&mut output).ok()?;
+  channel.wait_close().ok()?;
+  match channel.exit_status() {
+    Ok(0) => output.trim().parse().ok(),
+    _ => None,
+  }
+}
+
+fn check_host_key(session: &ssh2::Session, location: &SftpLocation) -> anyhow::Result<()> {
+  let known_hosts_file = match std::env::var_os("BACKUPS_SSH_KNOWN_HOSTS") {
+    Some(path) => PathBuf::from(path),
+    None => home_dir()?.join(".ssh/known_hosts"),
+  };
+  let mut known_hosts = session.known_hosts()?;
+  if known_hosts_file.exists() {
+    known_hosts.read_file(&known_hosts_file, ssh2::KnownHostFileKind::OpenSSH)?;
+  }
+
+  let (key, _) = session.host_key().ok_or(anyhow::anyhow!("{} sent no host key", location.host))?;
+  match known_hosts.check_port(&location.host, location.port, key) {
+    ssh2::CheckResult::Match => Ok(()),
+    ssh2::CheckResult::NotFound => anyhow::bail!(
+      "host key of {}:{} is not in {}; check it and add it, e.g. with `ssh-keyscan -p {} {} >> {}`",
+      location.host,
+      location.port,
+      known_hosts_file.display(),
+      location.port,
+      location.host,
+      known_hosts_file.display()
+    ),
+    ssh2::CheckResult::Mismatch => anyhow::bail!(
+      "host key of {}:{} doesn't match the one in {}",
+      location.host,
+      location.port,
+      known_hosts_file.display()
+    ),
+    ssh2::CheckResult::Failure => anyhow::bail!("failed to check the host key of {}", location.host),
+  }
+}
+
+fn authenticate(session: &ssh2::Session, location: &SftpLocation) -> anyhow::Result<()> {
+  if let Some(key) = std::env::var_os("BACKUPS_SSH_KEY") {
+    session.userauth_pubkey_file(&location.user, None, Path::new(&key), None)?;
+    return Ok(());
+  }
+
+  if std::env::var_os("SSH_AUTH_SOCK").is_some() && session.userauth_agent(&location.user).is_ok() {
+    return Ok(());
+  }
+  let ssh_dir = home_dir()?.join(".ssh");
+  for key in DEFAULT_SSH_KEYS.iter().map(|name| ssh_dir.join(name)).filter(|key| key.exists()) {
+    match session.userauth_pubkey_file(&location.user, None, &key, None) {
+      Ok(()) => return Ok(()),
+      Err(e) => debug!("key {} was not accepted: {}", key.display(), e),
+    }
+  }
+  anyhow::bail!(
+    "no SSH key was accepted for {}@{}; set `BACKUPS_SSH_KEY` or add a key to the agent",
+    location.user,
+    location.host
+  )
+}
+
+fn home_dir() -> anyhow::Result<PathBuf> {
+  std::env::var_os("HOME").map(PathBuf::from).ok_or(anyhow::anyhow!("`HOME` is not set"))
+}
+
+fn is_sftp_error(e: &ssh2::Error, code: i32) -> bool {
+  e.code() == ssh2::ErrorCode::SFTP(code)
+}
+
+impl Fs for SftpFs {
+  fn metadata(&self, path: &Path) -> anyhow::Result<Option<FsMetadata>> {
+    match self.sftp.lstat(path) {
+      Ok(stat) => Ok(Some(Self::metadata_of(&stat))),
+      Err(e) if is_sftp_error(&e, FX_NO_SUCH_FILE) => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  fn read_dir(&self, path: &Path) -> anyhow::Result<Vec<(OsString, FsMetadata)>> {
+    let entries = self
+      .sftp
+      .readdir(path)?
+      .into_iter()
+      .filter_map(|(path, stat)| Some((path.file_name()?.to_os_string(), Self::metadata_of(&stat))));
+    Ok(entries.collect())
+  }
+
+  fn read_link(&self, path: &Path) -> anyhow::Result<PathBuf> {
+    Ok(self.sftp.readlink(path)?)
+  }
+
+  fn read(&self, path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    self.sftp.open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+  }
+
+  fn hash(&self, path: &Path) -> anyhow::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(self.sftp.open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+  }
+
+  fn write(&self, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    self.upload(&mut &data[..], path)
+  }
+
+  fn create_dir_all(&self, path: &Path) -> anyhow::Result<()> {
+    match self.metadata(path)? {
+      Some(metadata) if metadata.kind == FileKind::Dir => return Ok(()),
+      Some(_) => anyhow::bail!("{} exists and is not a directory", path.display()),
+      None => (),
+    }
+    if let Some(parent) = path.parent() {
+      self.create_dir_all(parent)?;
+    }
+    Ok(self.sftp.mkdir(path, 0o755)?)
+  }
+
+  fn remove(&self, path: &Path) -> anyhow::Result<()> {
+    match self.sftp.lstat(path)?.file_type() {
+      ssh2::FileType::Directory => {
+        for (child, _) in self.sftp.readdir(path)? {
+          self.remove(&child)?;
+        }
+        Ok(self.sftp.rmdir(path)?)
+      }
+      _ => Ok(self.sftp.unlink(path)?),
+    }
+  }
+
+  fn copy_entry(&self, src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()> {
+    if is_preserved_symlink(src, preserve) {
+      if self.metadata(dst)?.is_some() {
+        self.remove(dst)?;
+      }
+      // OpenSSH swaps the arguments of the symlink request, ssh2 follows it: target first, then the link
+      self.sftp.symlink(&std::fs::read_link(src)?, dst)?;
+    } else {
+      self.upload(&mut std::fs::File::open(src)?, dst)?;
+    }
+    self.copy_metadata(src, dst, preserve)
+  }
+
+  fn copy_metadata(&self, src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()> {
+    // SFTP v3 has no lsetstat, setting anything on a symlink would change its target instead
+    if is_preserved_symlink(src, preserve) {
+      return Ok(());
+    }
+    let metadata = src.metadata()?;
+    let stat = ssh2::FileStat { size: None, uid: None, gid: None, perm: None, atime: None, mtime: None };
+
+    // ownership first: chown clears setuid/setgid bits
+    if preserve.ownership && self.is_root.load(Ordering::Relaxed) {
+      let owner = ssh2::FileStat { uid: Some(metadata.uid()), gid: Some(metadata.gid()), ..stat.clone() };
+      match self.sftp.setstat(dst, owner) {
+        Err(e) if is_sftp_error(&e, FX_PERMISSION_DENIED) => {
+          debug!("can't change owner of {} to {}:{}: {}", dst.display(), metadata.uid(), metadata.gid(), e);
+          if self.is_root.swap(false, Ordering::Relaxed) {
+            warn!("the server doesn't let us change owners, ownership isn't preserved");
+          }
+        }
+        result => result?,
+      }
+    }
+    let mode_and_times = ssh2::FileStat {
+      perm: preserve.mode.then_some(metadata.mode() & 0o7777),
+      // both or neither: the protocol sets access and modification time together
+      atime: preserve.times.then_some(metadata.atime() as u64),
+      mtime: preserve.times.then_some(metadata.mtime() as u64),
+      ..stat
+    };
+    if preserve.mode || preserve.times {
+      self.sftp.setstat(dst, mode_and_times)?;
+    }
+
+    Ok(())
+  }
+
+  /// SFTP v3 keeps whole seconds
+  fn stored_mtime(&self, mtime: SystemTime) -> SystemTime {
+    let since_epoch = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())
+  }
+
+  fn sets_ownership(&self) -> bool {
+    self.is_root.load(Ordering::Relaxed)
+  }
+}
@@ -0,0 +1,62 @@
+//! Hook commands run around a backup. Every hook gets
+//! `BACKUPS_TASK`, `BACKUPS_SRC`, `BACKUPS_DST` and `BACKUPS_STRATEGY`; `after` and `on-failure`
+//! also get `BACKUPS_STATUS` (`success` or `failure`) and `BACKUPS_DURATION` in seconds, plus
+//! `BACKUPS_FILES` and `BACKUPS_BYTES` after a successful backup or `BACKUPS_ERROR` after a failed one
+
+use std::process::Command;
+use std::time::Duration;
+
+use tracing::*;
+
+use crate::backup::BackupSummary;
+use crate::config::BackupTaskConfig;
+
+/// Environment describing the task, and the finished run if there is one
+pub fn hook_env(
+  config: &BackupTaskConfig,
+  run: Option<(&anyhow::Result<BackupSummary>, Duration)>,
+) -> Vec<(&'static str, String)> {
+  let mut env = vec![
+    ("BACKUPS_TASK", config.name.clone()),
+    ("BACKUPS_SRC", config.src.display().to_string()),
+    ("BACKUPS_DST", config.dst.display().to_string()),
+    ("BACKUPS_STRATEGY", config.on.strategy.to_string()),
+  ];
+
+  if let Some((result, duration)) = run {
+    env.push(("BACKUPS_DURATION", format!("{:.3}", duration.as_secs_f64())));
+    match result {
+      Ok(summary) => {
+        env.push(("BACKUPS_STATUS", "success".to_string()));
+        env.push(("BACKUPS_FILES", summary.files.to_string()));
+        env.push(("BACKUPS_BYTES", summary.bytes.to_string()));
+      }
+      Err(e) => {
+        env.push(("BACKUPS_STATUS", "failure".to_string()));
+        env.push(("BACKUPS_ERROR", e.to_string()));
+      }
+    }
+  }
+
+  env
+}
+
+/// Runs `command` with `sh -c`, logging its output; fails if it exits unsuccessfully
+pub fn run_hook(name: &str, command: &str, env: &[(&'static str, String)]) -> anyhow::Result<()> {
+  let span = info_span!("hook", name);
+  let _guard = span.enter();
+
+  debug!("running `{}`", command);
+  let output = Command::new("sh").arg("-c").arg(command).envs(env.iter().cloned()).output()?;
+  for line in String::from_utf8_lossy(&output.stdout).lines() {
+    info!("{}", line);
+  }
+  for line in String::from_utf8_lossy(&output.stderr).lines() {
+    warn!("{}", line);
+  }
+
+  if !output.status.success() {
+    anyhow::bail!("`{}` hook failed: {}", name, output.status);
+  }
+  Ok(())
+}
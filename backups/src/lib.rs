@@ -1,3 +1,17 @@
 pub mod backup;
+pub mod compare;
 pub mod config;
+pub mod crypto;
+pub mod delta;
+pub mod filter;
+pub mod fs;
+pub mod hooks;
+pub mod manifest;
+pub mod metadata;
+pub mod restore;
+pub mod retention;
 pub mod scheduler;
+pub mod snapshot;
+pub mod state;
+pub mod storage;
+pub mod verify;
@@ -6,8 +6,12 @@ use tracing::*;
 use clap::*;
 use clap_derive::*;
 
+use backups::backup;
 use backups::config;
+use backups::restore;
 use backups::scheduler;
+use backups::state;
+use backups::verify;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -34,6 +38,51 @@ enum Commands {
   },
   /// Start the program
   Start,
+  /// Run backups once right away and exit; fails if any of them fails
+  Run {
+    /// Task to run, selected by its name, dst or src; may be omitted if there is only one task
+    #[arg(short, long, conflicts_with = "all")]
+    task: Option<String>,
+    /// Run all tasks, one after another
+    #[arg(long)]
+    all: bool,
+    /// Let the incremental mirror delete more than the task's `max-delete` allows
+    #[arg(long)]
+    force: bool,
+  },
+  /// Restore a backup into the task's `src` or another directory
+  Restore {
+    /// Task to restore, selected by its name, dst or src; may be omitted if there is only one task
+    #[arg(short, long)]
+    task: Option<String>,
+    /// Snapshot name or point in time (e.g. `2026-10-17T10:00`); defaults to the latest snapshot
+    #[arg(short, long)]
+    snapshot: Option<String>,
+    /// Restore only this path, relative to the backup root
+    #[arg(short, long)]
+    path: Option<PathBuf>,
+    /// Restore into this directory instead of the task's src
+    #[arg(long)]
+    target: Option<PathBuf>,
+    /// Only show what would be restored and removed
+    #[arg(long)]
+    dry_run: bool,
+    /// Overwrite files that are newer than their backed up version
+    #[arg(long)]
+    force: bool,
+    /// Remove files in the restored path that aren't in the backup, e.g. created after it
+    #[arg(long)]
+    delete: bool,
+  },
+  /// Re-hash the stored backup and check it against its manifest
+  Verify {
+    /// Task to verify, selected by its name, dst or src; may be omitted if there is only one task
+    #[arg(short, long)]
+    task: Option<String>,
+    /// Snapshot name or point in time; defaults to the latest snapshot
+    #[arg(short, long)]
+    snapshot: Option<String>,
+  },
 }
 
 fn write_example_config(path: Option<PathBuf>, format: Option<String>) -> anyhow::Result<()> {
@@ -84,6 +133,46 @@ async fn real_main() -> anyhow::Result<()> {
     Commands::Start => {
       start(config, format).await?;
     }
+    Commands::Run { task, all, force } => {
+      let config = config::Config::resolve(config, format)?;
+      let tasks = match all {
+        true => config.tasks.iter().collect(),
+        false => vec![config.find_task(task.as_deref())?],
+      };
+      let options = backup::BackupOptions { force };
+      let store = state::StateStore::open(&config.state_file())?;
+      let failed = tasks
+        .iter()
+        .filter(|task| {
+          let run = || store.track(&task.name, || scheduler::run_task(task, &options));
+          scheduler::task_span(task).in_scope(run).is_err()
+        })
+        .count();
+      if failed > 0 {
+        anyhow::bail!("{} of {} backups failed", failed, tasks.len());
+      }
+    }
+    Commands::Restore { task, snapshot, path, target, dry_run, force, delete } => {
+      let config = config::Config::resolve(config, format)?;
+      let task = config.find_task(task.as_deref())?;
+      let _guard = scheduler::task_span(task).entered();
+      let options = restore::RestoreOptions { snapshot, path, target, dry_run, force, delete };
+      restore::restore(task, &options)?;
+    }
+    Commands::Verify { task, snapshot } => {
+      let config = config::Config::resolve(config, format)?;
+      let task = config.find_task(task.as_deref())?;
+      let _guard = scheduler::task_span(task).entered();
+      let report = verify::verify(task, snapshot.as_deref())?;
+      if !report.is_intact() {
+        anyhow::bail!(
+          "backup of task `{}` is damaged: {} missing, {} corrupted files",
+          task.name,
+          report.missing.len(),
+          report.corrupted.len()
+        );
+      }
+    }
   }
 
   Ok(())
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+use crate::filter::Filter;
+
+/// Name of the manifest written into the root of every directory-tree backup
+pub const MANIFEST_FILE_NAME: &str = ".backups-manifest.json";
+
+/// Describes a tree captured by a backup run
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Manifest {
+  pub src: PathBuf,
+  pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ManifestEntry {
+  /// Path relative to the root of the backup
+  pub path: PathBuf,
+  pub kind: EntryKind,
+  pub size: u64,
+  pub mtime: SystemTime,
+  pub mode: u32,
+  /// Owner user and group; missing in manifests written before ownership was recorded
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub uid: Option<u32>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub gid: Option<u32>,
+  /// Target of a symlink, as stored in the link
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub link: Option<PathBuf>,
+  /// BLAKE3 hash of the whole file content
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub hash: Option<String>,
+  /// Hashes of the content-defined chunks the file consists of, in order
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub chunks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryKind {
+  File,
+  Dir,
+  Symlink,
+}
+
+impl Manifest {
+  /// Describes the tree under `root`, hashing every file. Hashes of files whose size and mtime
+  /// match their entry in `previous` are reused instead of reading the file again.
+  /// With a `filter`, paths it excludes are left out. Symlinks are recorded as links with `symlinks`,
+  /// otherwise they are followed and dangling ones are skipped
+  pub fn build(
+    src: &Path,
+    root: &Path,
+    previous: Option<&Manifest>,
+    filter: Option<&Filter>,
+    symlinks: bool,
+  ) -> anyhow::Result<Self> {
+    let previous = previous
+      .map(|manifest| manifest.entries.iter().map(|entry| (entry.path.as_path(), entry)).collect())
+      .unwrap_or_default();
+
+    let mut entries = Vec::new();
+    build_entries(root, Path::new(""), &previous, filter, symlinks, &mut entries)?;
+    Ok(Self { src: src.to_path_buf(), entries })
+  }
+
+  pub fn read_from_dir(root: &Path) -> anyhow::Result<Self> {
+    let path = root.join(MANIFEST_FILE_NAME);
+    if !path.exists() {
+      anyhow::bail!("no manifest found in {}", root.display());
+    }
+    Self::read(&path)
+  }
+
+  pub fn write_to_dir(&self, root: &Path) -> anyhow::Result<()> {
+    self.write(&root.join(MANIFEST_FILE_NAME))
+  }
+
+  pub fn read(path: &Path) -> anyhow::Result<Self> {
+    let content = std::fs::read(path)?;
+    Ok(serde_json::from_slice(&content)?)
+  }
+
+  /// Writes the manifest to a temp file next to `path` and renames it into place,
+  /// so a reader never sees a half-written manifest
+  pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+    write_atomic(path, &serde_json::to_vec(self)?)
+  }
+}
+
+/// Writes `data` to a temp file next to `path` and renames it into place
+pub fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+  let dir =
+    path.parent().ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "path has no parent"))?;
+  let mut file = tempfile::NamedTempFile::new_in(dir)?;
+  file.write_all(data)?;
+  file.flush()?;
+  file.persist(path)?;
+  Ok(())
+}
+
+pub fn hash_file(path: &Path) -> std::io::Result<String> {
+  let mut hasher = blake3::Hasher::new();
+  hasher.update_reader(std::fs::File::open(path)?)?;
+  Ok(hasher.finalize().to_hex().to_string())
+}
+
+fn build_entries(
+  root: &Path,
+  rel: &Path,
+  previous: &HashMap<&Path, &ManifestEntry>,
+  filter: Option<&Filter>,
+  symlinks: bool,
+  entries: &mut Vec<ManifestEntry>,
+) -> anyhow::Result<()> {
+  let mut children = std::fs::read_dir(root.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+  children.sort_by_key(|entry| entry.file_name());
+
+  for child in children {
+    if rel == Path::new("") && child.file_name() == MANIFEST_FILE_NAME {
+      continue;
+    }
+
+    let path = child.path();
+    let rel_path = rel.join(child.file_name());
+    let metadata = match symlinks {
+      true => path.symlink_metadata()?,
+      false => match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e.into()),
+      },
+    };
+    if !metadata.is_dir() && !metadata.is_file() && !metadata.is_symlink() {
+      continue;
+    }
+    if filter.is_some_and(|filter| filter.is_excluded_rel(&rel_path, metadata.is_dir())) {
+      continue;
+    }
+
+    let entry = ManifestEntry {
+      path: rel_path.clone(),
+      kind: EntryKind::Dir,
+      size: 0,
+      mtime: metadata.modified()?,
+      mode: metadata.permissions().mode(),
+      uid: Some(metadata.uid()),
+      gid: Some(metadata.gid()),
+      link: None,
+      hash: None,
+      chunks: vec![],
+    };
+
+    if metadata.is_dir() {
+      entries.push(entry);
+      build_entries(root, &rel_path, previous, filter, symlinks, entries)?;
+      continue;
+    }
+
+    if metadata.is_symlink() {
+      entries.push(ManifestEntry {
+        kind: EntryKind::Symlink,
+        link: Some(std::fs::read_link(&path)?),
+        ..entry
+      });
+      continue;
+    }
+
+    let cached = previous
+      .get(rel_path.as_path())
+      .filter(|prev| prev.kind == EntryKind::File && prev.size == metadata.len() && prev.mtime == entry.mtime)
+      .and_then(|prev| prev.hash.clone());
+    let hash = match cached {
+      Some(hash) => hash,
+      None => hash_file(&path)?,
+    };
+
+    entries.push(ManifestEntry { kind: EntryKind::File, size: metadata.len(), hash: Some(hash), ..entry });
+  }
+
+  Ok(())
+}
@@ -0,0 +1,142 @@
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use tracing::*;
+
+use crate::config::PreserveConfig;
+use crate::fs::*;
+
+/// Prefix of the extended attributes holding POSIX ACLs
+const ACL_XATTR_PREFIX: &str = "system.posix_acl_";
+
+/// Whether `path` is a symlink that should be copied as a link rather than followed
+pub fn is_preserved_symlink(path: &Path, preserve: &PreserveConfig) -> bool {
+  preserve.symlinks && path.symlink_metadata().is_ok_and(|metadata| metadata.is_symlink())
+}
+
+/// Copies a file or, if symlinks are preserved, a symlink from `src` to `dst` along with its metadata.
+/// Whatever is at `dst` is replaced rather than written through: it may be a symlink, a read-only file
+/// or a hardlink shared with an older snapshot
+pub fn copy_entry(src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()> {
+  match dst.symlink_metadata() {
+    Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(dst)?,
+    Ok(_) => std::fs::remove_file(dst)?,
+    Err(_) => (),
+  }
+
+  if is_preserved_symlink(src, preserve) {
+    std::os::unix::fs::symlink(std::fs::read_link(src)?, dst)?;
+  } else {
+    std::fs::copy(src, dst)?;
+  }
+  copy_metadata(src, dst, preserve)
+}
+
+/// Whether `dst` already holds the current version of `src`: the same symlink, or a file at least as new
+/// with the same preserved mode and owner
+pub fn is_up_to_date(src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<bool> {
+  let Some(dst_metadata) = LocalFs.metadata(dst)? else {
+    return Ok(false);
+  };
+  if is_preserved_symlink(src, preserve) {
+    return Ok(
+      dst_metadata.kind == FileKind::Symlink && std::fs::read_link(dst)? == std::fs::read_link(src)?,
+    );
+  }
+  let src_metadata = src.metadata()?;
+  Ok(
+    dst_metadata.kind == FileKind::File
+      && dst_metadata.mtime >= src_metadata.modified()?
+      && !metadata_differs(&src_metadata, &dst_metadata, preserve, true),
+  )
+}
+
+/// Whether the metadata of `dst` differs from `src` in a way a content check wouldn't notice;
+/// ownership only counts if `dst` can be given away
+pub fn metadata_differs(
+  src: &std::fs::Metadata,
+  dst: &FsMetadata,
+  preserve: &PreserveConfig,
+  ownership: bool,
+) -> bool {
+  (preserve.mode && src.mode() & 0o7777 != dst.mode & 0o7777)
+    || (preserve.ownership && ownership && (src.uid() != dst.uid || src.gid() != dst.gid))
+}
+
+/// Applies the metadata of `src` to `dst` as far as `preserve` asks. Directories should get theirs
+/// after their contents are copied, or adding children bumps their mtime again
+pub fn copy_metadata(src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()> {
+  let is_symlink = is_preserved_symlink(src, preserve);
+  let metadata = match is_symlink {
+    true => src.symlink_metadata()?,
+    false => src.metadata()?,
+  };
+
+  // ownership first: chown clears setuid/setgid bits
+  if preserve.ownership {
+    set_owner(dst, metadata.uid(), metadata.gid())?;
+  }
+  if preserve.mode && !is_symlink {
+    std::fs::set_permissions(dst, std::fs::Permissions::from_mode(metadata.mode()))?;
+  }
+  if preserve.xattrs || preserve.acls {
+    copy_xattrs(src, dst, preserve)?;
+  }
+  if preserve.times {
+    let atime = filetime::FileTime::from_last_access_time(&metadata);
+    let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+    filetime::set_symlink_file_times(dst, atime, mtime)?;
+  }
+
+  Ok(())
+}
+
+/// Changes the owner of `path` without following symlinks. Only root may give files away,
+/// so a permission error is logged and otherwise ignored
+pub fn set_owner(path: &Path, uid: u32, gid: u32) -> anyhow::Result<()> {
+  match std::os::unix::fs::lchown(path, Some(uid), Some(gid)) {
+    Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+      debug!("can't change owner of {} to {}:{}: {}", path.display(), uid, gid, e);
+      Ok(())
+    }
+    result => Ok(result?),
+  }
+}
+
+/// Copies extended attributes and ACLs as far as `preserve` asks; ones `dst` refuses are skipped
+pub fn copy_xattrs(src: &Path, dst: &Path, preserve: &PreserveConfig) -> anyhow::Result<()> {
+  // a symlink that isn't preserved was copied as its target, so are the attributes
+  let follow = !is_preserved_symlink(src, preserve);
+  let names = match follow {
+    true => xattr::list_deref(src),
+    false => xattr::list(src),
+  };
+  let names = match names {
+    Ok(names) => names,
+    Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(()),
+    Err(e) => return Err(e.into()),
+  };
+
+  for name in names {
+    let is_acl = name.to_string_lossy().starts_with(ACL_XATTR_PREFIX);
+    let wanted = if is_acl { preserve.acls } else { preserve.xattrs };
+    if !wanted {
+      continue;
+    }
+
+    let value = match follow {
+      true => xattr::get_deref(src, &name)?,
+      false => xattr::get(src, &name)?,
+    };
+    let Some(value) = value else {
+      continue;
+    };
+    // `trusted.*` and some `security.*` attributes need privileges, user symlinks can't have `user.*` ones
+    if let Err(e) = xattr::set(dst, &name, &value) {
+      debug!("can't set {} on {}: {}", name.to_string_lossy(), dst.display(), e);
+    }
+  }
+
+  Ok(())
+}
@@ -0,0 +1,472 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use tracing::*;
+
+use crate::backup::archive;
+use crate::backup::differential;
+use crate::backup::list_backup_snapshots;
+use crate::backup::repository::Repository;
+use crate::config::*;
+use crate::filter::Filter;
+use crate::manifest::*;
+use crate::metadata;
+use crate::snapshot::*;
+
+#[derive(Default, Clone, Debug)]
+pub struct RestoreOptions {
+  /// Snapshot name or point in time; the latest snapshot if not set
+  pub snapshot: Option<String>,
+  /// Restore only this path, relative to the root of the backup
+  pub path: Option<PathBuf>,
+  /// Restore into this directory instead of the task's `src`
+  pub target: Option<PathBuf>,
+  /// Only log what would be done
+  pub dry_run: bool,
+  /// Overwrite files that are newer than their backed up version
+  pub force: bool,
+  /// Remove files in the restored path that aren't in the backup
+  pub delete: bool,
+}
+
+#[derive(Default, Debug)]
+pub struct RestoreReport {
+  pub restored: usize,
+  pub unchanged: usize,
+  /// Paths in the target that aren't in the backup, relative to it; removed with `delete`
+  pub extra: Vec<PathBuf>,
+  pub removed: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Content {
+  File(PathBuf),
+  Chunks(Vec<String>),
+}
+
+#[derive(Clone, Debug)]
+struct RestoreEntry {
+  path: PathBuf,
+  kind: EntryKind,
+  size: u64,
+  mtime: SystemTime,
+  mode: u32,
+  uid: Option<u32>,
+  gid: Option<u32>,
+  link: Option<PathBuf>,
+  content: Content,
+}
+
+impl RestoreEntry {
+  fn new(entry: ManifestEntry, content: Content) -> Self {
+    Self {
+      path: entry.path,
+      kind: entry.kind,
+      size: entry.size,
+      mtime: entry.mtime,
+      mode: entry.mode,
+      uid: entry.uid,
+      gid: entry.gid,
+      link: entry.link,
+      content,
+    }
+  }
+}
+
+/// Where file contents come from: plain files in a directory tree, files unpacked from archives
+/// into a temporary directory or chunks of a repository
+enum Source {
+  Dir,
+  /// The directory is removed when the restore is done; a dry run extracts nothing
+  Archive {
+    _extracted: Option<tempfile::TempDir>,
+  },
+  Repository(Repository),
+}
+
+pub fn restore(config: &BackupTaskConfig, options: &RestoreOptions) -> anyhow::Result<RestoreReport> {
+  if config.src.is_file() {
+    anyhow::bail!(
+      "restoring single-file tasks is not supported, copy the file from {}",
+      config.dst.display()
+    );
+  }
+  if crate::fs::is_sftp(&config.dst) {
+    anyhow::bail!(
+      "restoring from an sftp dst is not supported, copy the files from {}",
+      config.dst.display()
+    );
+  }
+
+  let target = options.target.clone().unwrap_or(config.src.clone());
+  let scope = options.path.clone().unwrap_or_default();
+  if scope.is_absolute() || scope.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+    anyhow::bail!("path to restore must be relative to the backup root: {}", scope.display());
+  }
+
+  let (source, entries) = resolve_source(config, options, &target, &scope)?;
+
+  let entries = entries.into_iter().filter(|entry| entry.path.starts_with(&scope)).collect::<Vec<_>>();
+  if entries.is_empty() && scope != Path::new("") {
+    anyhow::bail!("{} is not in the backup", scope.display());
+  }
+
+  let span = info_span!("restore", target = target.display().to_string(), path = scope.display().to_string());
+  let _guard = span.enter();
+
+  let newer = find_newer_files(&entries, &target)?;
+  if !newer.is_empty() {
+    for path in newer.iter() {
+      warn!("{} is newer than its backed up version", path.display());
+    }
+    if !options.force {
+      anyhow::bail!("refusing to overwrite {} newer files, use --force to overwrite them", newer.len());
+    }
+  }
+
+  let mut report = RestoreReport::default();
+  if !options.dry_run {
+    std::fs::create_dir_all(&target)?;
+  }
+
+  let wanted = entries.iter().map(|entry| entry.path.clone()).collect::<HashSet<_>>();
+  if target.join(&scope).is_dir() {
+    find_extra_files(&target, &scope, &wanted, &Filter::new(config), &mut report.extra)?;
+  }
+  for path in report.extra.iter() {
+    match options.delete {
+      true => {
+        info!("removing {}, it is not in the backup", path.display());
+        if !options.dry_run {
+          let path = target.join(path);
+          match path.symlink_metadata()?.is_dir() {
+            true => std::fs::remove_dir_all(path)?,
+            false => std::fs::remove_file(path)?,
+          }
+        }
+        report.removed += 1;
+      }
+      false => warn!("{} is not in the backup, use --delete to remove it", path.display()),
+    }
+  }
+
+  for entry in entries.iter() {
+    let path = target.join(&entry.path);
+    match entry.kind {
+      EntryKind::Dir => {
+        if !options.dry_run {
+          if path.symlink_metadata().is_ok_and(|metadata| !metadata.is_dir()) {
+            std::fs::remove_file(&path)?;
+          }
+          std::fs::create_dir_all(&path)?;
+        }
+      }
+      EntryKind::File | EntryKind::Symlink if is_unchanged(entry, &path, &config.preserve) => {
+        report.unchanged += 1
+      }
+      EntryKind::File | EntryKind::Symlink => {
+        info!("restoring {}", entry.path.display());
+        if !options.dry_run {
+          match entry.kind {
+            EntryKind::Symlink => restore_symlink(entry, &path)?,
+            _ => restore_file(&source, entry, &path)?,
+          }
+          apply_metadata(&path, entry, &config.preserve)?;
+        }
+        report.restored += 1;
+      }
+    }
+  }
+
+  // directories last, so restoring their children doesn't bump mtime again
+  if !options.dry_run {
+    for entry in entries.iter().rev().filter(|entry| entry.kind == EntryKind::Dir) {
+      apply_metadata(&target.join(&entry.path), entry, &config.preserve)?;
+    }
+  }
+
+  info!(
+    "{}restored {} files, {} unchanged, {} extra entries, {} removed",
+    if options.dry_run { "[dry-run] " } else { "" },
+    report.restored,
+    report.unchanged,
+    report.extra.len(),
+    report.removed
+  );
+
+  Ok(report)
+}
+
+fn resolve_source(
+  config: &BackupTaskConfig,
+  options: &RestoreOptions,
+  target: &Path,
+  scope: &Path,
+) -> anyhow::Result<(Source, Vec<RestoreEntry>)> {
+  let snapshot = options.snapshot.as_deref();
+  match list_backup_snapshots(config)? {
+    None => {
+      if snapshot.is_some() {
+        anyhow::bail!("strategy `{}` keeps a single copy and has no snapshots", config.on.strategy);
+      }
+      if !config.dst.is_dir() {
+        anyhow::bail!("no backup found in {}", config.dst.display());
+      }
+      // `dst` of differential tasks written by older versions holds the tree itself
+      if let BackupStrategyConfig::Differential = config.on.strategy {
+        if config.dst.join(differential::FULL_DIR_NAME).is_dir() {
+          let entries = differential::current_manifest(&config.dst)?
+            .entries
+            .into_iter()
+            .map(|entry| {
+              let content = Content::File(differential::content_path(&config.dst, &entry.path));
+              RestoreEntry::new(entry, content)
+            })
+            .collect();
+          return Ok((Source::Dir, entries));
+        }
+      }
+      let entries = walk_dir(&config.dst)?;
+      Ok((Source::Dir, entries))
+    }
+    Some(snapshots) => {
+      let snapshot = select_snapshot(&snapshots, snapshot)?;
+      info!("restoring from snapshot {}", snapshot);
+      match config.on.strategy {
+        BackupStrategyConfig::Repository => {
+          let repo = Repository::for_task(config)?;
+          let entries = repo
+            .read_snapshot(&snapshot.name)?
+            .entries
+            .into_iter()
+            .map(|mut entry| {
+              let chunks = std::mem::take(&mut entry.chunks);
+              RestoreEntry::new(entry, Content::Chunks(chunks))
+            })
+            .collect();
+          Ok((Source::Repository(repo), entries))
+        }
+        _ if config.on.format.is_archive() => {
+          let archives = archive::Archives::for_task(config)?;
+          // a dry run only compares with the index, the content is never read
+          let (index, extracted) = match options.dry_run {
+            true => (archives.read_index(&snapshot.name)?, None),
+            false => {
+              let extracted = extraction_dir(target)?;
+              let index = archives.extract_chain(&snapshot.name, scope, extracted.path())?;
+              (index, Some(extracted))
+            }
+          };
+          let root = extracted.as_ref().map(|dir| dir.path().to_path_buf()).unwrap_or_default();
+          let entries = index
+            .manifest
+            .entries
+            .into_iter()
+            .map(|entry| {
+              let content = Content::File(root.join(&entry.path));
+              RestoreEntry::new(entry, content)
+            })
+            .collect();
+          Ok((Source::Archive { _extracted: extracted }, entries))
+        }
+        _ => {
+          let root = config.dst.join(&snapshot.name);
+          let entries = walk_dir(&root)?;
+          Ok((Source::Dir, entries))
+        }
+      }
+    }
+  }
+}
+
+/// A temporary directory next to `target`, so the extracted files are on its filesystem and don't fill up
+/// the system temp directory
+fn extraction_dir(target: &Path) -> anyhow::Result<tempfile::TempDir> {
+  let target = std::path::absolute(target)?;
+  let mut builder = tempfile::Builder::new();
+  builder.prefix(".backups-restore-");
+  // not inside `target`, the directory would be taken for an extra file there
+  let dir = match target.ancestors().skip(1).find(|dir| dir.is_dir()) {
+    Some(dir) => builder.tempdir_in(dir)?,
+    None => builder.tempdir()?,
+  };
+  Ok(dir)
+}
+
+fn walk_dir(root: &Path) -> anyhow::Result<Vec<RestoreEntry>> {
+  fn walk(root: &Path, rel: &Path, entries: &mut Vec<RestoreEntry>) -> anyhow::Result<()> {
+    let mut children = std::fs::read_dir(root.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+    children.sort_by_key(|entry| entry.file_name());
+
+    for child in children {
+      if rel == Path::new("") && child.file_name() == MANIFEST_FILE_NAME {
+        continue;
+      }
+
+      let path = child.path();
+      let rel_path = rel.join(child.file_name());
+      let metadata = path.symlink_metadata()?;
+      let kind = match metadata.file_type() {
+        file_type if file_type.is_dir() => EntryKind::Dir,
+        file_type if file_type.is_symlink() => EntryKind::Symlink,
+        _ => EntryKind::File,
+      };
+      entries.push(RestoreEntry {
+        path: rel_path.clone(),
+        kind,
+        size: metadata.len(),
+        mtime: metadata.modified()?,
+        mode: metadata.permissions().mode(),
+        uid: Some(metadata.uid()),
+        gid: Some(metadata.gid()),
+        link: metadata.is_symlink().then(|| std::fs::read_link(&path)).transpose()?,
+        content: Content::File(path),
+      });
+
+      if kind == EntryKind::Dir {
+        walk(root, &rel_path, entries)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  let mut entries = Vec::new();
+  walk(root, Path::new(""), &mut entries)?;
+  Ok(entries)
+}
+
+fn find_newer_files(entries: &[RestoreEntry], target: &Path) -> anyhow::Result<Vec<PathBuf>> {
+  let mut newer = Vec::new();
+  for entry in entries.iter().filter(|entry| entry.kind == EntryKind::File) {
+    let path = target.join(&entry.path);
+    if let Ok(metadata) = path.symlink_metadata() {
+      if metadata.is_file() && metadata.modified()? > entry.mtime {
+        newer.push(path);
+      }
+    }
+  }
+  Ok(newer)
+}
+
+fn is_unchanged(entry: &RestoreEntry, path: &Path, preserve: &PreserveConfig) -> bool {
+  path.symlink_metadata().is_ok_and(|metadata| {
+    let same_owner = !preserve.ownership
+      || (entry.uid.is_none_or(|uid| uid == metadata.uid())
+        && entry.gid.is_none_or(|gid| gid == metadata.gid()));
+    let same_content = match entry.kind {
+      EntryKind::Symlink => metadata.is_symlink() && std::fs::read_link(path).ok() == entry.link,
+      _ => {
+        // without the times restored, a file can't be told unchanged
+        metadata.is_file()
+          && metadata.len() == entry.size
+          && preserve.times
+          && metadata.modified().is_ok_and(|mtime| mtime == entry.mtime)
+          && (!preserve.mode || metadata.permissions().mode() == entry.mode)
+      }
+    };
+    same_content && same_owner
+  })
+}
+
+/// Collects what's in `target` under `rel` but not in the backup. A directory that isn't in the backup
+/// is listed alone, without its contents
+fn find_extra_files(
+  target: &Path,
+  rel: &Path,
+  wanted: &HashSet<PathBuf>,
+  filter: &Filter,
+  extra: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+  let mut children = std::fs::read_dir(target.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+  children.sort_by_key(|child| child.file_name());
+  for child in children {
+    let rel_path = rel.join(child.file_name());
+    let is_dir = child.file_type()?.is_dir();
+
+    if wanted.contains(&rel_path) {
+      if is_dir {
+        find_extra_files(target, &rel_path, wanted, filter, extra)?;
+      }
+    } else if !filter.is_excluded_rel(&rel_path, is_dir) {
+      // excluded paths were never backed up, they aren't extra
+      extra.push(rel_path);
+    }
+  }
+
+  Ok(())
+}
+
+/// Writes the file next to its final path and renames it into place, so an interrupted restore
+/// never leaves a half-written file behind
+fn restore_file(source: &Source, entry: &RestoreEntry, path: &Path) -> anyhow::Result<()> {
+  let dir = path.parent().expect("restored path always has a parent");
+  std::fs::create_dir_all(dir)?;
+  if path.symlink_metadata().is_ok_and(|metadata| metadata.is_dir()) {
+    std::fs::remove_dir_all(path)?;
+  }
+
+  // created with the usual mode, unless `apply_metadata` restores the backed up one
+  let mut file =
+    tempfile::Builder::new().permissions(std::fs::Permissions::from_mode(0o666)).tempfile_in(dir)?;
+  match (&entry.content, source) {
+    (Content::File(from), _) => {
+      std::io::copy(&mut std::fs::File::open(from)?, &mut file)?;
+    }
+    (Content::Chunks(chunks), Source::Repository(repo)) => {
+      for hash in chunks {
+        let data = repo.read_chunk(hash)?;
+        if repo.chunk_id(&data) != *hash {
+          anyhow::bail!("chunk {} of {} is corrupted", hash, entry.path.display());
+        }
+        file.write_all(&data)?;
+      }
+    }
+    (Content::Chunks(_), Source::Dir | Source::Archive { .. }) => {
+      unreachable!("chunked entries only come from a repository")
+    }
+  }
+
+  file.flush()?;
+  file.persist(path)?;
+  Ok(())
+}
+
+fn restore_symlink(entry: &RestoreEntry, path: &Path) -> anyhow::Result<()> {
+  let Some(link) = &entry.link else {
+    anyhow::bail!("symlink {} has no target in the backup", entry.path.display());
+  };
+  std::fs::create_dir_all(path.parent().expect("restored path always has a parent"))?;
+  match path.symlink_metadata() {
+    Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path)?,
+    Ok(_) => std::fs::remove_file(path)?,
+    Err(_) => (),
+  }
+  std::os::unix::fs::symlink(link, path)?;
+  Ok(())
+}
+
+/// Restores metadata recorded in the backup. Files of directory backups and unpacked archives still
+/// carry their extended attributes, those are copied over too
+fn apply_metadata(path: &Path, entry: &RestoreEntry, preserve: &PreserveConfig) -> anyhow::Result<()> {
+  let is_symlink = entry.kind == EntryKind::Symlink;
+  if let (true, Some(uid), Some(gid)) = (preserve.ownership, entry.uid, entry.gid) {
+    metadata::set_owner(path, uid, gid)?;
+  }
+  if preserve.mode && !is_symlink {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode))?;
+  }
+  if let Content::File(from) = &entry.content {
+    metadata::copy_xattrs(from, path, preserve)?;
+  }
+  if preserve.times {
+    let atime = filetime::FileTime::from_last_access_time(&path.symlink_metadata()?);
+    filetime::set_symlink_file_times(path, atime, filetime::FileTime::from_system_time(entry.mtime))?;
+  }
+  Ok(())
+}
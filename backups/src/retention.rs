@@ -0,0 +1,116 @@
+use chrono::Datelike;
+use chrono::NaiveDateTime;
+use tracing::*;
+
+use crate::config::RetentionConfig;
+use crate::snapshot::Snapshot;
+
+#[derive(Clone, Debug)]
+pub struct RetentionDecision {
+  pub snapshot: Snapshot,
+  pub keep: bool,
+  /// Why the snapshot is kept (rules that selected it) or pruned (why every rule passed it over)
+  pub reasons: Vec<String>,
+}
+
+struct Rule {
+  name: &'static str,
+  count: usize,
+  bucket: fn(&NaiveDateTime) -> String,
+}
+
+/// Decides which snapshots to keep, GFS-style: each `keep-*` rule walks snapshots from the newest
+/// and keeps the newest one of each of its last N hours/days/weeks/months. A snapshot is kept if
+/// any rule keeps it; the most recent snapshot is always kept. Without any rules everything is kept.
+pub fn apply_policy(snapshots: &[Snapshot], policy: &RetentionConfig) -> Vec<RetentionDecision> {
+  let rules = [
+    Rule { name: "keep-last", count: policy.keep_last.unwrap_or(0), bucket: |time| time.to_string() },
+    Rule {
+      name: "keep-hourly",
+      count: policy.keep_hourly.unwrap_or(0),
+      bucket: |time| time.format("%Y-%m-%d %H:00").to_string(),
+    },
+    Rule {
+      name: "keep-daily",
+      count: policy.keep_daily.unwrap_or(0),
+      bucket: |time| time.date().to_string(),
+    },
+    Rule {
+      name: "keep-weekly",
+      count: policy.keep_weekly.unwrap_or(0),
+      bucket: |time| format!("{}-W{:02}", time.iso_week().year(), time.iso_week().week()),
+    },
+    Rule {
+      name: "keep-monthly",
+      count: policy.keep_monthly.unwrap_or(0),
+      bucket: |time| time.format("%Y-%m").to_string(),
+    },
+  ];
+
+  if rules.iter().all(|rule| rule.count == 0) {
+    return snapshots
+      .iter()
+      .map(|snapshot| RetentionDecision {
+        snapshot: snapshot.clone(),
+        keep: true,
+        reasons: vec!["no retention rules configured".to_string()],
+      })
+      .collect();
+  }
+
+  let mut newest_first = snapshots.to_vec();
+  newest_first.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.time));
+
+  let mut decisions: Vec<RetentionDecision> = newest_first
+    .into_iter()
+    .map(|snapshot| RetentionDecision { snapshot, keep: false, reasons: vec![] })
+    .collect();
+
+  for rule in rules.iter().filter(|rule| rule.count > 0) {
+    let mut kept = 0;
+    let mut last_bucket: Option<(String, String)> = None;
+
+    for decision in decisions.iter_mut() {
+      let bucket = (rule.bucket)(&decision.snapshot.time);
+      match last_bucket {
+        Some((ref last, ref by)) if *last == bucket => {
+          if !decision.keep {
+            decision.reasons.push(format!("{}: {} already covered by {}", rule.name, bucket, by));
+          }
+        }
+        _ if kept >= rule.count => {
+          if !decision.keep {
+            decision.reasons.push(format!("{}: limit of {} reached", rule.name, rule.count));
+          }
+        }
+        _ => {
+          kept += 1;
+          if !decision.keep {
+            decision.reasons.clear();
+          }
+          decision.keep = true;
+          decision.reasons.push(format!("{} #{} ({})", rule.name, kept, bucket));
+          last_bucket = Some((bucket, decision.snapshot.name.clone()));
+        }
+      }
+    }
+  }
+
+  if let Some(latest) = decisions.first_mut().filter(|decision| !decision.keep) {
+    latest.keep = true;
+    latest.reasons = vec!["most recent snapshot".to_string()];
+  }
+
+  decisions.reverse();
+  decisions
+}
+
+pub fn log_decisions(decisions: &[RetentionDecision]) {
+  for decision in decisions {
+    if decision.keep {
+      debug!("keeping snapshot {}: {}", decision.snapshot, decision.reasons.join(", "));
+    } else {
+      info!("pruning snapshot {}: {}", decision.snapshot, decision.reasons.join("; "));
+    }
+  }
+}
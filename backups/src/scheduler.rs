@@ -1,77 +1,476 @@
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
 
+use notify::EventKind;
+use notify::RecommendedWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
 use tracing::*;
 
-use clokwerk::AsyncScheduler;
+use chrono::DateTime;
+use chrono::Local;
+use chrono::NaiveTime;
+use chrono::TimeZone;
 use clokwerk::Interval;
-use clokwerk::Job;
+use clokwerk::NextTime;
+use croner::Cron;
 
-use crate::backup::make_backup;
+use crate::backup::make_backup_with_options;
+use crate::backup::BackupOptions;
+use crate::backup::BackupSummary;
 use crate::config::*;
+use crate::filter::Filter;
+use crate::hooks::*;
+use crate::state::RunOutcome;
+use crate::state::StateStore;
 
 pub async fn run_backup_tasks(config: Config) -> anyhow::Result<()> {
+  let limit = Arc::new(Semaphore::new(config.max_concurrent.unwrap_or(Semaphore::MAX_PERMITS)));
+  let store = Arc::new(StateStore::open(&config.state_file())?);
   for task_config in config.tasks.iter().cloned() {
-    spawn_backup_task(task_config).await?;
+    spawn_backup_task(task_config, limit.clone(), store.clone()).await?;
   }
 
   Ok(())
 }
 
-pub async fn spawn_backup_task(config: BackupTaskConfig) -> anyhow::Result<()> {
+/// Span all logs of a task are recorded in
+pub fn task_span(config: &BackupTaskConfig) -> Span {
+  info_span!("task", name = config.name.as_str())
+}
+
+/// Schedules the task according to its trigger; `limit` is shared by all tasks and bounds how many
+/// backups run at the same time. A scheduled run missed since the last run recorded in `store` is
+/// made right away, otherwise the next run is the one due after that last run
+pub async fn spawn_backup_task(
+  config: BackupTaskConfig,
+  limit: Arc<Semaphore>,
+  store: Arc<StateStore>,
+) -> anyhow::Result<()> {
+  let span = task_span(&config);
+  let runner = TaskRunner::new(config.clone(), limit, store.clone())?;
+
+  let last_start = store.task(&config.name).map(|last| last.last_start);
+  if let Some(last) = store.task(&config.name) {
+    if let Some(missed) = missed_run(&config.on.trigger, last.last_start, Local::now())? {
+      span.in_scope(|| {
+        info!("missed the run due at {} since the last one at {}, running now", missed, last.last_start);
+        runner.trigger();
+      });
+    }
+  }
   match config.on.trigger {
-    BackupTrigger::Schedule { ref every, ref at } => {
-      let mut intervals = parse_schedule(every)?.into_iter();
+    BackupTrigger::Change { ref quiet, ref max_delay } => {
+      let quiet = parse_duration(quiet)?;
+      let max_delay = parse_duration(max_delay)?;
+
+      let (watcher, mut changes) = watch_changes(&config)?;
+      info!("watching {} for changes", config.src.display());
 
-      let Some(first_interval) = intervals.next() else {
+      tokio::spawn(
+        async move {
+          // dropping the watcher stops watching
+          let _watcher = watcher;
+          while wait_for_changes(&mut changes, quiet, max_delay).await {
+            runner.trigger();
+          }
+        }
+        .instrument(span),
+      );
+    }
+    BackupTrigger::Schedule { .. } => {
+      let trigger = config.on.trigger.clone();
+      let mut next = first_run(&trigger, last_start, Local::now())?;
+      if next.is_none() {
         anyhow::bail!("no intervals provided");
-      };
+      }
 
-      let mut scheduler = AsyncScheduler::new();
-      let task = scheduler.every(first_interval);
+      tokio::spawn(
+        async move {
+          while let Some(at) = next {
+            debug!("next backup at {}", at);
+            tokio::time::sleep((at - Local::now()).to_std().unwrap_or_default()).await;
+            runner.trigger();
+            // a late wakeup doesn't make up for the runs it slept through
+            next = match next_run(&trigger, at.max(Local::now())) {
+              Ok(next) => next,
+              Err(e) => {
+                error!("{}", e);
+                break;
+              }
+            };
+          }
+        }
+        .instrument(span),
+      );
+    }
+  }
 
-      intervals.for_each(|interval| {
-        task.and_every(interval);
-      });
+  Ok(())
+}
+
+/// Runs backups of a task on blocking threads, making sure two runs of the same task never overlap
+#[derive(Clone)]
+pub struct TaskRunner {
+  config: Arc<BackupTaskConfig>,
+  state: Arc<Mutex<RunState>>,
+  limit: Arc<Semaphore>,
+  store: Arc<StateStore>,
+  /// From `retry`: a single attempt without it
+  attempts: u32,
+  backoff: Duration,
+  timeout: Option<Duration>,
+}
+
+#[derive(Default)]
+struct RunState {
+  /// A run is in progress or waiting for a free slot
+  running: bool,
+  /// Another run was requested while `running`
+  queued: bool,
+}
+
+impl TaskRunner {
+  pub fn new(
+    config: BackupTaskConfig,
+    limit: Arc<Semaphore>,
+    store: Arc<StateStore>,
+  ) -> anyhow::Result<Self> {
+    let (attempts, backoff) = match &config.retry {
+      Some(retry) => (retry.attempts.max(1), parse_duration(&retry.backoff)?),
+      None => (1, Duration::ZERO),
+    };
+    let timeout = config.timeout.as_deref().map(parse_duration).transpose()?;
+    Ok(Self { config: Arc::new(config), state: Default::default(), limit, store, attempts, backoff, timeout })
+  }
 
-      if let Some(at) = at {
-        task.at(at);
+  /// Starts a backup in the background unless one is already in progress, in which case the
+  /// task's overlap policy decides whether the new run is skipped or queued.
+  /// Must be called within a tokio runtime
+  pub fn trigger(&self) {
+    let mut state = self.state.lock().unwrap();
+    if state.running {
+      match self.config.overlap {
+        OverlapPolicy::Skip => warn!("previous backup is still running, skipping this one"),
+        OverlapPolicy::Queue if state.queued => debug!("another backup is already queued"),
+        OverlapPolicy::Queue => {
+          info!("previous backup is still running, queueing another one");
+          state.queued = true;
+        }
       }
+      return;
+    }
+    state.running = true;
+    drop(state);
 
-      let config = Arc::new(config);
+    let runner = self.clone();
+    tokio::spawn(runner.run_until_idle().instrument(Span::current()));
+  }
 
-      task.forever().run(move || {
-        let config = config.clone();
-        async move {
-          let span = info_span!(
-            "backup",
-            r#type = config.on.strategy.to_string(),
-            src = config.src.display().to_string(),
-            dst = config.dst.display().to_string()
-          );
-
-          let _guard = span.enter();
-          let start = std::time::Instant::now();
-          match make_backup(&config) {
-            Ok(()) => info!("backup completed in {:?}", start.elapsed()),
-            Err(e) => error!("backup failed after {:?}: {}", start.elapsed(), e),
+  /// Whether a run is in progress or waiting for a free slot
+  pub fn is_running(&self) -> bool {
+    self.state.lock().unwrap().running
+  }
+
+  async fn run_until_idle(self) {
+    loop {
+      self.run_with_retries().await;
+
+      let mut state = self.state.lock().unwrap();
+      if !std::mem::take(&mut state.queued) {
+        state.running = false;
+        return;
+      }
+    }
+  }
+
+  /// Runs a backup on a blocking thread, trying again after a failure or a timeout as many times as `retry`
+  /// allows, and records how it went in the state file
+  async fn run_with_retries(&self) {
+    let mut backoff = self.backoff;
+    for attempt in 1..=self.attempts {
+      let permit = match self.limit.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+          info!("too many backups running, waiting for a free slot");
+          // the semaphore is never closed
+          self.limit.clone().acquire_owned().await.unwrap()
+        }
+      };
+      if attempt == 1 {
+        self.store.start(&self.config.name);
+      }
+
+      let config = self.config.clone();
+      let span = Span::current();
+      let mut backup =
+        tokio::task::spawn_blocking(move || span.in_scope(|| run_task(&config, &BackupOptions::default())));
+      let mut permit = Some(permit);
+      let mut timed_out = false;
+      let result = match self.timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, &mut backup).await {
+          Ok(joined) => joined.unwrap_or_else(|e| Err(anyhow::anyhow!("backup panicked: {}", e))),
+          Err(_) => {
+            error!("backup timed out after {:?}", timeout);
+            let result = Err(anyhow::anyhow!("timed out after {:?}", timeout));
+            timed_out = true;
+            drop(permit.take());
+            self.store.finish(&self.config.name, RunOutcome::of(&result));
+            self.run_timeout_hook(&result, timeout).await;
+            // a blocked read can't be interrupted: until it returns the task stays running, so no other
+            // run of it starts, but it no longer takes a slot from other tasks
+            let _ = backup.await;
+            warn!("backup that timed out has returned");
+            result
           }
+        },
+        None => backup.await.unwrap_or_else(|e| Err(anyhow::anyhow!("backup panicked: {}", e))),
+      };
+      drop(permit);
+
+      // `run_task` has logged the failure already, a timeout is recorded when it happens
+      if result.is_ok() || attempt == self.attempts {
+        if !timed_out {
+          self.store.finish(&self.config.name, RunOutcome::of(&result));
         }
-      });
+        return;
+      }
+      warn!("attempt {} of {} failed, retrying in {:?}", attempt, self.attempts, backoff);
+      tokio::time::sleep(backoff).await;
+      backoff *= 2;
+    }
+  }
+
+  /// Runs the `on-failure` hook of a run that timed out, `run_task` only gets to it once the backup returns
+  async fn run_timeout_hook(&self, result: &anyhow::Result<BackupSummary>, elapsed: Duration) {
+    let Some(on_failure) = self.config.hooks.on_failure.clone() else {
+      return;
+    };
+    let env = hook_env(&self.config, Some((result, elapsed)));
+    let span = Span::current();
+    let hook =
+      tokio::task::spawn_blocking(move || span.in_scope(|| run_hook("on-failure", &on_failure, &env)));
+    if let Ok(Err(e)) = hook.await {
+      error!("{}", e);
+    }
+  }
+}
+
+/// Runs one backup of the task right away with its hooks, logging how it went
+pub fn run_task(config: &BackupTaskConfig, options: &BackupOptions) -> anyhow::Result<BackupSummary> {
+  let span = info_span!("backup", r#type = config.on.strategy.to_string());
+
+  let _guard = span.enter();
+  let start = std::time::Instant::now();
+  let result = match &config.hooks.before {
+    Some(before) => run_hook("before", before, &hook_env(config, None))
+      .and_then(|()| make_backup_with_options(config, options)),
+    None => make_backup_with_options(config, options),
+  };
+  let elapsed = start.elapsed();
+  match &result {
+    Ok(summary) => {
+      info!("backup of {} files ({} bytes) completed in {:?}", summary.files, summary.bytes, elapsed)
+    }
+    Err(e) => error!("backup failed after {:?}: {}", elapsed, e),
+  }
+
+  // the backup is done either way, a failing hook afterwards is only logged
+  let env = hook_env(config, Some((&result, elapsed)));
+  let on_failure = config.hooks.on_failure.as_ref().filter(|_| result.is_err());
+  for (name, command) in [("after", config.hooks.after.as_ref()), ("on-failure", on_failure)] {
+    if let Some(command) = command {
+      if let Err(e) = run_hook(name, command, &env) {
+        error!("{}", e);
+      }
+    }
+  }
+
+  result
+}
+
+/// Watches `src` recursively and sends a message for every change that could affect the backup:
+/// reads, excluded paths and anything under `dst` are ignored
+fn watch_changes(config: &BackupTaskConfig) -> anyhow::Result<(RecommendedWatcher, UnboundedReceiver<()>)> {
+  let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+  let filter = Filter::new(config);
+  let dst = config.dst.clone();
 
-      tokio::spawn(async move {
-        loop {
-          scheduler.run_pending().await;
-          tokio::time::sleep(Duration::from_secs(1)).await;
+  let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+    Ok(event) => {
+      if matches!(event.kind, EventKind::Access(_)) {
+        return;
+      }
+      let relevant =
+        event.paths.iter().any(|path| !path.starts_with(&dst) && !filter.is_excluded(path, path.is_dir()));
+      if relevant {
+        debug!("change: {:?} {:?}", event.kind, event.paths);
+        // the receiver only goes away when the task stops
+        let _ = tx.send(());
+      }
+    }
+    Err(e) => error!("failed to watch for changes: {}", e),
+  })?;
+  watcher.watch(&config.src, RecursiveMode::Recursive)?;
+
+  Ok((watcher, rx))
+}
+
+/// Waits for the next burst of changes to settle: returns once nothing changed for `quiet`, or
+/// `max_delay` after the first change of the burst. Returns `false` once no more changes can come
+pub async fn wait_for_changes(
+  changes: &mut UnboundedReceiver<()>,
+  quiet: Duration,
+  max_delay: Duration,
+) -> bool {
+  if changes.recv().await.is_none() {
+    return false;
+  }
+
+  let deadline = Instant::now() + max_delay;
+  loop {
+    let wake = (Instant::now() + quiet).min(deadline);
+    tokio::select! {
+      change = changes.recv() => {
+        if change.is_none() {
+          return true;
         }
-      });
+      }
+      _ = tokio::time::sleep_until(wake) => return true,
     }
   }
+}
 
-  Ok(())
+/// When a scheduled run after `last_start` was due, if that is already past `now`.
+/// Tasks triggered by changes have no schedule to miss
+pub fn missed_run(
+  trigger: &BackupTrigger,
+  last_start: DateTime<Local>,
+  now: DateTime<Local>,
+) -> anyhow::Result<Option<DateTime<Local>>> {
+  Ok(next_run(trigger, last_start)?.filter(|next| *next <= now))
+}
+
+/// First scheduled run after starting at `now`: the one due after the last run, so restarting doesn't
+/// push it back. If that one was missed, or the task never ran, the next one from `now`
+pub fn first_run(
+  trigger: &BackupTrigger,
+  last_start: Option<DateTime<Local>>,
+  now: DateTime<Local>,
+) -> anyhow::Result<Option<DateTime<Local>>> {
+  if let Some(last_start) = last_start {
+    if let Some(next) = next_run(trigger, last_start)?.filter(|next| *next > now) {
+      return Ok(Some(next));
+    }
+  }
+  next_run(trigger, now)
+}
+
+/// Next scheduled run after `after`; tasks triggered by changes have none
+pub fn next_run(trigger: &BackupTrigger, after: DateTime<Local>) -> anyhow::Result<Option<DateTime<Local>>> {
+  let next = match trigger {
+    BackupTrigger::Change { .. } => return Ok(None),
+    BackupTrigger::Schedule { cron: Some(cron), .. } => parse_cron(cron)?
+      .find_next_occurrence(&after, false)
+      .map_err(|e| anyhow::anyhow!("no next run of `{}`: {}", cron, e))?,
+    BackupTrigger::Schedule { every, at, cron: None } => {
+      let at = at.as_deref().map(parse_at).transpose()?;
+      let intervals = parse_schedule(&schedule_every(every))?;
+      // like clokwerk, `at` applies to the last interval only
+      let last = intervals.len().saturating_sub(1);
+      let next = intervals
+        .into_iter()
+        .enumerate()
+        .map(|(i, interval)| match (interval_length(interval), at.filter(|_| i == last)) {
+          // a plain interval counts from the previous run, calendar ones are aligned like clokwerk does it
+          (Some(length), None) => after + length,
+          (_, at) => next_interval_run(interval, at, &after),
+        })
+        .min();
+      match next {
+        Some(next) => next,
+        None => return Ok(None),
+      }
+    }
+  };
+
+  Ok(Some(next))
+}
+
+/// How long `interval` is, unless it names days of the week
+fn interval_length(interval: Interval) -> Option<chrono::Duration> {
+  match interval {
+    Interval::Seconds(count) => Some(chrono::Duration::seconds(count as i64)),
+    Interval::Minutes(count) => Some(chrono::Duration::minutes(count as i64)),
+    Interval::Hours(count) => Some(chrono::Duration::hours(count as i64)),
+    Interval::Days(count) => Some(chrono::Duration::days(count as i64)),
+    Interval::Weeks(count) => Some(chrono::Duration::weeks(count as i64)),
+    _ => None,
+  }
+}
+
+/// Next run of `interval` at `at` after `from`, computed the same way clokwerk schedules it
+fn next_interval_run(interval: Interval, at: Option<NaiveTime>, from: &DateTime<Local>) -> DateTime<Local> {
+  let adjust = |time: DateTime<Local>| match at {
+    Some(at) => {
+      let date = match at >= time.time() {
+        true => time.date_naive(),
+        false => time.date_naive() + chrono::Days::new(1),
+      };
+      Local.from_local_datetime(&date.and_time(at)).earliest().unwrap_or(time)
+    }
+    None => time,
+  };
+
+  let candidate = adjust(interval.prev(from));
+  match candidate > *from {
+    true => candidate,
+    false => adjust(interval.next(from)),
+  }
 }
 
-fn parse_schedule(every: &Vec<String>) -> anyhow::Result<Vec<Interval>> {
+/// Parses the time of day of a schedule, like `10:00`, `10:00:30` or `3:20 pm`
+pub fn parse_at(at: &str) -> anyhow::Result<NaiveTime> {
+  ["%H:%M:%S", "%I:%M:%S %p", "%H:%M", "%I:%M %p"]
+    .iter()
+    .find_map(|format| NaiveTime::parse_from_str(at, format).ok())
+    .ok_or_else(|| anyhow::anyhow!("invalid time of day `{}`, expected e.g. `10:00` or `3:20 pm`", at))
+}
+
+/// Parses a 5-field cron expression, or a 6-field one with seconds first
+pub fn parse_cron(cron: &str) -> anyhow::Result<Cron> {
+  Cron::new(cron)
+    .with_seconds_optional()
+    .parse()
+    .map_err(|e| anyhow::anyhow!("invalid cron expression `{}`: {}", cron, e))
+}
+
+/// Parses durations like `10 seconds` or `5 minutes`
+pub fn parse_duration(duration: &str) -> anyhow::Result<Duration> {
+  let (count, unit) = duration.trim().split_once(' ').unwrap_or((duration, ""));
+  let count = count.parse::<u64>()?;
+  let unit = unit.trim().to_lowercase();
+
+  let duration = match unit.as_str() {
+    "millisecond" | "milliseconds" => Duration::from_millis(count),
+    "second" | "seconds" => Duration::from_secs(count),
+    "minute" | "minutes" => Duration::from_secs(count * 60),
+    "hour" | "hours" => Duration::from_secs(count * 60 * 60),
+    "day" | "days" => Duration::from_secs(count * 24 * 60 * 60),
+    _ => anyhow::bail!(
+      "invalid duration: `{}`, must be a number and one of: milliseconds, seconds, minutes, hours, days",
+      duration
+    ),
+  };
+
+  Ok(duration)
+}
+
+pub(crate) fn parse_schedule(every: &Vec<String>) -> anyhow::Result<Vec<Interval>> {
   const UNITS: &[&str] = &[
     "day",
     "days",
@@ -97,6 +496,9 @@ fn parse_schedule(every: &Vec<String>) -> anyhow::Result<Vec<Interval>> {
     let (count, unit) = every.split_once(' ').unwrap_or(("1", every));
     let count = count.parse::<u32>()?;
     let unit = unit.to_lowercase();
+    if count == 0 {
+      anyhow::bail!("invalid interval: `{}`, the count must be at least 1", every);
+    }
 
     if !UNITS.contains(&unit.as_str()) {
       anyhow::bail!("invalid unit: {}, must be one of: {}", unit, UNITS.join(", "));
@@ -0,0 +1,101 @@
+use std::path::Path;
+
+use chrono::Local;
+use chrono::NaiveDateTime;
+
+const SNAPSHOT_NAME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+const SNAPSHOT_NAME_FORMAT_PRECISE: &str = "%Y-%m-%dT%H:%M:%S%.6f";
+const SNAPSHOT_NAME_PARSE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+  pub name: String,
+  pub time: NaiveDateTime,
+}
+
+impl std::fmt::Display for Snapshot {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.name)
+  }
+}
+
+/// Name for a snapshot taken right now, e.g. `2026-10-17T10:00:00`.
+/// Falls back to sub-second precision if `taken` says the name is already in use.
+pub fn new_snapshot_name(taken: impl Fn(&str) -> bool) -> String {
+  let now = Local::now().naive_local();
+  let name = now.format(SNAPSHOT_NAME_FORMAT).to_string();
+  if !taken(&name) {
+    return name;
+  }
+
+  now.format(SNAPSHOT_NAME_FORMAT_PRECISE).to_string()
+}
+
+pub fn parse_snapshot_name(name: &str) -> Option<Snapshot> {
+  let time = NaiveDateTime::parse_from_str(name, SNAPSHOT_NAME_PARSE_FORMAT).ok()?;
+  Some(Snapshot { name: name.to_string(), time })
+}
+
+/// Lists snapshots stored as entries of `dir`, oldest first. With `ext`, only entries with
+/// that extension are considered and the extension is stripped from the name.
+pub fn list_snapshots(dir: &Path, ext: Option<&str>) -> std::io::Result<Vec<Snapshot>> {
+  let mut snapshots = Vec::new();
+
+  if !dir.exists() {
+    return Ok(snapshots);
+  }
+
+  for entry in std::fs::read_dir(dir)? {
+    let entry = entry?;
+    let file_name = entry.file_name();
+    let Some(file_name) = file_name.to_str() else {
+      continue;
+    };
+
+    let name = match ext {
+      Some(ext) => match file_name.strip_suffix(ext).and_then(|name| name.strip_suffix('.')) {
+        Some(name) => name,
+        None => continue,
+      },
+      None => file_name,
+    };
+
+    if let Some(snapshot) = parse_snapshot_name(name) {
+      snapshots.push(snapshot);
+    }
+  }
+
+  snapshots.sort_by_key(|snapshot| snapshot.time);
+  Ok(snapshots)
+}
+
+/// Picks a snapshot by its exact name or, given a point in time (`2026-10-17`, `2026-10-17T10:00`, ...),
+/// the latest snapshot taken at or before it. Without `spec`, the latest snapshot is returned
+pub fn select_snapshot(snapshots: &[Snapshot], spec: Option<&str>) -> anyhow::Result<Snapshot> {
+  let Some(spec) = spec else {
+    return snapshots.last().cloned().ok_or(anyhow::anyhow!("no snapshots found"));
+  };
+
+  if let Some(snapshot) = snapshots.iter().find(|snapshot| snapshot.name == spec) {
+    return Ok(snapshot.clone());
+  }
+
+  let time = parse_point_in_time(spec)
+    .ok_or(anyhow::anyhow!("`{}` is neither a snapshot name nor a point in time", spec))?;
+
+  snapshots
+    .iter()
+    .rev()
+    .find(|snapshot| snapshot.time <= time)
+    .cloned()
+    .ok_or(anyhow::anyhow!("no snapshots taken at or before {}", time))
+}
+
+fn parse_point_in_time(spec: &str) -> Option<NaiveDateTime> {
+  const FORMATS: &[&str] =
+    &[SNAPSHOT_NAME_PARSE_FORMAT, "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"];
+
+  FORMATS.iter().find_map(|format| NaiveDateTime::parse_from_str(spec, format).ok()).or_else(|| {
+    chrono::NaiveDate::parse_from_str(spec, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(23, 59, 59))
+  })
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::DateTime;
+use chrono::Local;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use tracing::*;
+
+use crate::backup::BackupSummary;
+use crate::manifest::write_atomic;
+
+/// State file used when the config doesn't name one, relative to the working directory
+pub const DEFAULT_STATE_FILE: &str = "backups-state.json";
+
+/// Last run of every task, keyed by task name
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct State {
+  pub tasks: BTreeMap<String, TaskState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct TaskState {
+  pub last_start: DateTime<Local>,
+  /// Missing while the run is in progress, or if the program stopped in the middle of it
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub last_finish: Option<DateTime<Local>>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub outcome: Option<RunOutcome>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum RunOutcome {
+  Success { files: u64, bytes: u64 },
+  Failure { error: String },
+}
+
+impl RunOutcome {
+  pub fn of(result: &anyhow::Result<BackupSummary>) -> Self {
+    match result {
+      Ok(summary) => RunOutcome::Success { files: summary.files, bytes: summary.bytes },
+      Err(e) => RunOutcome::Failure { error: e.to_string() },
+    }
+  }
+}
+
+/// Keeps the state file up to date as tasks start and finish; shared by all tasks
+pub struct StateStore {
+  path: PathBuf,
+  state: Mutex<State>,
+}
+
+impl StateStore {
+  /// Loads the state file at `path`; a missing file means nothing ran yet
+  pub fn open(path: &Path) -> anyhow::Result<Self> {
+    Ok(Self { path: path.to_path_buf(), state: Mutex::new(read_state(path)?) })
+  }
+
+  pub fn task(&self, name: &str) -> Option<TaskState> {
+    self.state.lock().unwrap().tasks.get(name).cloned()
+  }
+
+  /// Runs `backup` of task `name`, recording when it started and how it ended.
+  /// Failing to write the state file is logged, but doesn't fail the backup
+  pub fn track(
+    &self,
+    name: &str,
+    backup: impl FnOnce() -> anyhow::Result<BackupSummary>,
+  ) -> anyhow::Result<BackupSummary> {
+    self.start(name);
+    let result = backup();
+    self.finish(name, RunOutcome::of(&result));
+    result
+  }
+
+  /// Records that a run of task `name` started just now
+  pub fn start(&self, name: &str) {
+    let task = TaskState { last_start: Local::now(), last_finish: None, outcome: None };
+    self.update(|state| {
+      state.tasks.insert(name.to_string(), task);
+    });
+  }
+
+  /// Records how the run of task `name` started last ended
+  pub fn finish(&self, name: &str, outcome: RunOutcome) {
+    self.update(|state| {
+      if let Some(task) = state.tasks.get_mut(name) {
+        task.last_finish = Some(Local::now());
+        task.outcome = Some(outcome);
+      }
+    });
+  }
+
+  fn update(&self, update: impl FnOnce(&mut State)) {
+    let mut state = self.state.lock().unwrap();
+    // `run` may have recorded a run from another process in the meantime
+    if let Ok(current) = read_state(&self.path) {
+      *state = current;
+    }
+    update(&mut state);
+
+    let written = serde_json::to_vec_pretty(&*state).map_err(anyhow::Error::from).and_then(|data| {
+      // a bare file name has an empty parent, which `write_atomic` can't create a temp file in
+      let path = match self.path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new(".").join(&self.path),
+        _ => self.path.clone(),
+      };
+      write_atomic(&path, &data)
+    });
+    if let Err(e) = written {
+      warn!("failed to write state file {}: {}", self.path.display(), e);
+    }
+  }
+}
+
+fn read_state(path: &Path) -> anyhow::Result<State> {
+  match std::fs::read(path) {
+    Ok(data) => serde_json::from_slice(&data)
+      .map_err(|e| anyhow::anyhow!("invalid state file {}: {}", path.display(), e)),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+    Err(e) => Err(e.into()),
+  }
+}
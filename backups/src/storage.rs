@@ -0,0 +1,365 @@
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+use chrono::Utc;
+use hmac::Hmac;
+use hmac::Mac;
+use serde_derive::Deserialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::*;
+
+use crate::manifest::write_atomic;
+
+const S3_SCHEME: &str = "s3://";
+
+/// Where backups are kept: objects named by `/`-separated keys relative to the root of `dst`.
+/// Objects are written whole, a reader never sees a partially written one
+pub trait Storage: std::fmt::Display + Send + Sync {
+  fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()>;
+  fn get(&self, key: &str) -> anyhow::Result<Vec<u8>>;
+  fn exists(&self, key: &str) -> anyhow::Result<bool>;
+  /// All objects under `prefix`, at any depth; an empty prefix lists everything
+  fn list(&self, prefix: &str) -> anyhow::Result<Vec<Object>>;
+  /// Removing a missing object is not an error
+  fn delete(&self, key: &str) -> anyhow::Result<()>;
+  fn rename(&self, from: &str, to: &str) -> anyhow::Result<()>;
+  /// Where the object is, for messages and reports
+  fn path(&self, key: &str) -> PathBuf;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Object {
+  pub key: String,
+  pub size: u64,
+}
+
+/// Whether `dst` names an object store rather than a local directory
+pub fn is_remote(dst: &Path) -> bool {
+  dst.to_str().is_some_and(|dst| dst.starts_with(S3_SCHEME))
+}
+
+/// Storage for a task's `dst`: a local directory, or `s3://bucket/prefix`
+pub fn open_storage(dst: &Path) -> anyhow::Result<Box<dyn Storage>> {
+  match dst.to_str().and_then(|dst| dst.strip_prefix(S3_SCHEME)) {
+    Some(location) => Ok(Box::new(S3Storage::from_env(location)?)),
+    None => Ok(Box::new(LocalStorage::new(dst))),
+  }
+}
+
+pub struct LocalStorage {
+  root: PathBuf,
+}
+
+impl LocalStorage {
+  pub fn new(root: &Path) -> Self {
+    Self { root: root.to_path_buf() }
+  }
+}
+
+impl std::fmt::Display for LocalStorage {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.root.display())
+  }
+}
+
+impl Storage for LocalStorage {
+  fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+    let path = self.root.join(key);
+    std::fs::create_dir_all(path.parent().expect("object path always has a parent"))?;
+    write_atomic(&path, data)
+  }
+
+  fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+    Ok(std::fs::read(self.root.join(key))?)
+  }
+
+  fn exists(&self, key: &str) -> anyhow::Result<bool> {
+    Ok(self.root.join(key).is_file())
+  }
+
+  fn list(&self, prefix: &str) -> anyhow::Result<Vec<Object>> {
+    let mut objects = Vec::new();
+    let dir = self.root.join(prefix);
+    if dir.is_dir() {
+      list_dir(&self.root, &dir, &mut objects)?;
+    }
+    objects.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(objects)
+  }
+
+  fn delete(&self, key: &str) -> anyhow::Result<()> {
+    match std::fs::remove_file(self.root.join(key)) {
+      Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+      _ => Ok(()),
+    }
+  }
+
+  fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+    let to = self.root.join(to);
+    std::fs::create_dir_all(to.parent().expect("object path always has a parent"))?;
+    std::fs::rename(self.root.join(from), to)?;
+    Ok(())
+  }
+
+  fn path(&self, key: &str) -> PathBuf {
+    self.root.join(key)
+  }
+}
+
+fn list_dir(root: &Path, dir: &Path, objects: &mut Vec<Object>) -> anyhow::Result<()> {
+  for entry in std::fs::read_dir(dir)? {
+    let entry = entry?;
+    let path = entry.path();
+    if entry.file_type()?.is_dir() {
+      list_dir(root, &path, objects)?;
+    } else {
+      let key = path.strip_prefix(root)?.to_string_lossy().into_owned();
+      objects.push(Object { key, size: entry.metadata()?.len() });
+    }
+  }
+  Ok(())
+}
+
+/// Bucket of an S3-compatible object store, addressed path-style (`<endpoint>/<bucket>/<key>`) so
+/// it works with MinIO and similar servers as well as AWS. Requests are signed with AWS Signature V4
+pub struct S3Storage {
+  endpoint: String,
+  region: String,
+  bucket: String,
+  /// Prepended to every key, without leading or trailing `/`
+  prefix: String,
+  access_key: String,
+  secret_key: String,
+  agent: ureq::Agent,
+}
+
+impl S3Storage {
+  pub fn new(
+    endpoint: &str,
+    region: &str,
+    location: &str,
+    access_key: &str,
+    secret_key: &str,
+  ) -> anyhow::Result<Self> {
+    let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+    if bucket.is_empty() {
+      anyhow::bail!("no bucket in `{}{}`", S3_SCHEME, location);
+    }
+    Ok(Self {
+      endpoint: endpoint.trim_end_matches('/').to_string(),
+      region: region.to_string(),
+      bucket: bucket.to_string(),
+      prefix: prefix.trim_matches('/').to_string(),
+      access_key: access_key.to_string(),
+      secret_key: secret_key.to_string(),
+      agent: ureq::AgentBuilder::new().build(),
+    })
+  }
+
+  /// Takes the credentials from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, the region from
+  /// `AWS_REGION` and the endpoint from `AWS_ENDPOINT_URL`, defaulting to the AWS one of the region
+  pub fn from_env(location: &str) -> anyhow::Result<Self> {
+    let var = |name: &str| {
+      std::env::var(name)
+        .map_err(|_| anyhow::anyhow!("`{}` must be set to use `{}{}`", name, S3_SCHEME, location))
+    };
+    let region = std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+    let endpoint =
+      std::env::var("AWS_ENDPOINT_URL").unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region));
+    Self::new(&endpoint, &region, location, &var("AWS_ACCESS_KEY_ID")?, &var("AWS_SECRET_ACCESS_KEY")?)
+  }
+
+  fn full_key(&self, key: &str) -> String {
+    match self.prefix.is_empty() {
+      true => key.to_string(),
+      false => format!("{}/{}", self.prefix, key),
+    }
+  }
+
+  /// Sends a signed request for `key` (the bucket itself if `None`) and returns the response,
+  /// or `None` for 404
+  fn request(
+    &self,
+    method: &str,
+    key: Option<&str>,
+    query: &[(&str, &str)],
+    headers: &[(&str, &str)],
+    body: &[u8],
+  ) -> anyhow::Result<Option<ureq::Response>> {
+    let path = match key {
+      Some(key) => format!("/{}/{}", self.bucket, uri_encode(&self.full_key(key), false)),
+      None => format!("/{}", self.bucket),
+    };
+    let mut query = query.iter().map(|(k, v)| (uri_encode(k, true), uri_encode(v, true))).collect::<Vec<_>>();
+    query.sort();
+    let query = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&");
+
+    let host = self.endpoint.split_once("://").map_or(self.endpoint.as_str(), |(_, host)| host);
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = hex(&Sha256::digest(body));
+
+    let mut signed = vec![
+      ("host", host.to_string()),
+      ("x-amz-content-sha256", payload_hash.clone()),
+      ("x-amz-date", amz_date.clone()),
+    ];
+    signed.extend(headers.iter().map(|(name, value)| (*name, value.to_string())));
+    signed.sort();
+    let canonical_headers =
+      signed.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect::<String>();
+    let signed_headers = signed.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+
+    let canonical_request =
+      [method, &path, &query, &canonical_headers, &signed_headers, &payload_hash].join("\n");
+    let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+    let string_to_sign =
+      ["AWS4-HMAC-SHA256", &amz_date, &scope, &hex(&Sha256::digest(canonical_request.as_bytes()))].join("\n");
+    let key = [date.as_str(), &self.region, "s3", "aws4_request"]
+      .iter()
+      .fold(format!("AWS4{}", self.secret_key).into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+    let authorization = format!(
+      "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+      self.access_key, scope, signed_headers, signature
+    );
+
+    let url = match query.is_empty() {
+      true => format!("{}{}", self.endpoint, path),
+      false => format!("{}{}?{}", self.endpoint, path, query),
+    };
+    let mut request = self.agent.request(method, &url).set("authorization", &authorization);
+    for (name, value) in signed.iter().filter(|(name, _)| *name != "host") {
+      request = request.set(name, value);
+    }
+    match request.send_bytes(body) {
+      Ok(response) => Ok(Some(response)),
+      Err(ureq::Error::Status(404, _)) => Ok(None),
+      Err(ureq::Error::Status(status, response)) => {
+        let message = response.into_string().unwrap_or_default();
+        anyhow::bail!("{} {} failed with status {}: {}", method, url, status, message)
+      }
+      Err(e) => Err(e.into()),
+    }
+  }
+}
+
+impl std::fmt::Display for S3Storage {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}{}/{}", S3_SCHEME, self.bucket, self.prefix)
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+  #[serde(default)]
+  contents: Vec<ListedObject>,
+  #[serde(default)]
+  is_truncated: bool,
+  next_continuation_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedObject {
+  key: String,
+  size: u64,
+}
+
+impl Storage for S3Storage {
+  fn put(&self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+    self.request("PUT", Some(key), &[], &[], data)?;
+    Ok(())
+  }
+
+  fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+    let Some(response) = self.request("GET", Some(key), &[], &[], &[])? else {
+      anyhow::bail!("no object {}", self.path(key).display());
+    };
+    let mut data = Vec::new();
+    response.into_reader().read_to_end(&mut data)?;
+    Ok(data)
+  }
+
+  fn exists(&self, key: &str) -> anyhow::Result<bool> {
+    Ok(self.request("HEAD", Some(key), &[], &[], &[])?.is_some())
+  }
+
+  fn list(&self, prefix: &str) -> anyhow::Result<Vec<Object>> {
+    let full_prefix = match self.full_key(prefix).trim_end_matches('/') {
+      "" => String::new(),
+      full_prefix => format!("{}/", full_prefix),
+    };
+    let strip = match self.prefix.is_empty() {
+      true => 0,
+      false => self.prefix.len() + 1,
+    };
+
+    let mut objects = Vec::new();
+    let mut token: Option<String> = None;
+    loop {
+      let mut query = vec![("list-type", "2"), ("prefix", full_prefix.as_str())];
+      if let Some(token) = &token {
+        query.push(("continuation-token", token.as_str()));
+      }
+      let Some(response) = self.request("GET", None, &query, &[], &[])? else {
+        anyhow::bail!("no bucket `{}`", self.bucket);
+      };
+      let result: ListBucketResult = quick_xml::de::from_str(&response.into_string()?)?;
+      objects.extend(
+        result
+          .contents
+          .into_iter()
+          .map(|object| Object { key: object.key[strip..].to_string(), size: object.size }),
+      );
+
+      match result.next_continuation_token.filter(|_| result.is_truncated) {
+        Some(next) => token = Some(next),
+        None => break,
+      }
+    }
+    debug!("listed {} objects under {}", objects.len(), self.path(prefix).display());
+    Ok(objects)
+  }
+
+  fn delete(&self, key: &str) -> anyhow::Result<()> {
+    self.request("DELETE", Some(key), &[], &[], &[])?;
+    Ok(())
+  }
+
+  fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+    // S3 has no rename: copy, then remove the original
+    let source = format!("/{}/{}", self.bucket, uri_encode(&self.full_key(from), false));
+    self.request("PUT", Some(to), &[], &[("x-amz-copy-source", &source)], &[])?;
+    self.delete(from)
+  }
+
+  fn path(&self, key: &str) -> PathBuf {
+    PathBuf::from(format!("{}{}/{}", S3_SCHEME, self.bucket, self.full_key(key)))
+  }
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` unless `slash` is set, as SigV4 wants
+fn uri_encode(s: &str, slash: bool) -> String {
+  s.bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+      b'/' if !slash => "/".to_string(),
+      b => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes a key of any size");
+  mac.update(data);
+  mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
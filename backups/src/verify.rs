@@ -0,0 +1,246 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use tracing::*;
+
+use crate::backup::archive::*;
+use crate::backup::differential;
+use crate::backup::list_backup_snapshots;
+use crate::backup::repository::Repository;
+use crate::config::*;
+use crate::manifest::*;
+use crate::snapshot::*;
+
+#[derive(Default, Debug)]
+pub struct VerifyReport {
+  /// Number of files whose content was checked
+  pub checked: usize,
+  /// Entries of the manifest that are gone from the backup
+  pub missing: Vec<PathBuf>,
+  /// Files whose content no longer matches the hash in the manifest
+  pub corrupted: Vec<PathBuf>,
+  /// Files in the backup that the manifest doesn't know about
+  pub extra: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+  pub fn is_intact(&self) -> bool {
+    self.missing.is_empty() && self.corrupted.is_empty()
+  }
+}
+
+/// Re-hashes the stored copy of the backup (the given or latest snapshot, if the strategy keeps them)
+/// and compares it against the manifest written by the backup run
+pub fn verify(config: &BackupTaskConfig, snapshot: Option<&str>) -> anyhow::Result<VerifyReport> {
+  if config.src.is_file() {
+    anyhow::bail!("verifying single-file tasks is not supported");
+  }
+  if crate::fs::is_sftp(&config.dst) {
+    anyhow::bail!("verifying an sftp dst is not supported, verify a local copy of {}", config.dst.display());
+  }
+
+  let report = match list_backup_snapshots(config)? {
+    None => {
+      if snapshot.is_some() {
+        anyhow::bail!("strategy `{}` keeps a single copy and has no snapshots", config.on.strategy);
+      }
+      match config.on.strategy {
+        BackupStrategyConfig::Differential => verify_differential(&config.dst)?,
+        _ => verify_tree(&config.dst)?,
+      }
+    }
+    Some(snapshots) => {
+      let snapshot = select_snapshot(&snapshots, snapshot)?;
+      info!("verifying snapshot {}", snapshot);
+      match config.on.strategy {
+        BackupStrategyConfig::Repository => verify_repository(&Repository::for_task(config)?, &snapshot)?,
+        _ if config.on.format.is_archive() => verify_archive(&Archives::for_task(config)?, &snapshot)?,
+        _ => verify_tree(&config.dst.join(&snapshot.name))?,
+      }
+    }
+  };
+
+  for path in report.missing.iter() {
+    warn!("missing: {}", path.display());
+  }
+  for path in report.corrupted.iter() {
+    warn!("corrupted: {}", path.display());
+  }
+  for path in report.extra.iter() {
+    warn!("extra: {}", path.display());
+  }
+  info!(
+    "checked {} files: {} missing, {} corrupted, {} extra",
+    report.checked,
+    report.missing.len(),
+    report.corrupted.len(),
+    report.extra.len()
+  );
+
+  Ok(report)
+}
+
+fn verify_tree(root: &Path) -> anyhow::Result<VerifyReport> {
+  let manifest = Manifest::read_from_dir(root)?;
+  let mut report = VerifyReport::default();
+
+  for entry in manifest.entries.iter() {
+    check_entry(entry, &root.join(&entry.path), &mut report)?;
+  }
+
+  let known = manifest.entries.iter().map(|entry| entry.path.as_path()).collect::<HashSet<_>>();
+  find_extra_files(root, Path::new(""), &known, &mut report.extra)?;
+
+  Ok(report)
+}
+
+/// Checks the latest state of a differential task: every entry of the differential set's manifest
+/// must be stored either in the set or, unchanged, in the full backup
+fn verify_differential(dst: &Path) -> anyhow::Result<VerifyReport> {
+  let full = dst.join(differential::FULL_DIR_NAME);
+  if !full.is_dir() {
+    return verify_tree(dst);
+  }
+
+  let manifest = differential::current_manifest(dst)?;
+  let mut report = VerifyReport::default();
+  for entry in manifest.entries.iter() {
+    check_entry(entry, &differential::content_path(dst, &entry.path), &mut report)?;
+  }
+
+  let full_manifest = Manifest::read_from_dir(&full)?;
+  let known = full_manifest.entries.iter().map(|entry| entry.path.as_path()).collect::<HashSet<_>>();
+  find_extra_files(&full, Path::new(""), &known, &mut report.extra)?;
+  let diff = dst.join(differential::DIFF_DIR_NAME);
+  if diff.is_dir() {
+    let known = manifest.entries.iter().map(|entry| entry.path.as_path()).collect::<HashSet<_>>();
+    find_extra_files(&diff, Path::new(""), &known, &mut report.extra)?;
+  }
+
+  Ok(report)
+}
+
+fn check_entry(entry: &ManifestEntry, path: &Path, report: &mut VerifyReport) -> anyhow::Result<()> {
+  match entry.kind {
+    EntryKind::Dir if path.is_dir() => (),
+    EntryKind::Symlink if path.is_symlink() => {
+      report.checked += 1;
+      if std::fs::read_link(path).ok() != entry.link {
+        report.corrupted.push(entry.path.clone());
+      }
+    }
+    EntryKind::File if path.is_file() => {
+      report.checked += 1;
+      if entry.hash.as_deref() != Some(hash_file(path)?.as_str()) {
+        report.corrupted.push(entry.path.clone());
+      }
+    }
+    _ => report.missing.push(entry.path.clone()),
+  }
+  Ok(())
+}
+
+fn find_extra_files(
+  root: &Path,
+  rel: &Path,
+  known: &HashSet<&Path>,
+  extra: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+  let mut children = std::fs::read_dir(root.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+  children.sort_by_key(|entry| entry.file_name());
+
+  for child in children {
+    if rel == Path::new("") && child.file_name() == MANIFEST_FILE_NAME {
+      continue;
+    }
+
+    let rel_path = rel.join(child.file_name());
+    if !known.contains(rel_path.as_path()) {
+      extra.push(rel_path);
+    } else if child.file_type()?.is_dir() {
+      find_extra_files(root, &rel_path, known, extra)?;
+    }
+  }
+
+  Ok(())
+}
+
+fn verify_repository(repo: &Repository, snapshot: &Snapshot) -> anyhow::Result<VerifyReport> {
+  let manifest = repo.read_snapshot(&snapshot.name)?;
+  let mut report = VerifyReport::default();
+
+  'files: for entry in manifest.entries.iter().filter(|entry| entry.kind == EntryKind::File) {
+    report.checked += 1;
+    let mut file_hasher = blake3::Hasher::new();
+
+    for hash in entry.chunks.iter() {
+      if !repo.has_chunk(hash)? {
+        report.missing.push(entry.path.clone());
+        continue 'files;
+      }
+      let data = match repo.read_chunk(hash) {
+        Ok(data) if repo.chunk_id(&data) == *hash => data,
+        _ => {
+          report.corrupted.push(entry.path.clone());
+          continue 'files;
+        }
+      };
+      file_hasher.update(&data);
+    }
+
+    if entry.hash.as_deref() != Some(file_hasher.finalize().to_hex().as_str()) {
+      report.corrupted.push(entry.path.clone());
+    }
+  }
+
+  report.extra = repo.unreferenced_chunks()?;
+  Ok(report)
+}
+
+fn verify_archive(archives: &Archives, snapshot: &Snapshot) -> anyhow::Result<VerifyReport> {
+  let index = archives.read_index(&snapshot.name)?;
+  let mut report = VerifyReport::default();
+
+  let base = match &index.base {
+    Some(base) => match archives.read_index(base) {
+      Ok(base) => Some(base),
+      Err(_) => {
+        warn!("base archive {} is gone, the archive can't be restored", base);
+        report.missing.push(PathBuf::from(format!("{}.json", base)));
+        None
+      }
+    },
+    None => None,
+  };
+  let mut stored = index.stored_files(base.as_ref());
+  let known = index.manifest.entries.iter().map(|entry| entry.path.as_path()).collect::<HashSet<_>>();
+
+  let mut archive = archives.open_archive(&snapshot.name, &index)?;
+  for entry in archive.entries()? {
+    let entry = entry?;
+    let path = entry.path()?.into_owned();
+    if path == Path::new(DELETED_LIST_NAME) {
+      continue;
+    }
+
+    match stored.remove(&path) {
+      Some(expected) => {
+        report.checked += 1;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(entry)?;
+        if expected.hash.as_deref() != Some(hasher.finalize().to_hex().as_str()) {
+          report.corrupted.push(path);
+        }
+      }
+      None if !entry.header().entry_type().is_file() && known.contains(path.as_path()) => (),
+      None => report.extra.push(path),
+    }
+  }
+
+  let mut missing = stored.into_keys().collect::<Vec<_>>();
+  missing.sort();
+  report.missing.extend(missing);
+
+  Ok(report)
+}
@@ -1,4 +1,5 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use backups::backup::*;
 use backups::config::*;
@@ -13,17 +14,7 @@ fn prepare_test_dir() -> (PathBuf, PathBuf, TempDir, BackupTaskConfig) {
   std::fs::create_dir_all(&src).unwrap();
   std::fs::create_dir_all(&dst).unwrap();
 
-  let config = BackupTaskConfig {
-    src: src.clone(),
-    dst: dst.clone(),
-    on: BackupTriggerConfig {
-      trigger: BackupTrigger::Schedule {
-        every: vec!["1 second".to_string()],
-        at: Some("00:00:00".to_string()),
-      },
-      strategy: BackupStrategyConfig::Differential,
-    },
-  };
+  let config = BackupTaskConfig::new("test", &src, &dst, BackupStrategyConfig::Differential);
 
   std::fs::write(src.join("file1"), "content1").unwrap();
   std::fs::write(src.join("file2"), "content2").unwrap();
@@ -32,30 +23,107 @@ fn prepare_test_dir() -> (PathBuf, PathBuf, TempDir, BackupTaskConfig) {
   (src, dst, temp_dir, config)
 }
 
+/// Lets the filesystem clock move on, so files written after a backup get a newer mtime
+fn tick() {
+  std::thread::sleep(Duration::from_millis(50));
+}
+
 #[test]
 fn differential_backup() {
-  let (src, dst, _temp_dir, config) = prepare_test_dir();
+  let (src, dst, temp_dir, mut config) = prepare_test_dir();
+  config.on.full_every = FullEveryConfig { runs: Some(3), days: None };
+  let (full, diff) = (dst.join(differential::FULL_DIR_NAME), dst.join(differential::DIFF_DIR_NAME));
 
   make_backup(&config).unwrap();
 
-  assert_eq!(std::fs::read_to_string(dst.join("file1")).unwrap(), "content1");
-  assert_eq!(std::fs::read_to_string(dst.join("file2")).unwrap(), "content2");
-  assert_eq!(std::fs::read_to_string(dst.join("dir1/file3")).unwrap(), "content3");
+  assert_eq!(std::fs::read_to_string(full.join("file1")).unwrap(), "content1");
+  assert_eq!(std::fs::read_to_string(full.join("file2")).unwrap(), "content2");
+  assert_eq!(std::fs::read_to_string(full.join("dir1/file3")).unwrap(), "content3");
+  assert!(!diff.exists());
 
   std::fs::write(src.join("file2"), "content2_modified").unwrap();
   std::fs::remove_file(src.join("dir1/file3")).unwrap();
+  make_backup(&config).unwrap();
 
+  // the set holds only what changed since the full backup, which stays as it was
+  assert_eq!(std::fs::read_to_string(diff.join("file2")).unwrap(), "content2_modified");
+  assert!(!diff.join("file1").exists());
+  assert_eq!(std::fs::read_to_string(full.join("file2")).unwrap(), "content2");
+
+  // the next set is still relative to the full backup, not to the previous set
+  std::fs::write(src.join("file4"), "content4").unwrap();
   make_backup(&config).unwrap();
+  assert_eq!(std::fs::read_to_string(diff.join("file2")).unwrap(), "content2_modified");
+  assert_eq!(std::fs::read_to_string(diff.join("file4")).unwrap(), "content4");
 
-  assert_eq!(std::fs::read_to_string(dst.join("file1")).unwrap(), "content1");
-  assert_eq!(std::fs::read_to_string(dst.join("file2")).unwrap(), "content2_modified");
-  assert!(dst.join("dir1").exists());
-  assert!(!dst.join("dir1/file3").exists());
+  let target = temp_dir.path().join("restored");
+  backups::restore::restore(
+    &config,
+    &backups::restore::RestoreOptions { target: Some(target.clone()), ..Default::default() },
+  )
+  .unwrap();
+  assert_eq!(std::fs::read_to_string(target.join("file1")).unwrap(), "content1");
+  assert_eq!(std::fs::read_to_string(target.join("file2")).unwrap(), "content2_modified");
+  assert_eq!(std::fs::read_to_string(target.join("file4")).unwrap(), "content4");
+  assert!(target.join("dir1").is_dir());
+  assert!(!target.join("dir1/file3").exists());
+
+  // the fourth run starts a new cycle
+  make_backup(&config).unwrap();
+  assert!(!diff.exists());
+  assert_eq!(std::fs::read_to_string(full.join("file2")).unwrap(), "content2_modified");
+  assert!(!full.join("dir1/file3").exists());
+
+  // the replaced copies are gone, nothing is left next to dst or in it
+  let names = |dir: &std::path::Path| {
+    let mut names = std::fs::read_dir(dir)
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect::<Vec<_>>();
+    names.sort();
+    names
+  };
+  assert_eq!(names(temp_dir.path()), vec!["dst", "restored", "src"]);
+  assert_eq!(names(&dst), vec![differential::STATE_FILE_NAME, differential::FULL_DIR_NAME]);
 }
 
 #[test]
-fn incremental_backup() {
+fn differential_set_holds_ownership_changes() {
+  use std::os::unix::fs::MetadataExt;
+
   let (src, dst, _temp_dir, config) = prepare_test_dir();
+  make_backup(&config).unwrap();
+
+  std::os::unix::fs::lchown(src.join("file1"), Some(1234), Some(5678)).unwrap();
+  make_backup(&config).unwrap();
+  let metadata = dst.join(differential::DIFF_DIR_NAME).join("file1").metadata().unwrap();
+  assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+  assert!(!dst.join(differential::DIFF_DIR_NAME).join("file2").exists());
+}
+
+#[test]
+fn differential_archives_are_based_on_full() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.format = OutputFormat::TarZst;
+  config.on.full_every = FullEveryConfig { runs: Some(3), days: None };
+
+  for i in 0..4 {
+    std::fs::write(src.join("file2"), format!("content2_{}", i)).unwrap();
+    make_backup(&config).unwrap();
+  }
+
+  let store = archive::Archives::open(&dst);
+  let archives = store.list().unwrap();
+  let bases =
+    archives.iter().map(|snapshot| store.read_index(&snapshot.name).unwrap().base).collect::<Vec<_>>();
+  let full = Some(archives[0].name.clone());
+  assert_eq!(bases, vec![None, full.clone(), full, None]);
+}
+
+#[test]
+fn incremental_backup() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Incremental;
 
   make_backup(&config).unwrap();
 
@@ -78,3 +146,469 @@ fn incremental_backup() {
   assert_eq!(std::fs::read_to_string(dst.join("file2")).unwrap(), "content2_modified");
   assert_eq!(std::fs::read_to_string(dst.join("dir1/file3")).unwrap(), "content3_modified");
 }
+
+#[test]
+fn repository_backup() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Repository;
+
+  make_backup(&config).unwrap();
+
+  let repo = repository::Repository::open(&dst).unwrap();
+  let count_chunks = || walk_files(&dst.join("chunks")).len();
+  assert_eq!(count_chunks(), 3);
+
+  std::fs::write(src.join("file2"), "content2_modified").unwrap();
+  std::fs::write(src.join("file4"), "content1").unwrap();
+
+  make_backup(&config).unwrap();
+
+  let snapshots = repo.snapshots().unwrap();
+  assert_eq!(snapshots.len(), 2);
+  assert_eq!(count_chunks(), 4);
+
+  let manifest = repo.read_snapshot(&snapshots[1].name).unwrap();
+  let file4 = manifest.entries.iter().find(|entry| entry.path == std::path::Path::new("file4")).unwrap();
+  assert_eq!(repo.read_chunk(&file4.chunks[0]).unwrap(), b"content1");
+  assert!(manifest.entries.iter().any(|entry| entry.path == std::path::Path::new("dir1/file3")));
+}
+
+#[test]
+fn repository_archives_fail_at_config_load() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let path = temp_dir.path().join("config.yaml");
+  let config = |on: &str, destination: &str| {
+    format!(
+      "tasks:\n  - name: test\n    src: /src\n    dst: /dst\n    on:\n      trigger:\n        type: schedule\n\
+       {}\n    destinations:\n      - dst: /dst2\n{}\n",
+      on, destination
+    )
+  };
+
+  for (on, destination) in [
+    ("      strategy: repository\n      format: tar-zst", "        copy: true"),
+    ("      strategy: incremental", "        strategy: repository\n        format: tar-gz"),
+    ("      strategy: differential\n      format: tar-zst", "        strategy: repository"),
+  ] {
+    std::fs::write(&path, config(on, destination)).unwrap();
+    let error = Config::from_file(path.clone(), None).unwrap_err().to_string();
+    assert!(error.contains("with the `repository` strategy"), "{}", error);
+  }
+  for (on, destination) in [
+    ("      strategy: repository", "        copy: true"),
+    (
+      "      strategy: incremental\n      format: tar-zst",
+      "        strategy: repository\n        format: directory",
+    ),
+  ] {
+    std::fs::write(&path, config(on, destination)).unwrap();
+    Config::from_file(path.clone(), None).unwrap();
+  }
+}
+
+fn walk_files(dir: &std::path::Path) -> Vec<PathBuf> {
+  let mut files = Vec::new();
+  for entry in std::fs::read_dir(dir).unwrap() {
+    let path = entry.unwrap().path();
+    if path.is_dir() {
+      files.extend(walk_files(&path));
+    } else {
+      files.push(path);
+    }
+  }
+  files
+}
+
+#[test]
+fn incremental_snapshot_backup() {
+  use std::os::unix::fs::MetadataExt;
+
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Incremental;
+  config.on.snapshots = true;
+
+  make_backup(&config).unwrap();
+  tick();
+
+  std::fs::write(src.join("file2"), "content2_modified").unwrap();
+  std::fs::remove_file(src.join("dir1/file3")).unwrap();
+
+  make_backup(&config).unwrap();
+
+  let snapshots = backups::snapshot::list_snapshots(&dst, None).unwrap();
+  assert_eq!(snapshots.len(), 2);
+  let (old, new) = (dst.join(&snapshots[0].name), dst.join(&snapshots[1].name));
+
+  assert_eq!(std::fs::read_to_string(old.join("file2")).unwrap(), "content2");
+  assert_eq!(std::fs::read_to_string(old.join("dir1/file3")).unwrap(), "content3");
+  assert_eq!(std::fs::read_to_string(new.join("file2")).unwrap(), "content2_modified");
+  assert!(!new.join("dir1/file3").exists());
+
+  let inode = |path: PathBuf| std::fs::metadata(path).unwrap().ino();
+  assert_eq!(inode(old.join("file1")), inode(new.join("file1")));
+  assert_ne!(inode(old.join("file2")), inode(new.join("file2")));
+}
+
+#[test]
+fn repository_retention() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Repository;
+  config.retention = Some(RetentionConfig { keep_last: Some(1), ..Default::default() });
+
+  make_backup(&config).unwrap();
+  std::fs::write(src.join("file2"), "content2_modified").unwrap();
+  make_backup(&config).unwrap();
+
+  let repo = repository::Repository::open(&dst).unwrap();
+  assert_eq!(repo.snapshots().unwrap().len(), 1);
+  assert_eq!(walk_files(&dst.join("chunks")).len(), 3);
+}
+
+#[test]
+fn incremental_archive_backup() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Incremental;
+  config.on.format = OutputFormat::TarZst;
+
+  make_backup(&config).unwrap();
+  std::fs::write(src.join("file2"), "content2_modified").unwrap();
+  std::fs::remove_file(src.join("dir1/file3")).unwrap();
+  make_backup(&config).unwrap();
+
+  let store = archive::Archives::open(&dst);
+  let archives = store.list().unwrap();
+  assert_eq!(archives.len(), 2);
+  let (full, incremental) =
+    (store.read_index(&archives[0].name).unwrap(), store.read_index(&archives[1].name).unwrap());
+  assert!(full.base.is_none());
+  assert_eq!(incremental.base.as_deref(), Some(archives[0].name.as_str()));
+  assert_eq!(incremental.deleted, vec![PathBuf::from("dir1/file3")]);
+
+  let members = |index: &archive::ArchiveIndex, name: &str| {
+    let mut archive = store.open_archive(name, index).unwrap();
+    let mut members = archive
+      .entries()
+      .unwrap()
+      .map(|entry| entry.unwrap())
+      .filter(|entry| entry.header().entry_type().is_file())
+      .map(|entry| entry.path().unwrap().into_owned())
+      .collect::<Vec<_>>();
+    members.sort();
+    members
+  };
+  assert_eq!(
+    members(&full, &archives[0].name),
+    vec![PathBuf::from("dir1/file3"), PathBuf::from("file1"), PathBuf::from("file2")]
+  );
+  assert_eq!(
+    members(&incremental, &archives[1].name),
+    vec![PathBuf::from(archive::DELETED_LIST_NAME), PathBuf::from("file2")]
+  );
+
+  // the full archive is the base of the kept one, so it survives pruning
+  config.retention = Some(RetentionConfig { keep_last: Some(1), ..Default::default() });
+  prune_snapshots(&config, config.retention.as_ref().unwrap()).unwrap();
+  assert_eq!(store.list().unwrap().len(), 2);
+}
+
+#[test]
+fn incremental_archive_chains_restart_per_full_every() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Incremental;
+  config.on.format = OutputFormat::TarZst;
+  config.on.full_every = FullEveryConfig { runs: Some(3), days: None };
+
+  for i in 0..5 {
+    std::fs::write(src.join("file2"), format!("content2_{}", i)).unwrap();
+    make_backup(&config).unwrap();
+  }
+
+  let store = archive::Archives::open(&dst);
+  let archives = store.list().unwrap();
+  let names = archives.iter().map(|snapshot| snapshot.name.clone()).collect::<Vec<_>>();
+  let bases = names.iter().map(|name| store.read_index(name).unwrap().base).collect::<Vec<_>>();
+  assert_eq!(bases, vec![None, Some(names[0].clone()), Some(names[1].clone()), None, Some(names[3].clone())]);
+
+  // only the chain of the kept archive survives
+  config.retention = Some(RetentionConfig { keep_last: Some(1), ..Default::default() });
+  prune_snapshots(&config, config.retention.as_ref().unwrap()).unwrap();
+  let kept = store.list().unwrap().into_iter().map(|snapshot| snapshot.name).collect::<Vec<_>>();
+  assert_eq!(kept, names[3..]);
+}
+
+#[test]
+fn excluded_paths_in_dst() {
+  for strategy in [BackupStrategyConfig::Incremental, BackupStrategyConfig::Differential] {
+    let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+    // excluded paths are dropped or kept when a new full backup replaces the old one
+    let dst = match strategy {
+      BackupStrategyConfig::Differential => dst.join(differential::FULL_DIR_NAME),
+      _ => dst,
+    };
+    config.on.strategy = strategy;
+    config.on.full_every = FullEveryConfig { runs: Some(1), days: None };
+    std::fs::create_dir_all(src.join("dir1/node_modules/pkg")).unwrap();
+    std::fs::write(src.join("dir1/node_modules/pkg/index.js"), "js").unwrap();
+    std::fs::write(src.join("scratch.tmp"), "tmp").unwrap();
+
+    make_backup(&config).unwrap();
+    assert!(dst.join("dir1/node_modules/pkg/index.js").exists());
+    assert!(dst.join("scratch.tmp").exists());
+
+    config.exclude = vec!["node_modules".to_string(), "*.tmp".to_string()];
+    std::fs::write(src.join("file1"), "content1_modified").unwrap();
+    make_backup(&config).unwrap();
+    assert_eq!(std::fs::read_to_string(dst.join("file1")).unwrap(), "content1_modified");
+    assert!(dst.join("dir1/node_modules/pkg/index.js").exists());
+    assert!(dst.join("scratch.tmp").exists());
+
+    config.excluded_in_dst = ExcludedPolicy::Delete;
+    make_backup(&config).unwrap();
+    assert!(!dst.join("dir1/node_modules").exists());
+    assert!(!dst.join("scratch.tmp").exists());
+    assert_eq!(std::fs::read_to_string(dst.join("dir1/file3")).unwrap(), "content3");
+  }
+}
+
+#[test]
+fn include_patterns() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Repository;
+  config.include = vec!["**/file3".to_string(), "file1".to_string()];
+  config.exclude = vec!["dir2".to_string()];
+  std::fs::create_dir_all(src.join("dir2")).unwrap();
+  std::fs::write(src.join("dir2/file1"), "excluded").unwrap();
+
+  make_backup(&config).unwrap();
+
+  let repo = repository::Repository::open(&dst).unwrap();
+  let manifest = repo.read_snapshot(&repo.snapshots().unwrap()[0].name).unwrap();
+  let paths = manifest.entries.iter().map(|entry| entry.path.clone()).collect::<Vec<_>>();
+  assert_eq!(paths, vec![PathBuf::from("dir1"), PathBuf::from("dir1/file3"), PathBuf::from("file1")]);
+}
+
+#[test]
+fn preserves_metadata_and_symlinks() {
+  use std::os::unix::fs::MetadataExt;
+  use std::os::unix::fs::PermissionsExt;
+
+  for (strategy, snapshots) in [
+    (BackupStrategyConfig::Differential, false),
+    (BackupStrategyConfig::Incremental, false),
+    (BackupStrategyConfig::Incremental, true),
+  ] {
+    let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+    config.on.strategy = strategy;
+    config.on.snapshots = snapshots;
+
+    let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    std::fs::set_permissions(src.join("file1"), std::fs::Permissions::from_mode(0o640)).unwrap();
+    xattr::set(src.join("file1"), "user.backups-test", b"value").unwrap();
+    std::os::unix::fs::lchown(src.join("file1"), Some(1234), Some(5678)).unwrap();
+    filetime::set_file_times(src.join("file1"), mtime, mtime).unwrap();
+    filetime::set_file_mtime(src.join("dir1"), mtime).unwrap();
+    std::os::unix::fs::symlink("file1", src.join("link")).unwrap();
+    std::os::unix::fs::symlink("missing", src.join("dangling")).unwrap();
+
+    make_backup(&config).unwrap();
+    let root = match (snapshots, &config.on.strategy) {
+      (true, _) => dst.join(&list_backup_snapshots(&config).unwrap().unwrap()[0].name),
+      (false, BackupStrategyConfig::Differential) => dst.join(differential::FULL_DIR_NAME),
+      (false, _) => dst.clone(),
+    };
+
+    let metadata = root.join("file1").metadata().unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+    assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+    assert_eq!(filetime::FileTime::from_last_modification_time(&metadata), mtime);
+    assert_eq!(xattr::get(root.join("file1"), "user.backups-test").unwrap().as_deref(), Some(&b"value"[..]));
+    assert_eq!(
+      filetime::FileTime::from_last_modification_time(&root.join("dir1").metadata().unwrap()),
+      mtime
+    );
+    assert!(root.join("link").is_symlink());
+    assert_eq!(std::fs::read_link(root.join("link")).unwrap(), PathBuf::from("file1"));
+    assert!(root.join("dangling").is_symlink());
+  }
+}
+
+#[test]
+fn follows_symlinks_unless_preserved() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Incremental;
+  config.preserve.symlinks = false;
+  std::os::unix::fs::symlink("file1", src.join("link")).unwrap();
+  std::os::unix::fs::symlink("missing", src.join("dangling")).unwrap();
+
+  make_backup(&config).unwrap();
+
+  assert!(!dst.join("link").is_symlink());
+  assert_eq!(std::fs::read_to_string(dst.join("link")).unwrap(), "content1");
+  assert!(!dst.join("dangling").exists());
+}
+
+#[test]
+fn incremental_mirror_propagates_deletions() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Incremental;
+  make_backup(&config).unwrap();
+
+  std::fs::remove_file(src.join("dir1/file3")).unwrap();
+  make_backup(&config).unwrap();
+  assert!(!dst.join("dir1/file3").exists());
+  assert!(dst.join("dir1").is_dir());
+
+  std::fs::remove_dir(src.join("dir1")).unwrap();
+  std::fs::write(src.join("dir1"), "now a file").unwrap();
+  make_backup(&config).unwrap();
+  assert_eq!(std::fs::read_to_string(dst.join("dir1")).unwrap(), "now a file");
+  assert_eq!(std::fs::read_to_string(dst.join("file1")).unwrap(), "content1");
+}
+
+#[test]
+fn incremental_mirror_refuses_mass_deletion() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Incremental;
+  make_backup(&config).unwrap();
+
+  // an emptied src, e.g. a mount point whose filesystem isn't mounted
+  for entry in std::fs::read_dir(&src).unwrap() {
+    let path = entry.unwrap().path();
+    match path.is_dir() {
+      true => std::fs::remove_dir_all(path).unwrap(),
+      false => std::fs::remove_file(path).unwrap(),
+    }
+  }
+  assert!(make_backup(&config).is_err());
+  assert_eq!(std::fs::read_to_string(dst.join("dir1/file3")).unwrap(), "content3");
+
+  make_backup_with_options(&config, &BackupOptions { force: true }).unwrap();
+  assert!(!dst.join("file1").exists());
+  assert!(!dst.join("dir1").exists());
+
+  config.max_delete = 100;
+  std::fs::write(src.join("file1"), "content1").unwrap();
+  make_backup(&config).unwrap();
+  std::fs::remove_file(src.join("file1")).unwrap();
+  make_backup(&config).unwrap();
+}
+
+#[test]
+fn incremental_mirror_compare_modes() {
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Incremental;
+  let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+  filetime::set_file_mtime(src.join("file1"), mtime).unwrap();
+  make_backup(&config).unwrap();
+
+  // same size and modification time, e.g. rewritten by a tool that keeps the times
+  std::fs::write(src.join("file1"), "CONTENT1").unwrap();
+  filetime::set_file_mtime(src.join("file1"), mtime).unwrap();
+  for compare in [CompareMode::Mtime, CompareMode::SizeMtime] {
+    config.compare = compare;
+    make_backup(&config).unwrap();
+    assert_eq!(std::fs::read_to_string(dst.join("file1")).unwrap(), "content1", "{}", compare);
+  }
+  config.compare = CompareMode::Checksum;
+  make_backup(&config).unwrap();
+  assert_eq!(std::fs::read_to_string(dst.join("file1")).unwrap(), "CONTENT1");
+
+  // an older version put back into src, e.g. restored from elsewhere
+  std::fs::write(src.join("file2"), "content2_restored").unwrap();
+  filetime::set_file_mtime(src.join("file2"), mtime).unwrap();
+  config.compare = CompareMode::Mtime;
+  make_backup(&config).unwrap();
+  assert_eq!(std::fs::read_to_string(dst.join("file2")).unwrap(), "content2");
+  config.compare = CompareMode::SizeMtime;
+  make_backup(&config).unwrap();
+  assert_eq!(std::fs::read_to_string(dst.join("file2")).unwrap(), "content2_restored");
+}
+
+#[test]
+fn checksum_compare_skips_unchanged_content() {
+  use std::os::unix::fs::MetadataExt;
+
+  let (src, dst, _temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Incremental;
+  config.compare = CompareMode::Checksum;
+  make_backup(&config).unwrap();
+
+  // a touched file only gets its times updated
+  let ino = dst.join("file1").metadata().unwrap().ino();
+  let mtime = filetime::FileTime::from_unix_time(2_000_000_000, 0);
+  filetime::set_file_mtime(src.join("file1"), mtime).unwrap();
+  make_backup(&config).unwrap();
+  let metadata = dst.join("file1").metadata().unwrap();
+  assert_eq!(metadata.ino(), ino);
+  assert_eq!(filetime::FileTime::from_last_modification_time(&metadata), mtime);
+
+  // the manifest's hash of a mirrored file is trusted while its size and modification time match
+  let old_mtime = filetime::FileTime::from_last_modification_time(&dst.join("file2").metadata().unwrap());
+  std::fs::write(dst.join("file2"), "CONTENT2").unwrap();
+  filetime::set_file_mtime(dst.join("file2"), old_mtime).unwrap();
+  make_backup(&config).unwrap();
+  assert_eq!(std::fs::read_to_string(dst.join("file2")).unwrap(), "CONTENT2");
+
+  filetime::set_file_mtime(dst.join("file2"), mtime).unwrap();
+  make_backup(&config).unwrap();
+  assert_eq!(std::fs::read_to_string(dst.join("file2")).unwrap(), "content2");
+}
+
+fn destination(dst: PathBuf) -> DestinationConfig {
+  DestinationConfig { dst, copy: false, strategy: None, snapshots: None, format: None, retention: None }
+}
+
+#[test]
+fn backup_to_further_destinations() {
+  let (src, dst, temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Repository;
+  let (mirror, copy) = (temp_dir.path().join("mirror"), temp_dir.path().join("copy"));
+  config.destinations = vec![
+    DestinationConfig { strategy: Some(BackupStrategyConfig::Incremental), ..destination(mirror.clone()) },
+    DestinationConfig {
+      copy: true,
+      retention: Some(RetentionConfig { keep_last: Some(2), ..Default::default() }),
+      ..destination(copy.clone())
+    },
+  ];
+
+  make_backup(&config).unwrap();
+  std::fs::write(src.join("file2"), "content2_modified").unwrap();
+  make_backup(&config).unwrap();
+  std::fs::write(src.join("file4"), "content4").unwrap();
+  make_backup(&config).unwrap();
+
+  assert_eq!(std::fs::read_to_string(mirror.join("file2")).unwrap(), "content2_modified");
+  assert_eq!(std::fs::read_to_string(mirror.join("file4")).unwrap(), "content4");
+
+  // the copy has the repository's snapshots and chunks, pruned by its own retention
+  let repo = repository::Repository::open(&dst).unwrap();
+  let copied = repository::Repository::open(&copy).unwrap();
+  let snapshots = repo.snapshots().unwrap();
+  assert_eq!(snapshots.len(), 3);
+  assert_eq!(copied.snapshots().unwrap(), snapshots[1..]);
+  assert_eq!(walk_files(&dst.join("chunks")).len(), 5);
+  // only the first snapshot had the old file2
+  assert_eq!(walk_files(&copy.join("chunks")).len(), 4);
+  let copy_config = config.for_destination(&config.destinations[1]);
+  assert!(backups::verify::verify(&copy_config, None).unwrap().is_intact());
+
+  // what the copy's retention pruned isn't sent again
+  assert_eq!(repository::copy_repository(&config, &copy_config).unwrap(), (0, 0));
+  make_backup(&config).unwrap();
+  assert_eq!(copied.snapshots().unwrap(), repo.snapshots().unwrap()[2..]);
+  assert_eq!(repository::copy_repository(&config, &copy_config).unwrap(), (0, 0));
+}
+
+#[test]
+fn failed_destination_doesnt_stop_the_others() {
+  let (_src, _dst, temp_dir, mut config) = prepare_test_dir();
+  config.on.strategy = BackupStrategyConfig::Incremental;
+  let (blocked, mirror) = (temp_dir.path().join("blocked"), temp_dir.path().join("mirror"));
+  std::fs::write(&blocked, "not a directory").unwrap();
+  config.destinations = vec![destination(blocked.join("mirror")), destination(mirror.clone())];
+
+  let error = make_backup(&config).unwrap_err().to_string();
+  assert!(error.contains("1 of 2"), "{}", error);
+  assert_eq!(std::fs::read_to_string(mirror.join("file1")).unwrap(), "content1");
+}
@@ -0,0 +1,106 @@
+use std::os::unix::fs::MetadataExt;
+
+use backups::backup::*;
+use backups::config::*;
+use backups::delta::*;
+
+type Change = Box<dyn Fn(&mut Vec<u8>)>;
+
+/// Content that doesn't repeat, so every block is unique
+fn random(len: usize, seed: &[u8]) -> Vec<u8> {
+  let mut data = vec![0; len];
+  blake3::Hasher::new().update(seed).finalize_xof().fill(&mut data);
+  data
+}
+
+#[test]
+fn only_changed_blocks_are_written() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let (src, dst) = (temp_dir.path().join("src"), temp_dir.path().join("dst"));
+  let preserve = PreserveConfig::default();
+  let old = random(3 * 1024 * 1024, b"old");
+  std::fs::write(&dst, &old).unwrap();
+  let ino = dst.metadata().unwrap().ino();
+
+  // whatever comes after an insertion or a removal moves, so only the other changes keep most blocks
+  let changes: Vec<(&str, bool, Change)> = vec![
+    ("unchanged", true, Box::new(|_| ())),
+    ("overwritten", true, Box::new(|data| data[1_500_000..1_500_100].fill(0))),
+    ("appended", true, Box::new(|data| data.extend(random(5000, b"appended")))),
+    ("truncated", true, Box::new(|data| data.truncate(2_500_000))),
+    ("inserted", false, Box::new(|data| drop(data.splice(1000..1000, random(333, b"inserted"))))),
+    ("removed", false, Box::new(|data| drop(data.drain(2_000_000..2_000_777)))),
+  ];
+  for (name, in_place, change) in changes.iter() {
+    let mut new = old.clone();
+    change(&mut new);
+    std::fs::write(&src, &new).unwrap();
+    std::fs::write(&dst, &old).unwrap();
+
+    let summary = update_file(&src, &dst, &preserve).unwrap().unwrap();
+    assert!(std::fs::read(&dst).unwrap() == new, "{}", name);
+    assert_eq!(summary.kept + summary.moved + summary.written, new.len() as u64, "{}", name);
+    if *in_place {
+      assert!(summary.written + summary.moved < 64 * 1024, "{}: {:?}", name, summary);
+    }
+    assert_eq!(dst.metadata().unwrap().ino(), ino, "{}", name);
+    assert_eq!(dst.metadata().unwrap().modified().unwrap(), src.metadata().unwrap().modified().unwrap());
+  }
+
+  // blocks moved towards the start are reused from their old place
+  let mut new = old.clone();
+  new.drain(..4096);
+  std::fs::write(&src, &new).unwrap();
+  std::fs::write(&dst, &old).unwrap();
+  let summary = update_file(&src, &dst, &preserve).unwrap().unwrap();
+  assert!(std::fs::read(&dst).unwrap() == new);
+  assert_eq!(summary.written, 0);
+}
+
+#[test]
+fn small_and_shared_files_are_copied_whole() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let (src, dst) = (temp_dir.path().join("src"), temp_dir.path().join("dst"));
+  let preserve = PreserveConfig::default();
+
+  std::fs::write(&src, "content1_modified").unwrap();
+  std::fs::write(&dst, "content1").unwrap();
+  assert_eq!(update_file(&src, &dst, &preserve).unwrap(), None);
+  assert_eq!(std::fs::read_to_string(&dst).unwrap(), "content1_modified");
+
+  // a hardlink into an older snapshot must keep its content
+  let old = random(2 * 1024 * 1024, b"old");
+  std::fs::write(&dst, &old).unwrap();
+  let snapshot = temp_dir.path().join("snapshot");
+  std::fs::hard_link(&dst, &snapshot).unwrap();
+  let mut new = old.clone();
+  new[0] ^= 1;
+  std::fs::write(&src, &new).unwrap();
+  assert_eq!(update_file(&src, &dst, &preserve).unwrap(), None);
+  assert!(std::fs::read(&dst).unwrap() == new);
+  assert!(std::fs::read(&snapshot).unwrap() == old);
+}
+
+#[test]
+fn incremental_mirror_updates_large_files_in_place() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let src = temp_dir.path().join("src");
+  std::fs::create_dir_all(&src).unwrap();
+  let mut image = random(4 * 1024 * 1024, b"image");
+  std::fs::write(src.join("disk.img"), &image).unwrap();
+
+  let config =
+    BackupTaskConfig::new("test", &src, &temp_dir.path().join("dst"), BackupStrategyConfig::Incremental);
+  make_backup(&config).unwrap();
+  let dst = config.dst.join("disk.img");
+  let ino = dst.metadata().unwrap().ino();
+
+  std::thread::sleep(std::time::Duration::from_millis(50));
+  image[3_000_000..3_000_016].copy_from_slice(b"a few new bytes!");
+  std::fs::write(src.join("disk.img"), &image).unwrap();
+  make_backup(&config).unwrap();
+
+  assert!(std::fs::read(&dst).unwrap() == image);
+  assert_eq!(dst.metadata().unwrap().ino(), ino);
+  assert!(backups::verify::verify(&config, None).unwrap().is_intact());
+}
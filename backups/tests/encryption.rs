@@ -0,0 +1,152 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use backups::backup::*;
+use backups::config::*;
+use backups::restore::*;
+use backups::verify::*;
+use tempfile::TempDir;
+
+fn prepare_test_dir(
+  strategy: BackupStrategyConfig,
+  format: OutputFormat,
+) -> (PathBuf, TempDir, BackupTaskConfig) {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let src = temp_dir.path().join("src");
+
+  std::fs::create_dir_all(src.join("dir1")).unwrap();
+  std::fs::write(src.join("file1"), "content1").unwrap();
+  std::fs::write(src.join("dir1/file2"), "content2").unwrap();
+  // spans several segments of the encrypted stream and doesn't compress away
+  let mut large = vec![0; 1024 * 1024 + 17];
+  blake3::Hasher::new().finalize_xof().fill(&mut large);
+  std::fs::write(src.join("large"), &large).unwrap();
+
+  let passphrase = temp_dir.path().join("passphrase");
+  std::fs::write(&passphrase, "correct horse battery staple\n").unwrap();
+
+  let mut config = BackupTaskConfig::new("test", &src, &temp_dir.path().join("dst"), strategy);
+  config.on.format = format;
+  config.encryption = Some(EncryptionConfig::PassphraseFile(passphrase));
+
+  (src, temp_dir, config)
+}
+
+/// Fails if any file under `dir` contains `needle` in plain text
+fn assert_not_in_plaintext(dir: &Path, needle: &[u8]) {
+  for entry in std::fs::read_dir(dir).unwrap() {
+    let path = entry.unwrap().path();
+    if path.is_dir() {
+      assert_not_in_plaintext(&path, needle);
+    } else {
+      let data = std::fs::read(&path).unwrap();
+      assert!(
+        !data.windows(needle.len()).any(|window| window == needle),
+        "{} leaks plaintext",
+        path.display()
+      );
+    }
+  }
+}
+
+#[test]
+fn encrypted_backups_restore_and_verify() {
+  for (strategy, format) in [
+    (BackupStrategyConfig::Repository, OutputFormat::Directory),
+    (BackupStrategyConfig::Incremental, OutputFormat::TarZst),
+  ] {
+    let (src, temp_dir, config) = prepare_test_dir(strategy, format);
+    make_backup(&config).unwrap();
+    std::fs::write(src.join("file1"), "content1_modified").unwrap();
+    make_backup(&config).unwrap();
+
+    assert_not_in_plaintext(&config.dst, b"content1");
+    assert_not_in_plaintext(&config.dst, b"content2");
+    assert_not_in_plaintext(&config.dst, b"file2");
+
+    let report = verify(&config, None).unwrap();
+    assert!(report.is_intact());
+
+    let target = temp_dir.path().join("restored");
+    restore(&config, &RestoreOptions { target: Some(target.clone()), ..Default::default() }).unwrap();
+    assert_eq!(std::fs::read_to_string(target.join("file1")).unwrap(), "content1_modified");
+    assert_eq!(std::fs::read_to_string(target.join("dir1/file2")).unwrap(), "content2");
+    assert_eq!(std::fs::read(target.join("large")).unwrap(), std::fs::read(src.join("large")).unwrap());
+  }
+}
+
+#[test]
+fn encryption_rejects_wrong_passphrase_and_missing_config() {
+  let (_src, temp_dir, mut config) =
+    prepare_test_dir(BackupStrategyConfig::Repository, OutputFormat::Directory);
+  make_backup(&config).unwrap();
+
+  let wrong = temp_dir.path().join("wrong");
+  std::fs::write(&wrong, "wrong passphrase").unwrap();
+  config.encryption = Some(EncryptionConfig::PassphraseFile(wrong));
+  assert!(make_backup(&config).is_err());
+  assert!(verify(&config, None).is_err());
+
+  config.encryption = None;
+  assert!(make_backup(&config).is_err());
+}
+
+#[test]
+fn encryption_requires_repository_or_archive() {
+  let (_src, _temp_dir, config) =
+    prepare_test_dir(BackupStrategyConfig::Incremental, OutputFormat::Directory);
+  assert!(make_backup(&config).is_err());
+}
+
+#[test]
+fn tampered_encrypted_chunk_is_corrupted() {
+  let (_src, _temp_dir, config) = prepare_test_dir(BackupStrategyConfig::Repository, OutputFormat::Directory);
+  make_backup(&config).unwrap();
+
+  let chunks = config.dst.join("chunks");
+  let chunk = std::fs::read_dir(std::fs::read_dir(&chunks).unwrap().next().unwrap().unwrap().path())
+    .unwrap()
+    .next()
+    .unwrap()
+    .unwrap()
+    .path();
+  let mut data = std::fs::read(&chunk).unwrap();
+  let last = data.len() - 1;
+  data[last] ^= 1;
+  std::fs::write(&chunk, data).unwrap();
+
+  let report = verify(&config, None).unwrap();
+  assert_eq!(report.corrupted.len(), 1);
+}
+
+#[test]
+fn encrypted_repository_is_copied_as_stored() {
+  let (_src, temp_dir, mut config) =
+    prepare_test_dir(BackupStrategyConfig::Repository, OutputFormat::Directory);
+  let copy = temp_dir.path().join("copy");
+  config.destinations = vec![DestinationConfig {
+    dst: copy.clone(),
+    copy: true,
+    strategy: None,
+    snapshots: None,
+    format: None,
+    retention: None,
+  }];
+  make_backup(&config).unwrap();
+
+  assert_not_in_plaintext(&copy, b"content1");
+  let copy_config = config.for_destination(&config.destinations[0]);
+  assert!(verify(&copy_config, None).unwrap().is_intact());
+
+  let target = temp_dir.path().join("restored");
+  restore(&copy_config, &RestoreOptions { target: Some(target.clone()), ..Default::default() }).unwrap();
+  assert_eq!(std::fs::read_to_string(target.join("dir1/file2")).unwrap(), "content2");
+
+  // a repository encrypted with another key isn't mixed in
+  let other = temp_dir.path().join("other");
+  config.dst = other;
+  config.destinations.clear();
+  make_backup(&config).unwrap();
+  let error = backups::backup::repository::copy_repository(&config, &copy_config).unwrap_err();
+  assert!(error.to_string().contains("encrypted differently"), "{}", error);
+}
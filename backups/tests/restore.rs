@@ -0,0 +1,220 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use backups::backup::*;
+use backups::config::*;
+use backups::restore::*;
+use tempfile::TempDir;
+
+fn prepare_test_dir(strategy: BackupStrategyConfig) -> (PathBuf, TempDir, BackupTaskConfig) {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let src = temp_dir.path().join("src");
+
+  std::fs::create_dir_all(src.join("dir1")).unwrap();
+  std::fs::write(src.join("file1"), "content1").unwrap();
+  std::fs::write(src.join("dir1/file2"), "content2").unwrap();
+
+  let config = BackupTaskConfig::new("test", &src, &temp_dir.path().join("dst"), strategy);
+
+  (src, temp_dir, config)
+}
+
+fn tick() {
+  std::thread::sleep(Duration::from_millis(50));
+}
+
+#[test]
+fn restore_repository_snapshot_into_target() {
+  let (src, temp_dir, config) = prepare_test_dir(BackupStrategyConfig::Repository);
+
+  make_backup(&config).unwrap();
+  let first = repository::Repository::open(&config.dst).unwrap().snapshots().unwrap()[0].name.clone();
+  std::fs::write(src.join("file1"), "content1_modified").unwrap();
+  std::fs::write(src.join("file3"), "content3").unwrap();
+  make_backup(&config).unwrap();
+
+  let target = temp_dir.path().join("restored");
+  let options = RestoreOptions { snapshot: Some(first), target: Some(target.clone()), ..Default::default() };
+  let report = restore(&config, &options).unwrap();
+
+  assert_eq!(report.restored, 2);
+  assert_eq!(std::fs::read_to_string(target.join("file1")).unwrap(), "content1");
+  assert_eq!(std::fs::read_to_string(target.join("dir1/file2")).unwrap(), "content2");
+  assert!(!target.join("file3").exists());
+
+  let options =
+    RestoreOptions { target: Some(target.clone()), path: Some("file3".into()), ..Default::default() };
+  restore(&config, &options).unwrap();
+  assert_eq!(std::fs::read_to_string(target.join("file3")).unwrap(), "content3");
+  assert_eq!(std::fs::read_to_string(target.join("file1")).unwrap(), "content1");
+}
+
+#[test]
+fn restore_mirror_in_place() {
+  let (src, _temp_dir, config) = prepare_test_dir(BackupStrategyConfig::Incremental);
+
+  make_backup(&config).unwrap();
+  tick();
+  std::fs::write(src.join("extra"), "extra").unwrap();
+  std::fs::remove_file(src.join("dir1/file2")).unwrap();
+
+  let dry_run = RestoreOptions { dry_run: true, delete: true, ..Default::default() };
+  let report = restore(&config, &dry_run).unwrap();
+  // the backup keeps mtimes, so the untouched file1 needs no restore
+  assert_eq!((report.restored, report.unchanged, report.removed), (1, 1, 1));
+  assert_eq!(report.extra, vec![PathBuf::from("extra")]);
+  assert!(src.join("extra").exists());
+
+  // extra files are only listed unless asked to delete them
+  let report = restore(&config, &RestoreOptions::default()).unwrap();
+  assert_eq!((report.extra.len(), report.removed), (1, 0));
+  assert!(src.join("extra").exists());
+  assert_eq!(std::fs::read_to_string(src.join("dir1/file2")).unwrap(), "content2");
+
+  restore(&config, &RestoreOptions { delete: true, ..Default::default() }).unwrap();
+  assert!(!src.join("extra").exists());
+  assert_eq!(std::fs::read_to_string(src.join("dir1/file2")).unwrap(), "content2");
+}
+
+#[test]
+fn restore_refuses_to_overwrite_newer_files() {
+  let (src, _temp_dir, config) = prepare_test_dir(BackupStrategyConfig::Incremental);
+
+  make_backup(&config).unwrap();
+  tick();
+  std::fs::write(src.join("file1"), "content1_modified").unwrap();
+
+  assert!(restore(&config, &RestoreOptions::default()).is_err());
+  assert_eq!(std::fs::read_to_string(src.join("file1")).unwrap(), "content1_modified");
+
+  restore(&config, &RestoreOptions { force: true, ..Default::default() }).unwrap();
+  assert_eq!(std::fs::read_to_string(src.join("file1")).unwrap(), "content1");
+}
+
+#[test]
+fn restore_from_archives() {
+  for (strategy, format) in [
+    (BackupStrategyConfig::Incremental, OutputFormat::TarZst),
+    (BackupStrategyConfig::Differential, OutputFormat::TarGz),
+  ] {
+    let (src, temp_dir, mut config) = prepare_test_dir(strategy);
+    config.on.format = format;
+
+    make_backup(&config).unwrap();
+    let first = list_backup_snapshots(&config).unwrap().unwrap()[0].name.clone();
+    tick();
+    std::fs::write(src.join("file1"), "content1_modified").unwrap();
+    std::fs::remove_file(src.join("dir1/file2")).unwrap();
+    std::fs::write(src.join("file3"), "content3").unwrap();
+    make_backup(&config).unwrap();
+
+    let target = temp_dir.path().join("restored");
+    let dry_run = RestoreOptions { target: Some(target.clone()), dry_run: true, ..Default::default() };
+    assert_eq!(restore(&config, &dry_run).unwrap().restored, 2);
+    assert!(!target.exists());
+
+    let options =
+      RestoreOptions { target: Some(target.clone()), path: Some("file3".into()), ..Default::default() };
+    assert_eq!(restore(&config, &options).unwrap().restored, 1);
+    assert!(!target.join("file1").exists());
+
+    let report =
+      restore(&config, &RestoreOptions { target: Some(target.clone()), ..Default::default() }).unwrap();
+    assert_eq!(report.restored, 1);
+    assert_eq!(std::fs::read_to_string(target.join("file1")).unwrap(), "content1_modified");
+    assert_eq!(std::fs::read_to_string(target.join("file3")).unwrap(), "content3");
+    assert!(target.join("dir1").is_dir());
+    assert!(!target.join("dir1/file2").exists());
+
+    // the restored file1 is newer than its version in the first archive
+    let options =
+      RestoreOptions { snapshot: Some(first), target: Some(target.clone()), ..Default::default() };
+    assert!(restore(&config, &options).is_err());
+    restore(&config, &RestoreOptions { force: true, delete: true, ..options }).unwrap();
+    assert_eq!(std::fs::read_to_string(target.join("file1")).unwrap(), "content1");
+    assert_eq!(std::fs::read_to_string(target.join("dir1/file2")).unwrap(), "content2");
+    assert!(!target.join("file3").exists());
+
+    // archives are extracted next to the target and cleaned up
+    let leftovers = std::fs::read_dir(temp_dir.path())
+      .unwrap()
+      .filter(|child| child.as_ref().unwrap().file_name().to_string_lossy().starts_with(".backups-restore-"))
+      .count();
+    assert_eq!(leftovers, 0);
+  }
+}
+
+#[test]
+fn restore_keeps_excluded_files() {
+  let (src, _temp_dir, mut config) = prepare_test_dir(BackupStrategyConfig::Repository);
+  config.exclude = vec!["*.cache".to_string()];
+  std::fs::write(src.join("dir1/build.cache"), "cache").unwrap();
+
+  make_backup(&config).unwrap();
+  std::fs::write(src.join("extra"), "extra").unwrap();
+  let report = restore(&config, &RestoreOptions { delete: true, ..Default::default() }).unwrap();
+
+  assert_eq!(report.removed, 1);
+  assert!(!src.join("extra").exists());
+  assert_eq!(std::fs::read_to_string(src.join("dir1/build.cache")).unwrap(), "cache");
+}
+
+#[test]
+fn restore_honours_preserve() {
+  use std::os::unix::fs::PermissionsExt;
+
+  let (src, temp_dir, mut config) = prepare_test_dir(BackupStrategyConfig::Repository);
+  config.preserve.mode = false;
+  config.preserve.times = false;
+  std::fs::set_permissions(src.join("file1"), std::fs::Permissions::from_mode(0o600)).unwrap();
+  filetime::set_file_mtime(src.join("file1"), filetime::FileTime::from_unix_time(1_000_000, 0)).unwrap();
+
+  make_backup(&config).unwrap();
+  let target = temp_dir.path().join("restored");
+  restore(&config, &RestoreOptions { target: Some(target.clone()), ..Default::default() }).unwrap();
+
+  let metadata = target.join("file1").metadata().unwrap();
+  assert_ne!(metadata.permissions().mode() & 0o777, 0o600);
+  assert_ne!(metadata.modified().unwrap(), src.join("file1").metadata().unwrap().modified().unwrap());
+
+  // without the times restored nothing counts as unchanged
+  let options = RestoreOptions { target: Some(target.clone()), force: true, ..Default::default() };
+  assert_eq!(restore(&config, &options).unwrap().unchanged, 0);
+}
+
+#[test]
+fn restore_symlinks_and_ownership() {
+  use std::os::unix::fs::MetadataExt;
+
+  for (strategy, format) in [
+    (BackupStrategyConfig::Repository, OutputFormat::Directory),
+    (BackupStrategyConfig::Incremental, OutputFormat::TarZst),
+    (BackupStrategyConfig::Incremental, OutputFormat::Directory),
+  ] {
+    let (src, temp_dir, mut config) = prepare_test_dir(strategy);
+    config.on.format = format;
+    config.on.snapshots =
+      matches!((&config.on.strategy, format), (BackupStrategyConfig::Incremental, OutputFormat::Directory));
+    std::os::unix::fs::symlink("dir1/file2", src.join("link")).unwrap();
+    std::os::unix::fs::lchown(src.join("dir1/file2"), Some(1234), Some(5678)).unwrap();
+
+    make_backup(&config).unwrap();
+    tick();
+    make_backup(&config).unwrap();
+
+    let target = temp_dir.path().join("restored");
+    restore(&config, &RestoreOptions { target: Some(target.clone()), ..Default::default() }).unwrap();
+    assert_eq!(std::fs::read_link(target.join("link")).unwrap(), PathBuf::from("dir1/file2"));
+    let metadata = target.join("dir1/file2").metadata().unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+
+    // a file in place of the symlink is replaced by the link again
+    std::fs::remove_file(target.join("link")).unwrap();
+    std::fs::write(target.join("link"), "not a link").unwrap();
+    filetime::set_file_mtime(target.join("link"), filetime::FileTime::from_unix_time(0, 0)).unwrap();
+    let report =
+      restore(&config, &RestoreOptions { target: Some(target.clone()), ..Default::default() }).unwrap();
+    assert_eq!(report.restored, 1);
+    assert!(target.join("link").is_symlink());
+  }
+}
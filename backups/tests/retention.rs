@@ -0,0 +1,91 @@
+use backups::config::Config;
+use backups::config::RetentionConfig;
+use backups::retention::*;
+use backups::snapshot::*;
+
+fn snapshots(names: &[&str]) -> Vec<Snapshot> {
+  names.iter().map(|name| parse_snapshot_name(name).unwrap()).collect()
+}
+
+fn kept(decisions: &[RetentionDecision]) -> Vec<&str> {
+  decisions.iter().filter(|decision| decision.keep).map(|decision| decision.snapshot.name.as_str()).collect()
+}
+
+#[test]
+fn keep_last() {
+  let snapshots = snapshots(&["2026-10-01T10:00:00", "2026-10-02T10:00:00", "2026-10-03T10:00:00"]);
+  let policy = RetentionConfig { keep_last: Some(2), ..Default::default() };
+
+  let decisions = apply_policy(&snapshots, &policy);
+
+  assert_eq!(kept(&decisions), ["2026-10-02T10:00:00", "2026-10-03T10:00:00"]);
+  assert!(decisions[0].reasons[0].contains("limit of 2 reached"));
+}
+
+#[test]
+fn keep_daily_and_monthly() {
+  let snapshots = snapshots(&[
+    "2026-08-15T10:00:00",
+    "2026-09-30T10:00:00",
+    "2026-10-16T09:00:00",
+    "2026-10-16T21:00:00",
+    "2026-10-17T08:00:00",
+    "2026-10-17T10:00:00",
+  ]);
+  let policy = RetentionConfig { keep_daily: Some(2), keep_monthly: Some(3), ..Default::default() };
+
+  let decisions = apply_policy(&snapshots, &policy);
+
+  assert_eq!(
+    kept(&decisions),
+    ["2026-08-15T10:00:00", "2026-09-30T10:00:00", "2026-10-16T21:00:00", "2026-10-17T10:00:00"]
+  );
+  let pruned = decisions.iter().find(|decision| decision.snapshot.name == "2026-10-17T08:00:00").unwrap();
+  assert!(pruned.reasons.iter().any(|reason| reason.contains("already covered by 2026-10-17T10:00:00")));
+}
+
+#[test]
+fn keeps_everything_without_rules() {
+  let snapshots = snapshots(&["2026-10-01T10:00:00", "2026-10-02T10:00:00"]);
+
+  let decisions = apply_policy(&snapshots, &RetentionConfig::default());
+
+  assert!(decisions.iter().all(|decision| decision.keep));
+}
+
+#[test]
+fn always_keeps_latest() {
+  let snapshots = snapshots(&["2026-10-01T10:00:00", "2026-10-02T10:00:00"]);
+  let policy = RetentionConfig { keep_last: Some(0), keep_weekly: Some(0), ..Default::default() };
+
+  let decisions = apply_policy(&snapshots, &policy);
+
+  assert!(decisions.iter().all(|decision| decision.keep));
+}
+
+#[test]
+fn retention_without_snapshots_fails_at_config_load() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let path = temp_dir.path().join("config.yaml");
+  let config = |on: &str| {
+    format!(
+      "tasks:\n  - name: test\n    src: /src\n    dst: /dst\n    on:\n      trigger:\n        type: schedule\n\
+       {}\n    retention:\n      keep-last: 3\n",
+      on
+    )
+  };
+
+  for on in ["      strategy: incremental", "      strategy: differential"] {
+    std::fs::write(&path, config(on)).unwrap();
+    let error = Config::from_file(path.clone(), None).unwrap_err().to_string();
+    assert!(error.contains("keeps no snapshots"), "{}", error);
+  }
+  for on in [
+    "      strategy: incremental\n      snapshots: true",
+    "      strategy: differential\n      format: tar-zst",
+    "      strategy: repository",
+  ] {
+    std::fs::write(&path, config(on)).unwrap();
+    Config::from_file(path.clone(), None).unwrap();
+  }
+}
@@ -0,0 +1,428 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use backups::config::*;
+use backups::scheduler::*;
+use backups::state::*;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+
+#[test]
+fn parse_durations() {
+  assert_eq!(parse_duration("10 seconds").unwrap(), Duration::from_secs(10));
+  assert_eq!(parse_duration("1 minute").unwrap(), Duration::from_secs(60));
+  assert_eq!(parse_duration("2 hours").unwrap(), Duration::from_secs(2 * 60 * 60));
+  assert_eq!(parse_duration("250 milliseconds").unwrap(), Duration::from_millis(250));
+  assert!(parse_duration("10").is_err());
+  assert!(parse_duration("ten seconds").is_err());
+  assert!(parse_duration("10 fortnights").is_err());
+}
+
+#[tokio::test]
+async fn changes_settle_after_quiet_period_or_max_delay() {
+  let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+  // a single change settles after the quiet period
+  tx.send(()).unwrap();
+  let start = Instant::now();
+  assert!(wait_for_changes(&mut rx, Duration::from_millis(100), Duration::from_secs(10)).await);
+  assert!(start.elapsed() >= Duration::from_millis(100));
+  assert!(start.elapsed() < Duration::from_secs(1));
+
+  // a steady stream of changes is cut off by the maximum delay
+  let sender = tx.clone();
+  let stream = tokio::spawn(async move {
+    for _ in 0..40 {
+      if sender.send(()).is_err() {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+  });
+  let start = Instant::now();
+  assert!(wait_for_changes(&mut rx, Duration::from_millis(100), Duration::from_millis(300)).await);
+  assert!(start.elapsed() >= Duration::from_millis(300));
+  assert!(start.elapsed() < Duration::from_millis(600));
+  stream.await.unwrap();
+
+  drop(tx);
+  while rx.try_recv().is_ok() {}
+  assert!(!wait_for_changes(&mut rx, Duration::from_millis(100), Duration::from_secs(1)).await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn change_trigger_runs_backup() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let src = temp_dir.path().join("src");
+  let dst = temp_dir.path().join("dst");
+  std::fs::create_dir_all(&src).unwrap();
+
+  let mut config = BackupTaskConfig::new("test", &src, &dst, BackupStrategyConfig::Incremental);
+  config.on.trigger =
+    BackupTrigger::Change { quiet: "100 milliseconds".to_string(), max_delay: "1 second".to_string() };
+  config.exclude = vec!["*.tmp".to_string()];
+  let store = Arc::new(StateStore::open(&temp_dir.path().join("state.json")).unwrap());
+  spawn_backup_task(config, Arc::new(Semaphore::new(1)), store).await.unwrap();
+
+  std::fs::write(src.join("ignored.tmp"), "tmp").unwrap();
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  assert!(!dst.exists());
+
+  std::fs::write(src.join("file1"), "content1").unwrap();
+  let start = Instant::now();
+  while !dst.join("file1").exists() && start.elapsed() < Duration::from_secs(5) {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+  assert_eq!(std::fs::read_to_string(dst.join("file1")).unwrap(), "content1");
+}
+
+#[test]
+fn parse_cron_expressions() {
+  use chrono::TimeZone;
+
+  let weekdays = parse_cron("30 2 * * 1-5").unwrap();
+  let saturday = chrono::Utc.with_ymd_and_hms(2026, 10, 17, 12, 0, 0).unwrap();
+  let next = weekdays.find_next_occurrence(&saturday, false).unwrap();
+  assert_eq!(next, chrono::Utc.with_ymd_and_hms(2026, 10, 19, 2, 30, 0).unwrap());
+
+  let first_sunday = parse_cron("0 3 * * SUN#1").unwrap();
+  let next = first_sunday.find_next_occurrence(&saturday, false).unwrap();
+  assert_eq!(next, chrono::Utc.with_ymd_and_hms(2026, 11, 1, 3, 0, 0).unwrap());
+
+  assert!(parse_cron("15 30 2 * * *").is_ok());
+  assert!(parse_cron("61 * * * *").is_err());
+  assert!(parse_cron("every day").is_err());
+}
+
+#[test]
+fn invalid_cron_fails_at_config_load() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let write_config = |trigger: &str| {
+    let path = temp_dir.path().join("config.yaml");
+    let config = format!(
+      "tasks:\n  - name: test\n    src: /src\n    dst: /dst\n    on:\n      trigger:\n{}\n      strategy: incremental\n",
+      trigger
+    );
+    std::fs::write(&path, config).unwrap();
+    path
+  };
+
+  let valid = write_config("        type: schedule\n        cron: \"30 2 * * 1-5\"");
+  Config::from_file(valid, None).unwrap();
+
+  let invalid = write_config("        type: schedule\n        cron: \"30 25 * * *\"");
+  let error = Config::from_file(invalid, None).unwrap_err().to_string();
+  assert!(error.contains("invalid cron expression `30 25 * * *`"), "{}", error);
+
+  let conflicting =
+    write_config("        type: schedule\n        cron: \"30 2 * * *\"\n        at: \"10:00\"");
+  assert!(Config::from_file(conflicting, None).is_err());
+
+  // even when `every` is set to what it defaults to
+  let conflicting =
+    write_config("        type: schedule\n        cron: \"30 2 * * *\"\n        every: [\"1 day\"]");
+  let error = Config::from_file(conflicting, None).unwrap_err().to_string();
+  assert!(error.contains("`cron` replaces `every` and `at`"), "{}", error);
+}
+
+#[test]
+fn quiet_period_longer_than_max_delay_fails_at_config_load() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let path = temp_dir.path().join("config.yaml");
+  std::fs::write(
+    &path,
+    "tasks:\n  - name: test\n    src: /src\n    dst: /dst\n    on:\n      trigger:\n        type: change\n        \
+     quiet: 10 minutes\n        max-delay: 1 minute\n      strategy: incremental\n",
+  )
+  .unwrap();
+  let error = Config::from_file(path, None).unwrap_err().to_string();
+  assert!(error.contains("is longer than the maximum delay"), "{}", error);
+}
+
+#[test]
+fn run_task_reports_failures() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let src = temp_dir.path().join("src");
+  let dst = temp_dir.path().join("dst");
+
+  let config = BackupTaskConfig::new("test", &src, &dst, BackupStrategyConfig::Incremental);
+  assert!(run_task(&config, &Default::default()).is_err());
+
+  std::fs::create_dir_all(&src).unwrap();
+  std::fs::write(src.join("file1"), "content1").unwrap();
+  run_task(&config, &Default::default()).unwrap();
+  assert_eq!(std::fs::read_to_string(dst.join("file1")).unwrap(), "content1");
+
+  std::fs::remove_file(src.join("file1")).unwrap();
+  assert!(run_task(&config, &Default::default()).is_err());
+  run_task(&config, &backups::backup::BackupOptions { force: true }).unwrap();
+  assert!(!dst.join("file1").exists());
+}
+
+#[test]
+fn tasks_are_selected_by_unique_name() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let path = temp_dir.path().join("config.yaml");
+  let task = |name: &str, dst: &str| {
+    format!(
+      "  - name: {}\n    src: /src\n    dst: {}\n    on:\n      trigger:\n        type: schedule\n      strategy: incremental\n",
+      name, dst
+    )
+  };
+
+  std::fs::write(&path, format!("tasks:\n{}{}", task("home", "/dst1"), task("home-offsite", "/dst2")))
+    .unwrap();
+  let config = Config::from_file(path.clone(), None).unwrap();
+  assert_eq!(config.find_task(Some("home-offsite")).unwrap().dst, std::path::Path::new("/dst2"));
+  assert_eq!(config.find_task(Some("/dst1")).unwrap().name, "home");
+  // both tasks back up /src
+  assert!(config.find_task(Some("/src")).is_err());
+  assert!(config.find_task(None).is_err());
+
+  std::fs::write(&path, format!("tasks:\n{}{}", task("home", "/dst1"), task("home", "/dst2"))).unwrap();
+  let error = Config::from_file(path, None).unwrap_err().to_string();
+  assert!(error.contains("`home` is used more than once"), "{}", error);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn overlapping_runs_are_skipped_or_queued() {
+  for (overlap, expected) in [(OverlapPolicy::Skip, 1), (OverlapPolicy::Queue, 2)] {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let src = temp_dir.path().join("src");
+    let dst = temp_dir.path().join("dst");
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::write(src.join("file1"), "content1").unwrap();
+
+    let mut config = BackupTaskConfig::new("test", &src, &dst, BackupStrategyConfig::Incremental);
+    config.on.snapshots = true;
+    config.overlap = overlap;
+    let store = Arc::new(StateStore::open(&temp_dir.path().join("state.json")).unwrap());
+    let runner = TaskRunner::new(config, Arc::new(Semaphore::new(1)), store).unwrap();
+
+    // the first run is still in progress when the others are triggered
+    for _ in 0..3 {
+      runner.trigger();
+    }
+    let start = Instant::now();
+    while runner.is_running() && start.elapsed() < Duration::from_secs(10) {
+      tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(!runner.is_running());
+
+    let snapshots = backups::snapshot::list_snapshots(&dst, None).unwrap();
+    assert_eq!(snapshots.len(), expected, "{:?}", overlap);
+  }
+}
+
+#[test]
+fn missed_runs_are_detected() {
+  use chrono::Local;
+  use chrono::TimeZone;
+
+  let time = |day, hour, min| Local.with_ymd_and_hms(2026, 10, day, hour, min, 0).unwrap();
+  let schedule = |every: &str, at: Option<&str>| BackupTrigger::Schedule {
+    every: Some(vec![every.to_string()]),
+    at: at.map(str::to_string),
+    cron: None,
+  };
+
+  // daily at 02:00, last run on the 15th
+  let daily = schedule("day", Some("02:00"));
+  assert_eq!(missed_run(&daily, time(15, 2, 0), time(16, 10, 0)).unwrap(), Some(time(16, 2, 0)));
+  assert_eq!(missed_run(&daily, time(15, 2, 0), time(16, 1, 0)).unwrap(), None);
+
+  // fridays, last run on friday the 9th, down over the next friday
+  let weekly = schedule("friday", None);
+  assert_eq!(missed_run(&weekly, time(9, 0, 1), time(19, 9, 0)).unwrap(), Some(time(16, 0, 0)));
+  assert_eq!(missed_run(&weekly, time(9, 0, 1), time(15, 9, 0)).unwrap(), None);
+
+  let cron = BackupTrigger::Schedule { every: Some(vec![]), at: None, cron: Some("30 2 * * *".to_string()) };
+  assert_eq!(missed_run(&cron, time(15, 2, 30), time(16, 3, 0)).unwrap(), Some(time(16, 2, 30)));
+  assert_eq!(missed_run(&cron, time(15, 2, 30), time(16, 2, 0)).unwrap(), None);
+
+  let change = BackupTrigger::Change { quiet: "1 second".to_string(), max_delay: "1 minute".to_string() };
+  assert_eq!(missed_run(&change, time(1, 0, 0), time(16, 0, 0)).unwrap(), None);
+}
+
+#[test]
+fn first_run_counts_from_the_last_one() {
+  use chrono::Local;
+  use chrono::TimeZone;
+
+  let time = |day, hour, min| Local.with_ymd_and_hms(2026, 10, day, hour, min, 0).unwrap();
+  let schedule =
+    |every: &str| BackupTrigger::Schedule { every: Some(vec![every.to_string()]), at: None, cron: None };
+
+  // fridays, last run on friday the 9th, restarted on thursday: due tomorrow, not a week later
+  let weekly = schedule("friday");
+  assert_eq!(first_run(&weekly, Some(time(9, 0, 1)), time(15, 9, 0)).unwrap(), Some(time(16, 0, 0)));
+  // never ran, or the run was missed: the next one from now
+  assert_eq!(first_run(&weekly, None, time(15, 9, 0)).unwrap(), Some(time(16, 0, 0)));
+  assert_eq!(first_run(&weekly, Some(time(2, 0, 1)), time(16, 9, 0)).unwrap(), Some(time(23, 0, 0)));
+
+  // every 7 days, last run 6 days ago: due in a day, not 7 days from the restart
+  let last = time(9, 12, 0);
+  assert_eq!(first_run(&schedule("7 days"), Some(last), time(15, 12, 0)).unwrap(), Some(time(16, 12, 0)));
+  assert_eq!(first_run(&schedule("7 days"), None, time(15, 12, 0)).unwrap(), Some(time(22, 12, 0)));
+
+  let change = BackupTrigger::Change { quiet: "1 second".to_string(), max_delay: "1 minute".to_string() };
+  assert_eq!(first_run(&change, Some(last), time(15, 12, 0)).unwrap(), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn missed_run_is_made_on_start() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let src = temp_dir.path().join("src");
+  let dst = temp_dir.path().join("dst");
+  let state_file = temp_dir.path().join("state.json");
+  std::fs::create_dir_all(&src).unwrap();
+  std::fs::write(src.join("file1"), "content1").unwrap();
+
+  let mut config = BackupTaskConfig::new("test", &src, &dst, BackupStrategyConfig::Incremental);
+  config.on.trigger = BackupTrigger::Schedule { every: None, at: Some("02:00".to_string()), cron: None };
+
+  // the last run was two days ago, so at least one daily run was missed
+  let last_start = chrono::Local::now() - chrono::Duration::days(2);
+  std::fs::write(
+    &state_file,
+    format!(r#"{{"tasks": {{"test": {{"last-start": "{}"}}}}}}"#, last_start.to_rfc3339()),
+  )
+  .unwrap();
+
+  let store = Arc::new(StateStore::open(&state_file).unwrap());
+  spawn_backup_task(config, Arc::new(Semaphore::new(1)), store.clone()).await.unwrap();
+
+  let start = Instant::now();
+  while store.task("test").unwrap().outcome.is_none() && start.elapsed() < Duration::from_secs(5) {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+  assert_eq!(std::fs::read_to_string(dst.join("file1")).unwrap(), "content1");
+
+  let task = StateStore::open(&state_file).unwrap().task("test").unwrap();
+  assert!(task.last_start > last_start);
+  assert!(task.last_finish.unwrap() >= task.last_start);
+  assert_eq!(task.outcome, Some(RunOutcome::Success { files: 1, bytes: 8 }));
+}
+
+fn retry_config(src: &std::path::Path, dst: &std::path::Path) -> BackupTaskConfig {
+  BackupTaskConfig {
+    retry: Some(RetryConfig { attempts: 3, backoff: "300 milliseconds".to_string() }),
+    ..BackupTaskConfig::new("test", src, dst, BackupStrategyConfig::Incremental)
+  }
+}
+
+async fn wait_until_idle(runner: &TaskRunner) {
+  let start = Instant::now();
+  while runner.is_running() && start.elapsed() < Duration::from_secs(10) {
+    tokio::time::sleep(Duration::from_millis(20)).await;
+  }
+  assert!(!runner.is_running());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_runs_are_retried_with_backoff() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let src = temp_dir.path().join("src");
+  let dst = temp_dir.path().join("dst");
+  let store = Arc::new(StateStore::open(&temp_dir.path().join("state.json")).unwrap());
+  let limit = Arc::new(Semaphore::new(1));
+
+  // src stays missing: every attempt fails, with 300 and 600 ms between them
+  let runner = TaskRunner::new(retry_config(&src, &dst), limit.clone(), store.clone()).unwrap();
+  let start = Instant::now();
+  runner.trigger();
+  wait_until_idle(&runner).await;
+  assert!(start.elapsed() >= Duration::from_millis(900));
+  assert!(matches!(store.task("test").unwrap().outcome, Some(RunOutcome::Failure { .. })));
+
+  // src appears before the first retry
+  let runner = TaskRunner::new(retry_config(&src, &dst), limit, store.clone()).unwrap();
+  runner.trigger();
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  std::fs::create_dir_all(&src).unwrap();
+  std::fs::write(src.join("file1"), "content1").unwrap();
+  wait_until_idle(&runner).await;
+  assert_eq!(std::fs::read_to_string(dst.join("file1")).unwrap(), "content1");
+  assert_eq!(store.task("test").unwrap().outcome, Some(RunOutcome::Success { files: 1, bytes: 8 }));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hung_run_times_out() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let src = temp_dir.path().join("src");
+  let dst = temp_dir.path().join("dst");
+  std::fs::create_dir_all(&src).unwrap();
+
+  // reading a key from a FIFO nobody writes to blocks, like a read from a hung network mount
+  let key_file = temp_dir.path().join("key");
+  let c_path = std::ffi::CString::new(key_file.to_str().unwrap()).unwrap();
+  assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+  let mut config = retry_config(&src, &dst);
+  config.on.strategy = BackupStrategyConfig::Repository;
+  config.encryption = Some(EncryptionConfig::KeyFile(key_file.clone()));
+  config.timeout = Some("200 milliseconds".to_string());
+  let log = temp_dir.path().join("log");
+  config.hooks.on_failure = Some(format!(r#"echo "failed: $BACKUPS_ERROR" >> "{}""#, log.display()));
+  let store = Arc::new(StateStore::open(&temp_dir.path().join("state.json")).unwrap());
+  let limit = Arc::new(Semaphore::new(1));
+  let runner = TaskRunner::new(config, limit.clone(), store.clone()).unwrap();
+  runner.trigger();
+
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  // the failure is recorded and reported at the deadline, the slot is free for other tasks, but the task
+  // itself stays busy until the hung run returns
+  let Some(RunOutcome::Failure { error }) = store.task("test").unwrap().outcome else {
+    panic!("timeout wasn't recorded");
+  };
+  assert!(error.contains("timed out"), "{}", error);
+  assert_eq!(std::fs::read_to_string(&log).unwrap(), "failed: timed out after 200ms\n");
+  assert_eq!(limit.available_permits(), 1);
+  assert!(runner.is_running());
+
+  // opening the FIFO lets the stuck read return, the retry then finds no key file
+  let writer = std::fs::OpenOptions::new().write(true).open(&key_file).unwrap();
+  std::fs::remove_file(&key_file).unwrap();
+  drop(writer);
+  wait_until_idle(&runner).await;
+  assert_eq!(limit.available_permits(), 1);
+  let Some(RunOutcome::Failure { error }) = store.task("test").unwrap().outcome else {
+    panic!("backup didn't fail");
+  };
+  assert!(!error.contains("timed out"), "the timeout wasn't retried: {}", error);
+}
+
+#[test]
+fn hooks_run_around_backup() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let src = temp_dir.path().join("src");
+  let dst = temp_dir.path().join("dst");
+  let log = temp_dir.path().join("log");
+  std::fs::create_dir_all(&src).unwrap();
+
+  let mut config = retry_config(&src, &dst);
+  config.hooks = HooksConfig {
+    // e.g. a database dump, backed up along with the rest of src
+    before: Some(r#"echo dump > "$BACKUPS_SRC/dump.sql""#.to_string()),
+    after: Some(format!(
+      r#"echo "$BACKUPS_TASK $BACKUPS_STATUS $BACKUPS_FILES $BACKUPS_BYTES" >> "{}""#,
+      log.display()
+    )),
+    on_failure: Some(format!(r#"echo "failed: $BACKUPS_ERROR" >> "{}""#, log.display())),
+  };
+  run_task(&config, &Default::default()).unwrap();
+  assert_eq!(std::fs::read_to_string(dst.join("dump.sql")).unwrap(), "dump\n");
+  assert_eq!(std::fs::read_to_string(&log).unwrap(), "test success 1 5\n");
+
+  // a failing `before` hook fails the run without touching dst
+  std::fs::remove_file(&log).unwrap();
+  config.hooks.before = Some("exit 3".to_string());
+  std::fs::remove_file(src.join("dump.sql")).unwrap();
+  assert!(run_task(&config, &Default::default()).is_err());
+  assert!(dst.join("dump.sql").exists());
+  let log = std::fs::read_to_string(&log).unwrap();
+  let lines = log.lines().collect::<Vec<_>>();
+  assert_eq!(lines.len(), 2, "{}", log);
+  assert_eq!(lines[0], "test failure  ");
+  assert!(lines[1].starts_with("failed: `before` hook failed"), "{}", log);
+}
@@ -0,0 +1,192 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use backups::backup::*;
+use backups::config::*;
+use backups::fs::*;
+
+fn sftp_task(temp_dir: &Path, dst: &str) -> BackupTaskConfig {
+  let src = temp_dir.join("src");
+  std::fs::create_dir_all(src.join("dir1")).unwrap();
+  std::fs::write(src.join("file1"), "content1").unwrap();
+  std::fs::write(src.join("file2"), "content2").unwrap();
+  std::fs::write(src.join("dir1/file3"), "content3").unwrap();
+  std::os::unix::fs::symlink("file1", src.join("link1")).unwrap();
+
+  BackupTaskConfig::new("test", &src, Path::new(dst), BackupStrategyConfig::Incremental)
+}
+
+#[test]
+fn sftp_location_is_parsed() {
+  let location = SftpLocation::parse("backup@host.example:/srv/backups").unwrap();
+  assert_eq!(
+    location,
+    SftpLocation {
+      user: "backup".to_string(),
+      host: "host.example".to_string(),
+      port: 22,
+      path: PathBuf::from("/srv/backups")
+    }
+  );
+  assert_eq!(location.to_string(), "sftp://backup@host.example:22/srv/backups");
+
+  let location = SftpLocation::parse("backup@localhost:2222/tmp").unwrap();
+  assert_eq!((location.host.as_str(), location.port), ("localhost", 2222));
+  assert_eq!(location.path, PathBuf::from("/tmp"));
+
+  assert!(SftpLocation::parse("backup@host.example").is_err());
+  assert!(SftpLocation::parse("backup@host.example:ssh/srv").is_err());
+  assert!(SftpLocation::parse("backup@:/srv").is_err());
+}
+
+#[test]
+fn sftp_dst_needs_plain_mirror() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let mut config = sftp_task(temp_dir.path(), "sftp://backup@localhost:/srv/backups");
+
+  config.on.snapshots = true;
+  let error = make_backup(&config).unwrap_err().to_string();
+  assert!(error.contains("needs the `incremental` strategy without snapshots"), "{}", error);
+
+  config.on.snapshots = false;
+  config.on.strategy = BackupStrategyConfig::Differential;
+  assert!(make_backup(&config).is_err());
+
+  config.on.strategy = BackupStrategyConfig::Incremental;
+  let options = backups::restore::RestoreOptions::default();
+  assert!(backups::restore::restore(&config, &options).is_err());
+  assert!(backups::verify::verify(&config, None).is_err());
+}
+
+/// `sshd` run as the current user on a free local port, with throwaway host and client keys. Stopped
+/// when dropped
+struct LocalSshd {
+  process: std::process::Child,
+  _dir: tempfile::TempDir,
+  base: String,
+}
+
+impl Drop for LocalSshd {
+  fn drop(&mut self) {
+    let _ = self.process.kill();
+    let _ = self.process.wait();
+  }
+}
+
+fn ssh_keygen(path: &Path) {
+  let status = Command::new("ssh-keygen")
+    .args(["-q", "-t", "ecdsa", "-m", "PEM", "-N", "", "-f"])
+    .arg(path)
+    .status()
+    .unwrap();
+  assert!(status.success());
+}
+
+/// Starts `sshd` from `$BACKUPS_TEST_SSHD` or the usual places and points the SSH settings of the
+/// process at it; `None` when there's no `sshd` to run
+fn local_sshd() -> Option<LocalSshd> {
+  let sshd = std::env::var_os("BACKUPS_TEST_SSHD")
+    .map(PathBuf::from)
+    .into_iter()
+    .chain(["/usr/sbin/sshd", "/usr/local/sbin/sshd", "/usr/bin/sshd"].map(PathBuf::from))
+    .find(|path| path.is_file())?;
+
+  let dir = tempfile::tempdir().unwrap();
+  let (host_key, client_key) = (dir.path().join("host_key"), dir.path().join("client_key"));
+  ssh_keygen(&host_key);
+  ssh_keygen(&client_key);
+  std::fs::copy(client_key.with_extension("pub"), dir.path().join("authorized_keys")).unwrap();
+
+  let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+  let config = dir.path().join("sshd_config");
+  std::fs::write(
+    &config,
+    format!(
+      "Port {port}\nListenAddress 127.0.0.1\nHostKey {host_key}\nPidFile {dir}/sshd.pid\n\
+       AuthorizedKeysFile {dir}/authorized_keys\nStrictModes no\nUsePAM no\nPasswordAuthentication no\n\
+       PermitRootLogin prohibit-password\nSubsystem sftp internal-sftp\n",
+      host_key = host_key.display(),
+      dir = dir.path().display(),
+    ),
+  )
+  .unwrap();
+  let host_public_key = std::fs::read_to_string(host_key.with_extension("pub")).unwrap();
+  let known_hosts = dir.path().join("known_hosts");
+  std::fs::write(&known_hosts, format!("[127.0.0.1]:{} {}", port, host_public_key)).unwrap();
+
+  // sshd run as root wants its privilege separation directory
+  if unsafe { libc::getuid() } == 0 {
+    std::fs::create_dir_all("/run/sshd").unwrap();
+  }
+  let process = Command::new(sshd).arg("-D").arg("-e").arg("-f").arg(&config).spawn().unwrap();
+  let start = std::time::Instant::now();
+  while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+    assert!(start.elapsed() < std::time::Duration::from_secs(10), "sshd didn't start");
+    std::thread::sleep(std::time::Duration::from_millis(50));
+  }
+
+  std::env::set_var("BACKUPS_SSH_KEY", &client_key);
+  std::env::set_var("BACKUPS_SSH_KNOWN_HOSTS", &known_hosts);
+  let user = unsafe { std::ffi::CStr::from_ptr((*libc::getpwuid(libc::getuid())).pw_name) };
+  let base = format!("sftp://{}@127.0.0.1:{}{}", user.to_str().unwrap(), port, dir.path().display());
+  Some(LocalSshd { process, _dir: dir, base })
+}
+
+/// Needs an SSH server: a local `sshd`, e.g. from `apt-get install openssh-server`, found by itself or
+/// given in `BACKUPS_TEST_SSHD`, or any server in `BACKUPS_TEST_SFTP` whose key is known, e.g.
+/// `docker run -p 2222:2222 -e PUBLIC_KEY="$(cat ~/.ssh/id_ed25519.pub)" -e USER_NAME=backup
+/// linuxserver/openssh-server` with `BACKUPS_TEST_SFTP=sftp://backup@localhost:2222/tmp`. Run with
+/// `cargo test --test sftp -- --ignored`
+#[test]
+#[ignore]
+fn incremental_mirror_over_sftp() {
+  let sshd;
+  let base = match std::env::var("BACKUPS_TEST_SFTP") {
+    Ok(base) => base,
+    Err(_) => {
+      sshd = local_sshd().expect("no sshd to test against, set BACKUPS_TEST_SFTP or BACKUPS_TEST_SSHD");
+      sshd.base.clone()
+    }
+  };
+  let temp_dir = tempfile::tempdir().unwrap();
+  let name = temp_dir.path().file_name().unwrap().to_string_lossy().to_string();
+  let mut config = sftp_task(temp_dir.path(), &format!("{}/{}", base, name));
+  config.preserve.ownership = true;
+  let (fs, dst) = open_fs(&config.dst).unwrap();
+  let read = |path: &str| String::from_utf8(fs.read(&dst.join(path)).unwrap()).unwrap();
+
+  let summary = make_backup(&config).unwrap();
+  assert_eq!(summary.files, 4);
+  assert_eq!(read("file1"), "content1");
+  assert_eq!(read("dir1/file3"), "content3");
+  assert_eq!(fs.read_link(&dst.join("link1")).unwrap(), PathBuf::from("file1"));
+  assert!(fs.metadata(&dst.join(backups::manifest::MANIFEST_FILE_NAME)).unwrap().is_some());
+
+  // mtimes over SFTP have whole seconds, a change within the same second would go unnoticed
+  std::thread::sleep(std::time::Duration::from_millis(1100));
+  // a newer file in the mirror counts as up to date, so it isn't uploaded again
+  fs.write(&dst.join("file2"), b"untouched").unwrap();
+  std::fs::write(config.src.join("file1"), "content1_modified").unwrap();
+  std::fs::remove_file(config.src.join("dir1/file3")).unwrap();
+  make_backup(&config).unwrap();
+
+  assert_eq!(read("file1"), "content1_modified");
+  assert_eq!(read("file2"), "untouched");
+  assert!(fs.metadata(&dst.join("dir1/file3")).unwrap().is_none());
+  assert_eq!(fs.metadata(&dst.join("dir1")).unwrap().unwrap().kind, FileKind::Dir);
+
+  // the owner is set only where the server runs the session as root
+  if std::env::var("BACKUPS_TEST_SFTP").is_err() {
+    assert_eq!(fs.sets_ownership(), unsafe { libc::getuid() } == 0);
+  }
+  if fs.sets_ownership() {
+    let c_path = std::ffi::CString::new(config.src.join("file1").to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::lchown(c_path.as_ptr(), 1234, 5678) }, 0);
+    make_backup(&config).unwrap();
+    let metadata = fs.metadata(&dst.join("file1")).unwrap().unwrap();
+    assert_eq!((metadata.uid, metadata.gid), (1234, 5678));
+  }
+
+  fs.remove(&dst).unwrap();
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use backups::backup::*;
+use backups::config::*;
+use backups::restore::*;
+use backups::storage::*;
+use backups::verify::verify;
+
+const ACCESS_KEY: &str = "test-access-key";
+/// Existence checks of single chunks in the `backups` bucket, which the repository should never need
+static CHUNK_HEAD_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts an in-memory stand-in for an S3-compatible server like MinIO, speaking just the part of
+/// the API the storage uses, and points the environment at it. Listings return two keys per page
+/// to exercise pagination. Shared by all tests, which use different buckets
+fn fake_s3() -> &'static str {
+  static ENDPOINT: OnceLock<String> = OnceLock::new();
+  ENDPOINT.get_or_init(|| {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", server.server_addr().to_ip().unwrap());
+    let objects = Arc::new(Mutex::new(BTreeMap::<String, Vec<u8>>::new()));
+    std::thread::spawn(move || {
+      for mut request in server.incoming_requests() {
+        let response = handle(&mut request, &mut objects.lock().unwrap());
+        request.respond(response).unwrap();
+      }
+    });
+
+    std::env::set_var("AWS_ENDPOINT_URL", &endpoint);
+    std::env::set_var("AWS_ACCESS_KEY_ID", ACCESS_KEY);
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", "test-secret-key");
+    endpoint
+  })
+}
+
+fn handle(
+  request: &mut tiny_http::Request,
+  objects: &mut BTreeMap<String, Vec<u8>>,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+  let respond = |status: u16, body: Vec<u8>| tiny_http::Response::from_data(body).with_status_code(status);
+  let headers = request
+    .headers()
+    .iter()
+    .map(|header| (header.field.as_str().as_str().to_ascii_lowercase(), header.value.to_string()))
+    .collect::<BTreeMap<_, _>>();
+  let header = |name: &str| headers.get(name).cloned();
+
+  let signed = header("authorization")
+    .is_some_and(|auth| auth.starts_with(&format!("AWS4-HMAC-SHA256 Credential={}/", ACCESS_KEY)));
+  if !signed || header("x-amz-date").is_none() || header("x-amz-content-sha256").is_none() {
+    return respond(403, b"unsigned request".to_vec());
+  }
+
+  let url = request.url().to_string();
+  let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+  let path = decode(&path[1..]);
+  let query = query
+    .split('&')
+    .filter_map(|pair| pair.split_once('='))
+    .map(|(name, value)| (decode(name), decode(value)))
+    .collect::<BTreeMap<_, _>>();
+
+  match request.method() {
+    tiny_http::Method::Get if query.contains_key("list-type") => {
+      let prefix = format!("{}/{}", path, query.get("prefix").map(String::as_str).unwrap_or_default());
+      let after = query.get("continuation-token").map(|token| format!("{}/{}", path, token));
+      let mut keys = objects
+        .iter()
+        .filter(|(key, _)| key.starts_with(&prefix) && after.as_ref().is_none_or(|after| *key > after))
+        .map(|(key, data)| (key[path.len() + 1..].to_string(), data.len()));
+      let page = keys.by_ref().take(2).collect::<Vec<_>>();
+      let truncated = keys.next().is_some();
+
+      let mut xml = String::from("<ListBucketResult>");
+      for (key, size) in page.iter() {
+        xml += &format!("<Contents><Key>{}</Key><Size>{}</Size></Contents>", key, size);
+      }
+      xml += &format!("<IsTruncated>{}</IsTruncated>", truncated);
+      if truncated {
+        xml += &format!("<NextContinuationToken>{}</NextContinuationToken>", page.last().unwrap().0);
+      }
+      xml += "</ListBucketResult>";
+      respond(200, xml.into_bytes())
+    }
+    tiny_http::Method::Get => match objects.get(&path) {
+      Some(data) => respond(200, data.clone()),
+      None => respond(404, vec![]),
+    },
+    tiny_http::Method::Head => {
+      if path.starts_with("backups/") && path.contains("/chunks/") {
+        CHUNK_HEAD_REQUESTS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+      }
+      respond(if objects.contains_key(&path) { 200 } else { 404 }, vec![])
+    }
+    tiny_http::Method::Put => {
+      let data = match header("x-amz-copy-source") {
+        Some(source) => match objects.get(&decode(&source[1..])) {
+          Some(data) => data.clone(),
+          None => return respond(404, vec![]),
+        },
+        None => {
+          let mut data = Vec::new();
+          request.as_reader().read_to_end(&mut data).unwrap();
+          data
+        }
+      };
+      objects.insert(path, data);
+      respond(200, vec![])
+    }
+    tiny_http::Method::Delete => {
+      objects.remove(&path);
+      respond(204, vec![])
+    }
+    _ => respond(405, vec![]),
+  }
+}
+
+fn decode(s: &str) -> String {
+  let bytes = s.as_bytes();
+  let mut decoded = Vec::new();
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'%' {
+      decoded.push(u8::from_str_radix(&s[i + 1..i + 3], 16).unwrap());
+      i += 3;
+    } else {
+      decoded.push(bytes[i]);
+      i += 1;
+    }
+  }
+  String::from_utf8(decoded).unwrap()
+}
+
+fn check_storage(storage: &dyn Storage) {
+  assert!(storage.list("").unwrap().is_empty());
+
+  storage.put("snapshots/one.json", b"one").unwrap();
+  storage.put("chunks/ab/abc", b"chunk").unwrap();
+  storage.put("chunks/cd/cde", b"chunk2").unwrap();
+  storage.put("chunks/cd/cdf", b"").unwrap();
+  assert_eq!(storage.get("snapshots/one.json").unwrap(), b"one");
+  assert!(storage.exists("chunks/ab/abc").unwrap());
+  assert!(!storage.exists("chunks/ab/abd").unwrap());
+  assert!(storage.get("chunks/ab/abd").is_err());
+
+  let chunks = storage.list("chunks").unwrap();
+  let keys = chunks.iter().map(|object| (object.key.as_str(), object.size)).collect::<Vec<_>>();
+  assert_eq!(keys, vec![("chunks/ab/abc", 5), ("chunks/cd/cde", 6), ("chunks/cd/cdf", 0)]);
+  assert_eq!(storage.list("").unwrap().len(), 4);
+
+  storage.rename("snapshots/one.json", "snapshots/two.json").unwrap();
+  assert!(!storage.exists("snapshots/one.json").unwrap());
+  assert_eq!(storage.get("snapshots/two.json").unwrap(), b"one");
+
+  storage.delete("chunks/ab/abc").unwrap();
+  storage.delete("chunks/ab/abc").unwrap();
+  assert_eq!(storage.list("chunks").unwrap().len(), 2);
+}
+
+#[test]
+fn local_storage() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let storage = LocalStorage::new(&temp_dir.path().join("dst"));
+  check_storage(&storage);
+  assert_eq!(storage.path("chunks/cd/cde"), temp_dir.path().join("dst/chunks/cd/cde"));
+}
+
+#[test]
+fn s3_storage() {
+  let storage = S3Storage::new(fake_s3(), "us-east-1", "contract/some/prefix", ACCESS_KEY, "secret").unwrap();
+  check_storage(&storage);
+  assert_eq!(storage.path("chunks/cd/cde"), PathBuf::from("s3://contract/some/prefix/chunks/cd/cde"));
+
+  // the prefix keeps the tree apart from other data in the bucket
+  let other = S3Storage::new(fake_s3(), "us-east-1", "contract/some", ACCESS_KEY, "secret").unwrap();
+  assert_eq!(other.list("prefix").unwrap().len(), 3);
+  assert!(other.list("chunks").unwrap().is_empty());
+}
+
+fn s3_task(temp_dir: &std::path::Path, dst: &str, strategy: BackupStrategyConfig) -> BackupTaskConfig {
+  fake_s3();
+  let src = temp_dir.join("src");
+  std::fs::create_dir_all(src.join("dir1")).unwrap();
+  std::fs::write(src.join("file1"), "content1").unwrap();
+  std::fs::write(src.join("dir1/file2"), "content2").unwrap();
+
+  BackupTaskConfig::new("test", &src, std::path::Path::new(dst), strategy)
+}
+
+#[test]
+fn repository_in_s3() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let mut config = s3_task(temp_dir.path(), "s3://backups/host1", BackupStrategyConfig::Repository);
+  let key_file = temp_dir.path().join("key");
+  std::fs::write(&key_file, [7; 32]).unwrap();
+  config.encryption = Some(EncryptionConfig::KeyFile(key_file));
+
+  let summary = make_backup(&config).unwrap();
+  assert_eq!(summary, BackupSummary { files: 2, bytes: 16 });
+  std::fs::write(config.src.join("file1"), "content1_modified").unwrap();
+  std::thread::sleep(std::time::Duration::from_millis(1100));
+  make_backup(&config).unwrap();
+
+  let storage = open_storage(&config.dst).unwrap();
+  assert!(storage.exists(backups::crypto::ENCRYPTION_FILE_NAME).unwrap());
+  assert_eq!(storage.list("snapshots").unwrap().len(), 2);
+  let report = verify(&config, None).unwrap();
+  assert!(report.is_intact(), "{:?}", report);
+  // chunks are looked up in a single listing per run
+  assert_eq!(CHUNK_HEAD_REQUESTS.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+  let target = temp_dir.path().join("restored");
+  let options = RestoreOptions { target: Some(target.clone()), ..Default::default() };
+  restore(&config, &options).unwrap();
+  assert_eq!(std::fs::read_to_string(target.join("file1")).unwrap(), "content1_modified");
+  assert_eq!(std::fs::read_to_string(target.join("dir1/file2")).unwrap(), "content2");
+
+  // pruning the first snapshot drops the chunk of the old file1
+  let chunks = storage.list("chunks").unwrap().len();
+  prune_snapshots(&config, &RetentionConfig { keep_last: Some(1), ..Default::default() }).unwrap();
+  assert_eq!(storage.list("snapshots").unwrap().len(), 1);
+  assert_eq!(storage.list("chunks").unwrap().len(), chunks - 1);
+}
+
+#[test]
+fn remote_dst_needs_repository() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let config = s3_task(temp_dir.path(), "s3://backups/mirror", BackupStrategyConfig::Incremental);
+  let error = make_backup(&config).unwrap_err().to_string();
+  assert!(error.contains("needs the `repository` strategy"), "{}", error);
+}
@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+
+use backups::backup::*;
+use backups::config::*;
+use backups::manifest::MANIFEST_FILE_NAME;
+use backups::verify::*;
+use tempfile::TempDir;
+
+fn prepare_test_dir(strategy: BackupStrategyConfig, snapshots: bool) -> (PathBuf, TempDir, BackupTaskConfig) {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let src = temp_dir.path().join("src");
+
+  std::fs::create_dir_all(src.join("dir1")).unwrap();
+  std::fs::write(src.join("file1"), "content1").unwrap();
+  std::fs::write(src.join("dir1/file2"), "content2").unwrap();
+
+  let mut config = BackupTaskConfig::new("test", &src, &temp_dir.path().join("dst"), strategy);
+  config.on.snapshots = snapshots;
+
+  (src, temp_dir, config)
+}
+
+#[test]
+fn verify_mirror_backup() {
+  for strategy in [BackupStrategyConfig::Incremental, BackupStrategyConfig::Differential] {
+    let (_src, _temp_dir, config) = prepare_test_dir(strategy, false);
+    make_backup(&config).unwrap();
+    let root = match config.on.strategy {
+      BackupStrategyConfig::Differential => config.dst.join(differential::FULL_DIR_NAME),
+      _ => config.dst.clone(),
+    };
+    assert!(root.join(MANIFEST_FILE_NAME).is_file());
+
+    let report = verify(&config, None).unwrap();
+    assert!(report.is_intact());
+    assert_eq!(report.checked, 2);
+    assert!(report.extra.is_empty());
+
+    std::fs::write(root.join("file1"), "tampered").unwrap();
+    std::fs::remove_file(root.join("dir1/file2")).unwrap();
+    std::fs::write(root.join("file3"), "content3").unwrap();
+
+    let report = verify(&config, None).unwrap();
+    assert!(!report.is_intact());
+    assert_eq!(report.corrupted, vec![PathBuf::from("file1")]);
+    assert_eq!(report.missing, vec![PathBuf::from("dir1/file2")]);
+    assert_eq!(report.extra, vec![PathBuf::from("file3")]);
+  }
+}
+
+#[test]
+fn verify_differential_set() {
+  let (src, _temp_dir, config) = prepare_test_dir(BackupStrategyConfig::Differential, false);
+  make_backup(&config).unwrap();
+  std::fs::write(src.join("file1"), "content1_modified").unwrap();
+  make_backup(&config).unwrap();
+
+  let diff = config.dst.join(differential::DIFF_DIR_NAME);
+  assert!(diff.join("file1").is_file());
+  assert!(verify(&config, None).unwrap().is_intact());
+
+  std::fs::write(diff.join("file1"), "tampered").unwrap();
+  // file2 didn't change, so it is checked in the full backup
+  std::fs::remove_file(config.dst.join(differential::FULL_DIR_NAME).join("dir1/file2")).unwrap();
+  let report = verify(&config, None).unwrap();
+  assert_eq!(report.corrupted, vec![PathBuf::from("file1")]);
+  assert_eq!(report.missing, vec![PathBuf::from("dir1/file2")]);
+}
+
+#[test]
+fn verify_snapshot_backup() {
+  let (src, _temp_dir, config) = prepare_test_dir(BackupStrategyConfig::Incremental, true);
+  make_backup(&config).unwrap();
+  std::fs::write(src.join("file3"), "content3").unwrap();
+  make_backup(&config).unwrap();
+
+  let snapshots = list_backup_snapshots(&config).unwrap().unwrap();
+  let report = verify(&config, None).unwrap();
+  assert!(report.is_intact());
+  assert_eq!(report.checked, 3);
+
+  let first = config.dst.join(&snapshots[0].name);
+  std::fs::remove_file(first.join("file1")).unwrap();
+  std::fs::write(first.join("file1"), "tampered").unwrap();
+  let report = verify(&config, Some(&snapshots[0].name)).unwrap();
+  assert_eq!(report.corrupted, vec![PathBuf::from("file1")]);
+  assert!(verify(&config, None).unwrap().is_intact());
+}
+
+#[test]
+fn verify_repository_backup() {
+  let (_src, _temp_dir, config) = prepare_test_dir(BackupStrategyConfig::Repository, false);
+  make_backup(&config).unwrap();
+
+  let report = verify(&config, None).unwrap();
+  assert!(report.is_intact());
+  assert_eq!(report.checked, 2);
+
+  let repo = repository::Repository::open(&config.dst).unwrap();
+  let manifest = repo.read_snapshot(&repo.snapshots().unwrap()[0].name).unwrap();
+  let chunk_of = |path: &str| {
+    let entry = manifest.entries.iter().find(|entry| entry.path == std::path::Path::new(path)).unwrap();
+    repo.chunk_path(&entry.chunks[0])
+  };
+  std::fs::write(chunk_of("file1"), "tampered").unwrap();
+  std::fs::remove_file(chunk_of("dir1/file2")).unwrap();
+  let unreferenced = repo.chunk_path("00unreferenced");
+  std::fs::create_dir_all(unreferenced.parent().unwrap()).unwrap();
+  std::fs::write(&unreferenced, "data").unwrap();
+
+  let report = verify(&config, None).unwrap();
+  assert_eq!(report.corrupted, vec![PathBuf::from("file1")]);
+  assert_eq!(report.missing, vec![PathBuf::from("dir1/file2")]);
+  assert_eq!(report.extra, vec![unreferenced]);
+}
+
+#[test]
+fn verify_archive_backup() {
+  let (src, _temp_dir, mut config) = prepare_test_dir(BackupStrategyConfig::Incremental, false);
+  config.on.format = OutputFormat::TarGz;
+  make_backup(&config).unwrap();
+  std::fs::write(src.join("file1"), "content1_modified").unwrap();
+  make_backup(&config).unwrap();
+
+  let report = verify(&config, None).unwrap();
+  assert!(report.is_intact());
+  assert_eq!(report.checked, 1);
+
+  let snapshots = list_backup_snapshots(&config).unwrap().unwrap();
+  std::fs::remove_file(config.dst.join(format!("{}.json", snapshots[0].name))).unwrap();
+  let report = verify(&config, None).unwrap();
+  assert!(!report.is_intact());
+}